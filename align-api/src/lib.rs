@@ -2,20 +2,71 @@
 extern crate serde_json;
 extern crate geom;
 
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
 use geom::Matrix3x3;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AlignedImage {
     pub filename: String,
     pub transform: Matrix3x3<f64>,
+    /// Size and modification time of `filename` when it was aligned.
+    /// Missing in files written by older versions of `align`.
+    #[serde(default)]
+    pub stamp: Option<FileStamp>,
+    /// Weight of the frame when stacking, for `stack --weighting file`.
+    /// `align` doesn't set it, other tools can.
+    #[serde(default)]
+    pub weight: Option<f64>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FileStamp {
+    pub len: u64,
+    pub modified: u64,
+}
+
+impl FileStamp {
+    pub fn of<P: AsRef<Path>>(path: P) -> Self {
+        let metadata = fs::metadata(path).unwrap();
+        let modified = metadata.modified().unwrap()
+            .duration_since(UNIX_EPOCH).unwrap()
+            .as_secs();
+        FileStamp {
+            len: metadata.len(),
+            modified: modified,
+        }
+    }
+}
+
+impl AlignedImage {
+    /// Returns true if the file on disk still looks like the one that was aligned.
+    /// Entries without a stamp are trusted.
+    pub fn is_up_to_date(&self) -> bool {
+        match self.stamp {
+            Some(stamp) => {
+                Path::new(&self.filename).exists() && FileStamp::of(&self.filename) == stamp
+            }
+            None => true
+        }
+    }
+}
+
+/// Writes the alignment to a temp file first, then renames it over `filename`,
+/// so an interrupted write never leaves a truncated file behind.
 pub fn write(alignment: &[AlignedImage], filename: &str) {
-    let mut file = File::create(&filename).unwrap();
-    let json = serde_json::to_string(&alignment).unwrap();
-    file.write_all(json.as_bytes()).unwrap();
+    let tmp_filename = format!("{}.tmp", filename);
+    {
+        let mut file = File::create(&tmp_filename).unwrap();
+        let json = serde_json::to_string(&alignment).unwrap();
+        file.write_all(json.as_bytes()).unwrap();
+        file.sync_all().unwrap();
+    }
+    fs::rename(&tmp_filename, &filename).unwrap();
 }
 
 pub fn read(filename: &str) -> Vec<AlignedImage> {
@@ -24,3 +75,12 @@ pub fn read(filename: &str) -> Vec<AlignedImage> {
     file.read_to_string(&mut json).unwrap();
     serde_json::from_str(&json).unwrap()
 }
+
+/// Like `read`, but returns `None` if the file doesn't exist.
+pub fn try_read(filename: &str) -> Option<Vec<AlignedImage>> {
+    match File::open(&filename) {
+        Ok(_) => Some(read(filename)),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => panic!("failed to open {}: {}", filename, e)
+    }
+}
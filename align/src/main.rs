@@ -5,17 +5,17 @@ extern crate star_aligner;
 extern crate image;
 extern crate align_api;
 extern crate rayon;
-extern crate tempfile;
 #[macro_use] extern crate log;
 extern crate env_logger;
 
 use std::fs;
 use std::env;
 use std::path::Path;
-use std::process::Command;
+use std::sync::Mutex;
+use std::collections::HashMap;
 use structopt::StructOpt;
 use rayon::prelude::*;
-use align_api::AlignedImage;
+use align_api::{AlignedImage, FileStamp};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "align", about = "")]
@@ -28,23 +28,13 @@ struct Args {
     flag_min_matching_stars: usize,
     #[structopt(long = "threshold", help = "px")]
     flag_threshold: f64,
+    #[structopt(long = "update", help = "Existing alignment json file; unchanged entries are kept")]
+    flag_update: Option<String>,
+    #[structopt(long = "checkpoint-every", help = "Save progress every N images, 0 for never", default_value = "10")]
+    flag_checkpoint_every: usize,
     arg_input: Vec<String>,
 }
 
-fn with_fits<F,R,P>(src: P, mut f: F) -> R
-where P: AsRef<Path>, F: FnMut(&Path) -> R {
-    let out = tempfile::NamedTempFileOptions::new().suffix(".fits").create().unwrap();
-    let status = Command::new("convert")
-        .arg(src.as_ref())
-        .arg("-colorspace")
-        .arg("gray")
-        .arg(out.path())
-        .status()
-        .expect("failed to run convert");
-    assert!(status.success());
-    f(out.path())
-}
-
 fn main() {
     let args = Args::from_args();
     if env::var("RUST_LOG").is_err() {
@@ -52,41 +42,118 @@ fn main() {
     }
     env_logger::init().unwrap();
 
-    info!("aligning {} images", args.arg_input.len());
+    let checkpoint_filename = format!("{}.partial", args.flag_output);
+
+    // entries we can reuse: the file passed to --update, plus whatever an interrupted
+    // run managed to checkpoint
+    let mut existing: Vec<AlignedImage> = vec![];
+    if let Some(ref update) = args.flag_update {
+        existing.extend(align_api::read(update));
+        info!("loaded {} aligned images from {}", existing.len(), update);
+    }
+    if let Some(partial) = align_api::try_read(&checkpoint_filename) {
+        info!("resuming from checkpoint {} ({} images)", checkpoint_filename, partial.len());
+        existing.extend(partial);
+    }
+    let mut known: HashMap<String, AlignedImage> = existing
+        .iter()
+        .filter(|a| a.is_up_to_date())
+        .map(|a| (a.filename.clone(), a.clone()))
+        .collect();
+
+    // when updating, keep aligning against the original reference. If it's gone or changed,
+    // the old transforms are relative to something we can't see anymore, so start over.
+    let reference_filename = match args.flag_update {
+        Some(_) if !existing.is_empty() => {
+            let reference = &existing[0];
+            if Path::new(&reference.filename).exists() && reference.is_up_to_date() {
+                reference.filename.clone()
+            } else {
+                let first = args.arg_input.first()
+                    .expect(&format!("reference {} is missing or changed, and there are no inputs to use instead", reference.filename));
+                warn!("reference {} is missing or changed, realigning everything against {}", reference.filename, first);
+                known.clear();
+                canonical(first)
+            }
+        }
+        _ => canonical(&args.arg_input[0]),
+    };
+
+    // output order: reference first, then previously aligned images, then new ones
+    let mut order = vec![reference_filename.clone()];
+    for filename in existing.iter().map(|a| a.filename.clone())
+        .chain(args.arg_input.iter().map(|f| canonical(f))) {
+        if !Path::new(&filename).exists() {
+            warn!("dropping {}, file no longer exists", filename);
+            continue;
+        }
+        if !order.contains(&filename) {
+            order.push(filename);
+        }
+    }
+    let todo: Vec<_> = order
+        .iter()
+        .filter(|filename| !known.contains_key(*filename))
+        .cloned()
+        .collect();
+
+    info!("aligning {} images ({} already aligned)", todo.len(), order.len() - todo.len());
 
     //let ref_image = Image::<f32>::open(&args.arg_input[0]);
     //let three_axis = donuts::three_axis_2d::ThreeAxisDonuts::new(&ref_image);
-    let reference = with_fits(&args.arg_input[0], |filename| {
-        star_aligner::Reference::from_image(filename, star_aligner::Options {
+    info!("reference: {}", reference_filename);
+    let reference = star_aligner::Reference::from_stars(
+        star_aligner::stars(&reference_filename, args.flag_max_stars),
+        star_aligner::Options {
             max_stars: args.flag_max_stars,
             min_matching_stars: args.flag_min_matching_stars,
             threshold: args.flag_threshold,
-        })
-    });
+        });
 
-    let res: Vec<_> = args.arg_input
+    let done = Mutex::new(known);
+    let failed = Mutex::new(0);
+    todo
         .par_iter()
-        .filter_map(|filename| {
-            let filename = fs::canonicalize(filename).unwrap();
+        .for_each(|filename| {
             info!("aligning {:?}", filename);
             //let sample_image = Image::<f32>::open(&filename);
             //let transform = three_axis.align(&sample_image);
-            let transform = with_fits(&filename, |fits_filename| {
-                reference.align_image(fits_filename)
-            });
+            let stamp = FileStamp::of(filename);
+            let transform = reference.align_stars(&star_aligner::stars(filename, args.flag_max_stars));
             if let Some(transform) = transform {
-                Some(AlignedImage {
-                    filename: filename.to_string_lossy().into_owned(),
-                    transform: transform
-                })
+                let mut done = done.lock().unwrap();
+                done.insert(filename.clone(), AlignedImage {
+                    filename: filename.clone(),
+                    transform: transform,
+                    stamp: Some(stamp),
+                    weight: None,
+                });
+                if args.flag_checkpoint_every > 0 && done.len() % args.flag_checkpoint_every == 0 {
+                    align_api::write(&in_order(&order, &done), &checkpoint_filename);
+                }
             } else {
-                error!("failed to align {}", filename.to_str().unwrap());
-                None
+                error!("failed to align {}", filename);
+                *failed.lock().unwrap() += 1;
             }
-        })
-        .collect();
+        });
+
+    let res = in_order(&order, &done.into_inner().unwrap());
 
-    info!("good: {}, bad: {}", res.len(), args.arg_input.len() - res.len());
+    info!("good: {}, bad: {}", res.len(), failed.into_inner().unwrap());
 
     align_api::write(&res, &args.flag_output);
+    if Path::new(&checkpoint_filename).exists() {
+        fs::remove_file(&checkpoint_filename).unwrap();
+    }
+}
+
+fn canonical(filename: &str) -> String {
+    fs::canonicalize(filename).unwrap().to_string_lossy().into_owned()
+}
+
+fn in_order(order: &[String], aligned: &HashMap<String, AlignedImage>) -> Vec<AlignedImage> {
+    order
+        .iter()
+        .filter_map(|filename| aligned.get(filename).cloned())
+        .collect()
 }
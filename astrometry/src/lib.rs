@@ -5,6 +5,7 @@ extern crate tempfile;
 #[macro_use] extern crate error_chain;
 
 pub mod errors;
+pub mod wcs;
 
 use tempfile::NamedTempFile;
 use std::process::Command;
@@ -13,19 +14,42 @@ use std::fs::File;
 use std::path::Path;
 use regex::Regex;
 use errors::*;
+use wcs::Wcs;
 
 pub fn solve(path: &str) -> Result<(f64, f64)> {
-    //let wcs_file = NamedTempFile::new().unwrap();
+    let stdout = solve_field(path, (10.2, 10.4), "none")?;
+    let pattern = r"RA,Dec = \((.+),(.+)\), ";
+    let cap = Regex::new(pattern).unwrap()
+        .captures_iter(&stdout).next().ok_or_else(|| ErrorKind::NotSolved)?;
+    let ra = cap[1].parse::<f64>().unwrap();
+    let dec = cap[2].parse::<f64>().unwrap();
+    info!("ra: {} dec: {}", ra, dec);
 
-    let mut output = Command::new("solve-field")
+    Ok((ra, dec))
+}
+
+/// Solves the image at `path`, whose width is between the given degrees, for its full WCS.
+pub fn solve_wcs(path: &str, width: (f64, f64)) -> Result<Wcs> {
+    let wcs_file = NamedTempFile::new().unwrap();
+    let wcs_path = wcs_file.path().to_str().unwrap().to_string();
+    solve_field(path, width, &wcs_path)?;
+    if fs::metadata(&wcs_path).map(|m| m.len() == 0).unwrap_or(true) {
+        return Err(ErrorKind::NotSolved.into());
+    }
+    Ok(Wcs::open(&wcs_path))
+}
+
+/// Runs solve-field, writing the WCS to `wcs`, and returns its output.
+fn solve_field(path: &str, width: (f64, f64), wcs: &str) -> Result<String> {
+    let output = Command::new("solve-field")
         .arg("--scale-units").arg("degwidth")
-        .arg("--scale-low").arg("10.2")
-        .arg("--scale-high").arg("10.4")
+        .arg("--scale-low").arg(width.0.to_string())
+        .arg("--scale-high").arg(width.1.to_string())
         .arg("--overwrite")
         .arg("--downsample").arg("4")
         .arg("--no-plots")
         .arg("--new-fits").arg("none")
-        .arg("--wcs").arg("none")
+        .arg("--wcs").arg(wcs)
         .arg("--match").arg("none")
         .arg("--rdls").arg("none")
         .arg("--solved").arg("none")
@@ -37,18 +61,10 @@ pub fn solve(path: &str) -> Result<(f64, f64)> {
         .output()
         .expect("failed to execute solve-field");
     info!("stderr: {}", String::from_utf8_lossy(&output.stderr));
-    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
     info!("stdout: {}", stdout);
     assert!(output.status.success());
-
-    let pattern = r"RA,Dec = \((.+),(.+)\), ";
-    let cap = Regex::new(pattern).unwrap()
-        .captures_iter(&stdout).next().ok_or_else(|| ErrorKind::NotSolved)?;
-    let ra = cap[1].parse::<f64>().unwrap();
-    let dec = cap[2].parse::<f64>().unwrap();
-    info!("ra: {} dec: {}", ra, dec);
-
-    Ok((ra, dec))
+    Ok(stdout)
 }
 
 #[cfg(test)]
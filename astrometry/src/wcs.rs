@@ -0,0 +1,99 @@
+//! The world coordinate system of a solved image, as the gnomonic (TAN) projection that
+//! solve-field writes. Distortion terms are ignored.
+
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use fits::{self, HeaderRecord};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Wcs {
+    /// RA and Dec of the reference pixel, in degrees.
+    pub crval: (f64, f64),
+    /// Reference pixel, 1 based like all FITS pixel coordinates.
+    pub crpix: (f64, f64),
+    /// Degrees per pixel.
+    pub cd: [[f64; 2]; 2],
+}
+
+impl Wcs {
+    pub fn from_header(records: &[HeaderRecord]) -> Self {
+        let value = |name: &str| fits::get_header_value(records, name).trim().parse::<f64>().unwrap();
+        Wcs {
+            crval: (value("CRVAL1"), value("CRVAL2")),
+            crpix: (value("CRPIX1"), value("CRPIX2")),
+            cd: [[value("CD1_1"), value("CD1_2")], [value("CD2_1"), value("CD2_2")]],
+        }
+    }
+
+    /// Reads the WCS from the primary header of a FITS file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Self {
+        let mut r = BufReader::new(File::open(path).unwrap());
+        Wcs::from_header(&fits::read_header(&mut r))
+    }
+
+    /// Where `ra` and `dec`, in degrees, are on the image, with pixel centers at +0.5.
+    /// None for the half of the sky the projection doesn't reach.
+    pub fn to_pixel(&self, ra: f64, dec: f64) -> Option<(f64, f64)> {
+        let (a, d) = (ra.to_radians(), dec.to_radians());
+        let (a0, d0) = (self.crval.0.to_radians(), self.crval.1.to_radians());
+        let den = d.sin() * d0.sin() + d.cos() * d0.cos() * (a - a0).cos();
+        if den <= 0.0 {
+            return None;
+        }
+        let xi = (d.cos() * (a - a0).sin() / den).to_degrees();
+        let eta = ((d.sin() * d0.cos() - d.cos() * d0.sin() * (a - a0).cos()) / den).to_degrees();
+        let cd = &self.cd;
+        let det = cd[0][0] * cd[1][1] - cd[0][1] * cd[1][0];
+        let u = (cd[1][1] * xi - cd[0][1] * eta) / det;
+        let v = (cd[0][0] * eta - cd[1][0] * xi) / det;
+        Some((u + self.crpix.0 - 0.5, v + self.crpix.1 - 0.5))
+    }
+
+    /// RA and Dec, in degrees, of a point on the image.
+    pub fn to_sky(&self, x: f64, y: f64) -> (f64, f64) {
+        let (u, v) = (x + 0.5 - self.crpix.0, y + 0.5 - self.crpix.1);
+        let xi = (self.cd[0][0] * u + self.cd[0][1] * v).to_radians();
+        let eta = (self.cd[1][0] * u + self.cd[1][1] * v).to_radians();
+        let (a0, d0) = (self.crval.0.to_radians(), self.crval.1.to_radians());
+        let den = d0.cos() - eta * d0.sin();
+        let a = a0 + xi.atan2(den);
+        let d = (eta * d0.cos() + d0.sin()).atan2((xi * xi + den * den).sqrt());
+        let a = a.to_degrees();
+        (if a < 0.0 { a + 360.0 } else if a >= 360.0 { a - 360.0 } else { a }, d.to_degrees())
+    }
+}
+
+/// Angle between two points on the sky, in degrees.
+pub fn separation(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (a1, d1) = (a.0.to_radians(), a.1.to_radians());
+    let (a2, d2) = (b.0.to_radians(), b.1.to_radians());
+    // haversine, which stays accurate for small angles
+    let h = ((d1 - d2) / 2.0).sin().powi(2) + d1.cos() * d2.cos() * ((a1 - a2) / 2.0).sin().powi(2);
+    2.0 * h.sqrt().min(1.0).asin() * 180.0 / PI
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // 10 arcseconds per pixel, slightly rotated, near the pole where RA wraps.
+        let s = 10.0 / 3600.0;
+        let wcs = Wcs {
+            crval: (359.9, 80.0),
+            crpix: (1000.5, 750.5),
+            cd: [[-s * 0.99, s * 0.1], [s * 0.1, s * 0.99]],
+        };
+        let (ra, dec) = wcs.to_sky(1000.0, 750.0);
+        assert!(separation((ra, dec), wcs.crval) < 1e-9);
+        for &(x, y) in [(0.0, 0.0), (2000.0, 1500.0), (123.4, 1400.6)].iter() {
+            let (ra, dec) = wcs.to_sky(x, y);
+            let (px, py) = wcs.to_pixel(ra, dec).unwrap();
+            assert!((px - x).abs() < 1e-6 && (py - y).abs() < 1e-6, "{} {} -> {} {}", x, y, px, py);
+        }
+        assert_eq!(wcs.to_pixel(179.9, -80.0), None);
+    }
+}
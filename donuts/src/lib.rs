@@ -10,8 +10,10 @@ extern crate geom;
 #[cfg(test)] extern crate test;
 #[cfg(test)] extern crate rand;
 #[cfg(test)] extern crate byteorder;
+#[cfg(test)] extern crate star_stuff;
 
 pub mod remove_background;
+pub mod local_normalization;
 pub mod projection;
 pub mod correlation;
 pub mod align;
@@ -26,8 +28,11 @@ use image::Image;
 use projection::Projection;
 
 
+/// Wavelet layers under the background, which has no features smaller than 2^5 = 32 pixels.
+const BACKGROUND_LAYERS: usize = 5;
+
 pub fn preprocess_image(mut image: Image<f32>) -> Projection {
-    remove_background::remove_background(&mut image, 32);
+    remove_background::remove_background_wavelets(&mut image, BACKGROUND_LAYERS);
     Projection::new(&image)
 }
 
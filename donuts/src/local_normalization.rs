@@ -0,0 +1,183 @@
+//! Offset and gain of a frame relative to the reference, estimated on a grid of tiles,
+//! so that gradients which differ from frame to frame are matched too.
+//!
+//! Both images must be registered onto the same grid; pixels that are NaN are left out.
+//! A frame is modelled as `offset + gain * reference`.
+
+use image::Image;
+use image::stats::median;
+use remove_background::{map_tiles, tile_bounds};
+
+/// Median and MAD of each tile.
+pub struct TileStats {
+    pub medians: Image<f32>,
+    pub mads: Image<f32>,
+    width: usize,
+    height: usize,
+}
+
+impl TileStats {
+    pub fn new(image: &Image<f32>, tiles: usize) -> Self {
+        let medians = map_tiles(image, tiles, |_, _, tile| {
+            tile.retain(|v| !v.is_nan());
+            median(tile)
+        });
+        let mads = map_tiles(image, tiles, |x, y, tile| {
+            let m = medians.pixels[y * tiles + x];
+            tile.retain(|v| !v.is_nan());
+            for v in tile.iter_mut() {
+                *v = (*v - m).abs();
+            }
+            1.4826 * median(tile)
+        });
+        TileStats { medians, mads, width: image.width, height: image.height }
+    }
+
+    fn tiles(&self) -> usize {
+        self.medians.width
+    }
+}
+
+/// Offset and gain of each tile, smoothed.
+pub struct Maps {
+    pub offset: Image<f32>,
+    pub gain: Image<f32>,
+    centers_x: Vec<f32>,
+    centers_y: Vec<f32>,
+}
+
+impl Maps {
+    /// Estimates the maps of `frame` against `reference`. Without `fit_offset` the offset is 0,
+    /// without `fit_gain` the gain is 1. With both, the gain is the ratio of the MADs.
+    pub fn new(reference: &TileStats, frame: &TileStats, fit_offset: bool, fit_gain: bool) -> Self {
+        assert_eq!(reference.tiles(), frame.tiles());
+        let tiles = reference.tiles();
+        let n = tiles * tiles;
+        let mut offset = vec![0.0; n];
+        let mut gain = vec![1.0; n];
+        for i in 0..n {
+            let (mr, mf) = (reference.medians.pixels[i], frame.medians.pixels[i]);
+            let (sr, sf) = (reference.mads.pixels[i], frame.mads.pixels[i]);
+            match (fit_offset, fit_gain) {
+                (true, true) => {
+                    gain[i] = if sr > 0.0 && sf > 0.0 { sf / sr } else { ::std::f32::NAN };
+                    offset[i] = mf - gain[i] * mr;
+                }
+                (true, false) => offset[i] = mf - mr,
+                (false, true) => gain[i] = if mr != 0.0 { mf / mr } else { ::std::f32::NAN },
+                (false, false) => {}
+            }
+        }
+        let square = |pixels| Image { width: tiles, height: tiles, pixels: pixels };
+        let centers_x = (0..tiles).map(|t| {
+            let (x1, _, x2, _) = tile_bounds(reference.width, reference.height, tiles, t, 0);
+            (x1 + x2) as f32 / 2.0
+        }).collect();
+        let centers_y = (0..tiles).map(|t| {
+            let (_, y1, _, y2) = tile_bounds(reference.width, reference.height, tiles, 0, t);
+            (y1 + y2) as f32 / 2.0
+        }).collect();
+        Maps {
+            offset: smooth(&square(offset), 0.0),
+            gain: smooth(&square(gain), 1.0),
+            centers_x: centers_x,
+            centers_y: centers_y,
+        }
+    }
+
+    /// Offset and gain at `(x, y)` of the image, interpolated between the tile centers.
+    pub fn at(&self, x: f32, y: f32) -> (f32, f32) {
+        let (x0, x1, fx) = between(&self.centers_x, x);
+        let (y0, y1, fy) = between(&self.centers_y, y);
+        let lerp = |map: &Image<f32>| {
+            let at = |x: usize, y: usize| map.pixels[y * map.width + x];
+            let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+            let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+            top * (1.0 - fy) + bottom * fy
+        };
+        (lerp(&self.offset), lerp(&self.gain))
+    }
+}
+
+/// The two neighbouring centers of `v`, and how far `v` is from the first towards the second.
+fn between(centers: &[f32], v: f32) -> (usize, usize, f32) {
+    let last = centers.len() - 1;
+    if v <= centers[0] {
+        return (0, 0, 0.0);
+    }
+    if v >= centers[last] {
+        return (last, last, 0.0);
+    }
+    let i = centers.iter().position(|&c| c > v).unwrap() - 1;
+    (i, i + 1, (v - centers[i]) / (centers[i + 1] - centers[i]))
+}
+
+/// Averages each tile with its neighbours, which evens out tiles with a bright star or a
+/// satellite trail. Tiles that are NaN are left out, and filled with the median of the rest,
+/// or `default` if all tiles are NaN.
+fn smooth(map: &Image<f32>, default: f32) -> Image<f32> {
+    let (w, h) = (map.width as isize, map.height as isize);
+    let mut valid: Vec<f32> = map.pixels.iter().cloned().filter(|v| v.is_finite()).collect();
+    let fill = if valid.is_empty() { default } else { median(&mut valid) };
+    let mut pixels = Vec::with_capacity(map.pixels.len());
+    for y in 0..h {
+        for x in 0..w {
+            let (mut sum, mut n) = (0.0, 0);
+            for dy in -1..2 {
+                for dx in -1..2 {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx >= 0 && nx < w && ny >= 0 && ny < h {
+                        let v = map.pixels[(ny * w + nx) as usize];
+                        if v.is_finite() {
+                            sum += v;
+                            n += 1;
+                        }
+                    }
+                }
+            }
+            pixels.push(if n > 0 { sum / n as f32 } else { fill });
+        }
+    }
+    Image { width: map.width, height: map.height, pixels: pixels }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use star_stuff::test_support::{self, image};
+
+    fn sky(x: usize, y: usize) -> f32 {
+        test_support::sky(x, y) as f32
+    }
+
+    #[test]
+    fn constant_offset_and_gain() {
+        let reference = TileStats::new(&image(64, 64, sky), 4);
+        let frame = TileStats::new(&image(64, 64, |x, y| 30.0 + 2.0 * sky(x, y)), 4);
+        let maps = Maps::new(&reference, &frame, true, true);
+        for &(x, y) in [(0.0, 0.0), (31.5, 20.0), (64.0, 64.0)].iter() {
+            let (offset, gain) = maps.at(x, y);
+            assert!((offset - 30.0).abs() < 1e-3 && (gain - 2.0).abs() < 1e-3, "{} {}", offset, gain);
+        }
+    }
+
+    #[test]
+    fn follows_a_gradient() {
+        let reference = TileStats::new(&image(80, 80, sky), 8);
+        let frame = TileStats::new(&image(80, 80, |x, y| sky(x, y) + x as f32), 8);
+        let maps = Maps::new(&reference, &frame, true, false);
+        // The offset at each tile center is the gradient there, apart from the smoothing at the edges.
+        let (offset, gain) = maps.at(45.0, 40.0);
+        assert!((offset - 45.0).abs() < 1.0, "{}", offset);
+        assert_eq!(gain, 1.0);
+        assert!(maps.at(25.0, 40.0).0 < maps.at(55.0, 40.0).0);
+    }
+
+    #[test]
+    fn ignores_missing_pixels() {
+        let reference = TileStats::new(&image(64, 64, sky), 4);
+        let frame = TileStats::new(&image(64, 64, |x, y| if x < 16 { ::std::f32::NAN } else { 10.0 + sky(x, y) }), 4);
+        let maps = Maps::new(&reference, &frame, true, false);
+        assert!((maps.at(0.0, 0.0).0 - 10.0).abs() < 1e-3);
+    }
+}
@@ -1,25 +1,58 @@
 use image::Image;
+use image::wavelets::Decomposition;
 use quickersort::sort_floats;
 
-pub fn remove_background(image: &mut Image<f32>, tiles: usize) {
-    let max = *image.pixels.iter().max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
-    let tile_w = image.width / tiles;
-    let tile_h = image.height / tiles;
-    let mut tile: Vec<f32> = Vec::with_capacity(tile_w * tile_h);
+/// The pixel ranges of the tiles of a `tiles` × `tiles` grid. The last row and column
+/// of tiles take up the remainder.
+pub fn tile_bounds(width: usize, height: usize, tiles: usize, tile_x: usize, tile_y: usize) -> (usize, usize, usize, usize) {
+    let tile_w = width / tiles;
+    let tile_h = height / tiles;
+    let x1 = tile_x * tile_w;
+    let y1 = tile_y * tile_h;
+    let x2 = if tile_x == tiles - 1 { width } else { x1 + tile_w };
+    let y2 = if tile_y == tiles - 1 { height } else { y1 + tile_h };
+    (x1, y1, x2, y2)
+}
+
+/// Calls `f(tile_x, tile_y, pixels)` for each tile of a `tiles` × `tiles` grid,
+/// and returns the results as a `tiles` × `tiles` image.
+pub fn map_tiles<F>(image: &Image<f32>, tiles: usize, mut f: F) -> Image<f32>
+where F: FnMut(usize, usize, &mut Vec<f32>) -> f32 {
+    let mut tile: Vec<f32> = Vec::with_capacity((image.width / tiles + 1) * (image.height / tiles + 1));
+    let mut out = Image {
+        width: tiles,
+        height: tiles,
+        pixels: vec![0.0; tiles * tiles],
+    };
     for tile_x in 0..tiles {
         for tile_y in 0..tiles {
             tile.clear();
-            let x1 = tile_x * tile_w;
-            let y1 = tile_y * tile_h;
-            let x2 = if tile_x == tiles - 1 { image.width } else { x1 + tile_w };
-            let y2 = if tile_y == tiles - 1 { image.height } else { y1 + tile_h };
-
+            let (x1, y1, x2, y2) = tile_bounds(image.width, image.height, tiles, tile_x, tile_y);
             for y in y1..y2 {
                 let start = y * image.width;
                 tile.extend_from_slice(&image.pixels[start + x1 .. start + x2]);
             }
-            sort_floats(&mut tile[..]);
-            let med = tile[tile.len() / 2];
+            out.pixels[tile_y * tiles + tile_x] = f(tile_x, tile_y, &mut tile);
+        }
+    }
+    out
+}
+
+/// Median of the pixels of each tile.
+pub fn tile_medians(image: &Image<f32>, tiles: usize) -> Image<f32> {
+    map_tiles(image, tiles, |_, _, tile| {
+        sort_floats(&mut tile[..]);
+        tile[tile.len() / 2]
+    })
+}
+
+pub fn remove_background(image: &mut Image<f32>, tiles: usize) {
+    let max = *image.pixels.iter().max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
+    let medians = tile_medians(image, tiles);
+    for tile_x in 0..tiles {
+        for tile_y in 0..tiles {
+            let (x1, y1, x2, y2) = tile_bounds(image.width, image.height, tiles, tile_x, tile_y);
+            let med = medians.pixels[tile_y * tiles + tile_x];
             let bg = med + (max - med) * 0.1; // chop out faint stars & noise
             for y in y1..y2 {
                 for x in x1..x2 {
@@ -34,6 +67,19 @@ pub fn remove_background(image: &mut Image<f32>, tiles: usize) {
     }
 }
 
+/// Like `remove_background`, with the residual of `layers` wavelet layers as the background,
+/// which follows gradients smoothly instead of in steps of tiles.
+pub fn remove_background_wavelets(image: &mut Image<f32>, layers: usize) {
+    let max = *image.pixels.iter().max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
+    let background = Decomposition::new(&image.pixels, image.width, image.height, layers).residual;
+    for (pixel, &bg) in image.pixels.iter_mut().zip(background.iter()) {
+        let bg = bg + (max - bg) * 0.1; // chop out faint stars & noise
+        *pixel -= bg;
+        if *pixel < 0.0 {
+            *pixel = 0.0;
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -41,6 +87,7 @@ mod tests {
     use test::Bencher;
     use rand::{self, Rng};
     use image::Image;
+    use projection::Projection;
 
     #[test]
     fn test_remove_background() {
@@ -50,6 +97,56 @@ mod tests {
         img.save("test/in-minus-background.jpg");
     }
 
+    /// Stars on a steep gradient, as a `preprocess_image` input.
+    fn stars_on_gradient() -> Image<f32> {
+        let (w, h) = (256, 256);
+        let stars = [(40.0, 60.0), (128.0, 128.0), (200.0, 90.0), (70.0, 210.0)];
+        let mut pixels = Vec::with_capacity(w * h);
+        for y in 0..h {
+            for x in 0..w {
+                let mut v = 100.0 + 2.0 * x as f32 + y as f32;
+                for &(sx, sy) in stars.iter() {
+                    let d2 = (x as f32 - sx).powi(2) + (y as f32 - sy).powi(2);
+                    v += 2000.0 * (-d2 / 8.0).exp();
+                }
+                pixels.push(v);
+            }
+        }
+        Image { width: w, height: h, pixels }
+    }
+
+    #[test]
+    fn wavelets_against_tiles() {
+        let mut tiles = stars_on_gradient();
+        remove_background(&mut tiles, 32);
+        let mut wavelets = stars_on_gradient();
+        remove_background_wavelets(&mut wavelets, 5);
+        // Both leave the stars and nothing of the gradient.
+        for img in [&tiles, &wavelets].iter() {
+            assert!(*img.pixel_at(128, 128) > 1000.0);
+            assert!(*img.pixel_at(200, 90) > 1000.0);
+            assert_eq!(*img.pixel_at(10, 10), 0.0);
+            assert_eq!(*img.pixel_at(250, 250), 0.0);
+        }
+        // What gets aligned are the projections, where the steps of the tiles pull the stars off center.
+        let (a, b) = (Projection::new(&tiles), Projection::new(&wavelets));
+        let centroid = |v: &[f32], c: usize| {
+            let (mut sum, mut total) = (0.0, 0.0);
+            for i in c - 8..c + 9 {
+                sum += i as f32 * v[i];
+                total += v[i];
+            }
+            sum / total - c as f32
+        };
+        for &(x, y) in [(40, 60), (128, 128), (200, 90)].iter() {
+            for &(projections, c) in [((&a.x, &b.x), x), ((&a.y, &b.y), y)].iter() {
+                let (tiles_error, wavelets_error) = (centroid(projections.0, c).abs(), centroid(projections.1, c).abs());
+                assert!(wavelets_error < 0.01, "star at {} off by {}", c, wavelets_error);
+                assert!(wavelets_error <= tiles_error, "star at {}: {} with tiles, {} with wavelets", c, tiles_error, wavelets_error);
+            }
+        }
+    }
+
     #[bench]
     fn bench(b: &mut Bencher) {
         let w = 2000;
@@ -1,6 +1,7 @@
 extern crate byteorder;
 
 use std::io::prelude::*;
+use std::iter;
 use std::str;
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
@@ -9,7 +10,7 @@ const RECORD_LEN: usize = 80;
 const NAME_LEN: usize = 8;
 const TEXT_LEN: usize = RECORD_LEN - NAME_LEN - 2;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HeaderRecord {
     pub name: String,
     pub value: Option<String>,
@@ -110,6 +111,16 @@ pub enum Data {
     F64(Vec<f64>),
 }
 
+/// HISTORY records of `text`, wrapped to fit.
+pub fn history(text: &str) -> Vec<HeaderRecord> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.chunks(TEXT_LEN).map(|chunk| HeaderRecord {
+        name: "HISTORY".to_string(),
+        value: None,
+        comment: chunk.iter().collect(),
+    }).collect()
+}
+
 pub fn get_header_value<'a>(records: &'a [HeaderRecord], name: &str) -> &'a String {
     if let Some(ref r) = records
         .iter()
@@ -125,17 +136,65 @@ pub fn get_header_value<'a>(records: &'a [HeaderRecord], name: &str) -> &'a Stri
 }
 
 pub fn write_image<W: Write>(w: &mut W, shape: &[usize], data: &Data) {
-    let bitpix = match data {
-        &Data::U16(_) => "16",
-        &Data::F32(_) => "-32",
-        &Data::F64(_) => "-64",
-    }.to_string();
+    write_image_with_header(w, shape, data, &[]);
+}
+
+/// Like `write_image`, with `extra` records, like HISTORY, added to the header.
+pub fn write_image_with_header<W: Write>(w: &mut W, shape: &[usize], data: &Data, extra: &[HeaderRecord]) {
     let mut header = vec![
         HeaderRecord {
             name: "SIMPLE".to_string(),
             value: Some("T".to_string()),
             comment: "".to_string(),
         },
+    ];
+    header.extend(image_header(shape, data));
+    header.push(HeaderRecord {
+        name: "EXTEND".to_string(),
+        value: Some("T".to_string()),
+        comment: "".to_string(),
+    });
+    header.extend(extra.iter().cloned());
+    write_header(w, &header[..]);
+    write_data(w, data);
+}
+
+/// Writes an IMAGE extension. Must come after `write_image`.
+pub fn write_extension<W: Write>(w: &mut W, name: &str, shape: &[usize], data: &Data) {
+    let mut header = vec![
+        HeaderRecord {
+            name: "XTENSION".to_string(),
+            value: Some("'IMAGE   '".to_string()),
+            comment: "".to_string(),
+        },
+    ];
+    header.extend(image_header(shape, data));
+    header.push(HeaderRecord {
+        name: "PCOUNT".to_string(),
+        value: Some("0".to_string()),
+        comment: "".to_string(),
+    });
+    header.push(HeaderRecord {
+        name: "GCOUNT".to_string(),
+        value: Some("1".to_string()),
+        comment: "".to_string(),
+    });
+    header.push(HeaderRecord {
+        name: "EXTNAME".to_string(),
+        value: Some(format!("'{}'", name)),
+        comment: "".to_string(),
+    });
+    write_header(w, &header[..]);
+    write_data(w, data);
+}
+
+fn image_header(shape: &[usize], data: &Data) -> Vec<HeaderRecord> {
+    let bitpix = match data {
+        &Data::U16(_) => "16",
+        &Data::F32(_) => "-32",
+        &Data::F64(_) => "-64",
+    }.to_string();
+    let mut header = vec![
         HeaderRecord {
             name: "BITPIX".to_string(),
             value: Some(bitpix),
@@ -154,23 +213,35 @@ pub fn write_image<W: Write>(w: &mut W, shape: &[usize], data: &Data) {
             comment: "".to_string(),
         });
     }
-    write_header(w, &header[..]);
-    match data {
+    header
+}
+
+fn write_data<W: Write>(w: &mut W, data: &Data) {
+    let len = match data {
         &Data::U16(ref vec) => {
             for &v in vec.iter() {
                 w.write_u16::<BigEndian>(v).unwrap();
             }
+            vec.len() * 2
         },
         &Data::F32(ref vec) => {
             for &v in vec.iter() {
                 w.write_f32::<BigEndian>(v).unwrap();
             }
+            vec.len() * 4
         },
         &Data::F64(ref vec) => {
             for &v in vec.iter() {
                 w.write_f64::<BigEndian>(v).unwrap();
             }
+            vec.len() * 8
         },
+    };
+    // the data unit is zero-padded to a whole block, so the next HDU starts on a block boundary
+    let block_len = RECORDS_PER_BLOCK * RECORD_LEN;
+    let zero = [0u8];
+    for _ in 0..(block_len - len % block_len) % block_len {
+        w.write_all(&zero[..]).unwrap();
     }
 }
 
@@ -212,6 +283,22 @@ pub fn read_image<R: Read>(r: &mut R) -> (Vec<usize>, Data) {
     (shape, data)
 }
 
+/// Reads a mono image, or an RGB one with the channels as the first axis, as RGB values in
+/// pixel order, mono as gray. Returns the width, the height and the values.
+pub fn read_rgb<R: Read>(r: &mut R) -> (usize, usize, Vec<f64>) {
+    let (shape, data) = read_image(r);
+    let data: Vec<f64> = match data {
+        Data::U16(v) => v.into_iter().map(|v| v as f64).collect(),
+        Data::F32(v) => v.into_iter().map(|v| v as f64).collect(),
+        Data::F64(v) => v,
+    };
+    match shape.len() {
+        2 => (shape[0], shape[1], data.into_iter().flat_map(|v| iter::repeat(v).take(3)).collect()),
+        3 if shape[0] == 3 => (shape[1], shape[2], data),
+        _ => panic!("not a mono or RGB image: {:?}", shape),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -227,4 +314,40 @@ mod tests {
         //let (w,h,d) = read_image(&mut f);
         //println!("{}x{}", w, h);
     }
+
+    #[test]
+    fn test_write_extension() {
+        let mut buf = vec![];
+        write_image(&mut buf, &[2, 3], &Data::F32(vec![0.0; 6]));
+        assert_eq!(buf.len(), 2 * 2880);
+        write_extension(&mut buf, "WEIGHT", &[2, 3], &Data::F64(vec![1.0; 6]));
+        assert_eq!(buf.len(), 4 * 2880);
+        assert_eq!(&buf[2 * 2880..2 * 2880 + 20], b"XTENSION= 'IMAGE   '");
+
+        let mut r = &buf[..];
+        let (shape, _) = read_image(&mut r);
+        assert_eq!(shape, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_read_rgb() {
+        let mut buf = vec![];
+        write_image(&mut buf, &[2, 1], &Data::U16(vec![1, 2]));
+        assert_eq!(read_rgb(&mut &buf[..]), (2, 1, vec![1.0, 1.0, 1.0, 2.0, 2.0, 2.0]));
+        let mut buf = vec![];
+        write_image(&mut buf, &[3, 1, 2], &Data::F32(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]));
+        assert_eq!(read_rgb(&mut &buf[..]), (1, 2, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]));
+    }
+
+    #[test]
+    fn test_history() {
+        let text: String = (0..100).map(|i| (b'a' + i % 26) as char).collect();
+        let mut buf = vec![];
+        write_image_with_header(&mut buf, &[2, 3], &Data::F32(vec![0.0; 6]), &history(&text));
+        let mut r = &buf[..];
+        let header = read_header(&mut r);
+        let lines: Vec<&str> = header.iter().filter(|r| r.name == "HISTORY").map(|r| &r.comment[..]).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines.concat(), text);
+    }
 }
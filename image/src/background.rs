@@ -0,0 +1,382 @@
+//! Extracts a smooth background, like a light pollution gradient, from a stacked image.
+//!
+//! The background is sampled with the median of each box of a grid, then a surface is fitted
+//! to the samples of each channel. Samples well above the surface are stars or nebulosity,
+//! and are left out of the next fit, until none are.
+
+use std::f64;
+use std::str::FromStr;
+use image::{Image, OwnedImage, ImageDimensions};
+use rgb::Rgb;
+use linalg;
+use stats::median;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Surface {
+    /// 2D polynomial of the given degree.
+    Polynomial(usize),
+    /// Thin plate spline through the samples, relaxed by the smoothing factor.
+    Spline(f64),
+}
+
+impl Surface {
+    /// The fewest samples the surface can be fitted to.
+    fn min_samples(&self) -> usize {
+        match *self {
+            Surface::Polynomial(degree) => monomials(degree, 0.0, 0.0).len() + 1,
+            Surface::Spline(_) => 4,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Correction {
+    /// For additive gradients like light pollution.
+    Subtract,
+    /// For multiplicative ones like vignetting.
+    Divide,
+}
+
+impl FromStr for Correction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "subtract" => Ok(Correction::Subtract),
+            "divide" => Ok(Correction::Divide),
+            _ => Err(format!("unknown correction: {} (expected subtract or divide)", s))
+        }
+    }
+}
+
+/// The median of a box of the grid, placed at its center.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub x: f64,
+    pub y: f64,
+    pub value: Rgb<f64>,
+}
+
+/// Rejection passes before giving up on converging.
+const MAX_PASSES: usize = 10;
+
+/// Dividing treats the background as at least this fraction of its median level, so pixels
+/// where the fit gets close to or below 0 don't blow up.
+const DIVIDE_FLOOR: f64 = 0.01;
+
+/// The model is evaluated every this many pixels and interpolated in between.
+const STEP: usize = 16;
+
+/// One sample per box of a `grid` × `grid` grid. Boxes that are mostly not covered,
+/// that is NaN or 0 in all channels, get none.
+pub fn samples(img: &OwnedImage<Rgb<f64>>, grid: usize) -> Vec<Sample> {
+    let dim = img.dimensions();
+    let mut samples = Vec::with_capacity(grid * grid);
+    let (mut r, mut g, mut b) = (Vec::new(), Vec::new(), Vec::new());
+    for j in 0..grid {
+        for i in 0..grid {
+            let (x1, x2) = (i * dim.width / grid, (i + 1) * dim.width / grid);
+            let (y1, y2) = (j * dim.height / grid, (j + 1) * dim.height / grid);
+            r.clear();
+            g.clear();
+            b.clear();
+            for y in y1..y2 {
+                for p in img.row(y)[x1..x2].iter().filter(|p| is_covered(p)) {
+                    r.push(p.r);
+                    g.push(p.g);
+                    b.push(p.b);
+                }
+            }
+            if r.len() * 2 < (x2 - x1) * (y2 - y1) {
+                continue;
+            }
+            samples.push(Sample {
+                x: (x1 + x2) as f64 / 2.0,
+                y: (y1 + y2) as f64 / 2.0,
+                value: Rgb { r: median(&mut r), g: median(&mut g), b: median(&mut b) },
+            });
+        }
+    }
+    samples
+}
+
+/// A rejection pass of `extract`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Pass {
+    /// Samples the surface was fitted to.
+    pub samples: usize,
+    /// Samples too far above the fit, left out of the next pass.
+    pub rejected: usize,
+    /// Rejecting would have left too few samples, so the fit was kept as it is.
+    pub stopped: bool,
+}
+
+/// The background fitted by `extract`.
+pub struct Extraction {
+    pub background: OwnedImage<Rgb<f64>>,
+    /// The samples that were kept.
+    pub samples: Vec<Sample>,
+    pub passes: Vec<Pass>,
+}
+
+/// Fits `surface` to the samples, rejecting those more than `tolerance` standard deviations
+/// above it. Rejection stops early rather than leave too few samples for the surface.
+/// Fails if the grid has too few samples to start with.
+pub fn extract(img: &OwnedImage<Rgb<f64>>, surface: Surface, grid: usize, tolerance: f64) -> Result<Extraction, String> {
+    let dim = img.dimensions();
+    let frame = Frame::new(dim.width, dim.height);
+    let mut samples = samples(img, grid);
+    if samples.len() < surface.min_samples() {
+        return Err(format!("{} background samples, but {:?} needs at least {}; use a finer grid or a simpler surface",
+                           samples.len(), surface, surface.min_samples()));
+    }
+    let mut fits;
+    let mut passes = Vec::new();
+    loop {
+        fits = Rgb {
+            r: Fit::new(surface, &frame, &samples, |s| s.value.r),
+            g: Fit::new(surface, &frame, &samples, |s| s.value.g),
+            b: Fit::new(surface, &frame, &samples, |s| s.value.b),
+        };
+        let residuals: Vec<f64> = samples.iter().map(|s| {
+            let (u, v) = frame.to_unit(s.x, s.y);
+            ((s.value.r - fits.r.at(u, v)) + (s.value.g - fits.g.at(u, v)) + (s.value.b - fits.b.at(u, v))) / 3.0
+        }).collect();
+        let mut deviations: Vec<f64> = residuals.iter().map(|r| r.abs()).collect();
+        let sigma = 1.4826 * median(&mut deviations);
+        let before = samples.len();
+        let kept: Vec<Sample> = samples.iter().zip(residuals.into_iter())
+            .filter(|&(_, r)| sigma == 0.0 || r <= tolerance * sigma)
+            .map(|(&s, _)| s)
+            .collect();
+        if kept.len() < surface.min_samples() {
+            passes.push(Pass { samples: before, rejected: before - kept.len(), stopped: true });
+            break;
+        }
+        samples = kept;
+        passes.push(Pass { samples: before, rejected: before - samples.len(), stopped: false });
+        if samples.len() == before || passes.len() == MAX_PASSES {
+            break;
+        }
+    }
+    Ok(Extraction { background: render(&fits, &frame), samples, passes })
+}
+
+/// Removes `background` from `img`, keeping its median level so nothing goes negative.
+pub fn correct(img: &OwnedImage<Rgb<f64>>, background: &OwnedImage<Rgb<f64>>, correction: Correction) -> OwnedImage<Rgb<f64>> {
+    let channel = |f: &Fn(&Rgb<f64>) -> f64| median(&mut background.pixels().iter().map(f).collect::<Vec<_>>());
+    let level = Rgb { r: channel(&|p| p.r), g: channel(&|p| p.g), b: channel(&|p| p.b) };
+    let f = |v: f64, m: f64, level: f64| match correction {
+        Correction::Subtract => v - m + level,
+        Correction::Divide if level > 0.0 => v / m.max(DIVIDE_FLOOR * level) * level,
+        Correction::Divide => v,
+    };
+    let pixels = img.pixels().iter().zip(background.pixels().iter()).map(|(p, m)| Rgb {
+        r: f(p.r, m.r, level.r),
+        g: f(p.g, m.g, level.g),
+        b: f(p.b, m.b, level.b),
+    }).collect();
+    OwnedImage { dimensions: img.dimensions(), pixels }
+}
+
+fn is_covered(p: &Rgb<f64>) -> bool {
+    p.r.is_finite() && p.g.is_finite() && p.b.is_finite() && (p.r != 0.0 || p.g != 0.0 || p.b != 0.0)
+}
+
+/// Maps pixels to coordinates around 0 that are the same scale on both axes, which keeps
+/// the fits well conditioned.
+struct Frame {
+    width: usize,
+    height: usize,
+    scale: f64,
+}
+
+impl Frame {
+    fn new(width: usize, height: usize) -> Self {
+        Frame { width, height, scale: width.max(height) as f64 / 2.0 }
+    }
+
+    fn to_unit(&self, x: f64, y: f64) -> (f64, f64) {
+        ((x - self.width as f64 / 2.0) / self.scale, (y - self.height as f64 / 2.0) / self.scale)
+    }
+}
+
+enum Fit {
+    Polynomial { degree: usize, coefficients: Vec<f64> },
+    Spline { centers: Vec<(f64, f64)>, weights: Vec<f64>, affine: [f64; 3] },
+}
+
+impl Fit {
+    fn new<F: Fn(&Sample) -> f64>(surface: Surface, frame: &Frame, samples: &[Sample], value: F) -> Self {
+        let points: Vec<(f64, f64)> = samples.iter().map(|s| frame.to_unit(s.x, s.y)).collect();
+        let values: Vec<f64> = samples.iter().map(value).collect();
+        match surface {
+            Surface::Polynomial(degree) => {
+                assert!(points.len() >= surface.min_samples(), "too few background samples for a polynomial of degree {}", degree);
+                let rows: Vec<Vec<f64>> = points.iter().map(|&(u, v)| monomials(degree, u, v)).collect();
+                Fit::Polynomial { degree, coefficients: linalg::least_squares(&rows, &values) }
+            }
+            Surface::Spline(smoothing) => {
+                let n = points.len();
+                assert!(n >= surface.min_samples(), "too few background samples for a spline");
+                // [K + λI P; Pᵀ 0] [w; a] = [z; 0]
+                let m = n + 3;
+                let mut a = vec![0.0; m * m];
+                let mut b = vec![0.0; m];
+                for i in 0..n {
+                    for j in 0..n {
+                        a[i * m + j] = tps(points[i], points[j]);
+                    }
+                    a[i * m + i] += smoothing;
+                    let p = [1.0, points[i].0, points[i].1];
+                    for k in 0..3 {
+                        a[i * m + n + k] = p[k];
+                        a[(n + k) * m + i] = p[k];
+                    }
+                    b[i] = values[i];
+                }
+                let x = linalg::solve(a, b);
+                Fit::Spline { centers: points, weights: x[..n].to_vec(), affine: [x[n], x[n + 1], x[n + 2]] }
+            }
+        }
+    }
+
+    fn at(&self, u: f64, v: f64) -> f64 {
+        match *self {
+            Fit::Polynomial { degree, ref coefficients } =>
+                monomials(degree, u, v).iter().zip(coefficients.iter()).map(|(m, c)| m * c).sum(),
+            Fit::Spline { ref centers, ref weights, affine } =>
+                affine[0] + affine[1] * u + affine[2] * v
+                    + centers.iter().zip(weights.iter()).map(|(&c, w)| w * tps((u, v), c)).sum::<f64>(),
+        }
+    }
+}
+
+/// `u^i v^j` for `i + j <= degree`.
+fn monomials(degree: usize, u: f64, v: f64) -> Vec<f64> {
+    let mut terms = Vec::new();
+    for i in 0..degree + 1 {
+        for j in 0..degree + 1 - i {
+            terms.push(u.powi(i as i32) * v.powi(j as i32));
+        }
+    }
+    terms
+}
+
+/// Thin plate spline kernel, `r² ln r`.
+fn tps(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let r2 = (a.0 - b.0).powi(2) + (a.1 - b.1).powi(2);
+    if r2 == 0.0 { 0.0 } else { 0.5 * r2 * r2.ln() }
+}
+
+/// Evaluates the fits on a coarse grid and interpolates bilinearly in between.
+fn render(fits: &Rgb<Fit>, frame: &Frame) -> OwnedImage<Rgb<f64>> {
+    let (w, h) = (frame.width, frame.height);
+    let (gw, gh) = ((w + STEP - 1) / STEP + 1, (h + STEP - 1) / STEP + 1);
+    let mut coarse = Vec::with_capacity(gw * gh);
+    for j in 0..gh {
+        for i in 0..gw {
+            let (u, v) = frame.to_unit((i * STEP) as f64, (j * STEP) as f64);
+            coarse.push(Rgb { r: fits.r.at(u, v), g: fits.g.at(u, v), b: fits.b.at(u, v) });
+        }
+    }
+    let mut pixels = Vec::with_capacity(w * h);
+    for y in 0..h {
+        let (j, fy) = (y / STEP, (y % STEP) as f64 / STEP as f64);
+        for x in 0..w {
+            let (i, fx) = (x / STEP, (x % STEP) as f64 / STEP as f64);
+            let at = |i: usize, j: usize| coarse[j * gw + i];
+            let top = at(i, j) * (1.0 - fx) + at(i + 1, j) * fx;
+            let bottom = at(i, j + 1) * (1.0 - fx) + at(i + 1, j + 1) * fx;
+            pixels.push(top * (1.0 - fy) + bottom * fy);
+        }
+    }
+    OwnedImage { dimensions: ImageDimensions { width: w, height: h, pitch: w }, pixels }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> OwnedImage<Rgb<f64>> {
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let v = 100.0 + 0.5 * x as f64 + 0.25 * y as f64;
+                pixels.push(Rgb { r: v, g: 2.0 * v, b: v });
+            }
+        }
+        OwnedImage { dimensions: ImageDimensions { width, height, pitch: width }, pixels }
+    }
+
+    #[test]
+    fn removes_a_linear_gradient() {
+        let mut img = gradient(160, 120);
+        // A nebula in one corner, which the fit has to leave out.
+        for y in 10..40 {
+            for x in 10..50 {
+                img.pixels[y * 160 + x].g += 500.0;
+            }
+        }
+        for &surface in [Surface::Polynomial(1), Surface::Spline(0.1)].iter() {
+            let extraction = extract(&img, surface, 8, 3.0).unwrap();
+            assert!(extraction.samples.len() < 64, "{:?}: nebula not rejected", surface);
+            let corrected = correct(&img, &extraction.background, Correction::Subtract);
+            let p = corrected.pixel_at(120, 100);
+            assert!((p.g - corrected.pixel_at(60, 60).g).abs() < 1.0, "{:?}: {:?}", surface, p);
+            assert!(corrected.pixel_at(20, 20).g > p.g + 400.0);
+        }
+    }
+
+    #[test]
+    fn rejection_leaves_enough_samples() {
+        // Four samples, one of them raised, and no tolerance: rejecting would leave too few.
+        let mut img = gradient(40, 40);
+        for y in 0..20 {
+            for x in 0..20 {
+                img.pixels[y * 40 + x].r += 50.0;
+            }
+        }
+        let extraction = extract(&img, Surface::Polynomial(1), 2, 0.0).unwrap();
+        assert_eq!(extraction.samples.len(), 4);
+        assert!(extraction.passes.len() == 1 && extraction.passes[0].stopped);
+    }
+
+    #[test]
+    fn divide_where_the_background_crosses_zero() {
+        let img = gradient(40, 1);
+        let background = OwnedImage {
+            dimensions: img.dimensions(),
+            pixels: (0..40).map(|x| {
+                let v = 10.0 * x as f64 - 100.0;
+                Rgb { r: v, g: v, b: v }
+            }).collect(),
+        };
+        let corrected = correct(&img, &background, Correction::Divide);
+        for p in corrected.pixels() {
+            assert!(p.r.is_finite() && p.r > 0.0 && p.r < 100.0 * img.pixel_at(39, 0).r, "{:?}", p);
+        }
+        // Above the floor, the median level of 95 is kept as usual.
+        assert_eq!(corrected.pixel_at(30, 0).g, img.pixel_at(30, 0).g / 200.0 * 95.0);
+    }
+
+    #[test]
+    fn too_few_samples() {
+        let img = gradient(40, 40);
+        // A cubic has 10 terms, more than the 9 samples of a 3 × 3 grid.
+        assert!(extract(&img, Surface::Polynomial(3), 3, 3.0).is_err());
+        assert!(extract(&img, Surface::Spline(0.1), 0, 3.0).is_err());
+        assert!(extract(&img, Surface::Polynomial(2), 3, 3.0).is_ok());
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut img = gradient(40, 40);
+        for p in img.pixels[..40 * 15].iter_mut() {
+            *p = Rgb { r: f64::NAN, g: f64::NAN, b: f64::NAN };
+        }
+        let samples = samples(&img, 2);
+        assert_eq!(samples.len(), 2);
+        assert_eq!((samples[0].x, samples[0].y), (10.0, 30.0));
+    }
+}
@@ -0,0 +1,149 @@
+//! Color adjustments: SCNR to take out a green or magenta cast, and saturation in LCh,
+//! the polar form of CIE L*a*b*, which changes colorfulness without touching lightness or hue.
+//!
+//! Pixels are taken as linear RGB with sRGB primaries, scaled to 0..1.
+
+use std::str::FromStr;
+use num::Float;
+use image::{Image, OwnedImage};
+use rgb::Rgb;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Cast {
+    Green,
+    /// Removed as the green cast of the inverted image.
+    Magenta,
+}
+
+impl FromStr for Cast {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "green" => Ok(Cast::Green),
+            "magenta" => Ok(Cast::Magenta),
+            _ => Err(format!("unknown cast: {} (expected green or magenta)", s))
+        }
+    }
+}
+
+/// What green is limited to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Protection {
+    /// The average of red and blue. Removes the most.
+    AverageNeutral,
+    /// The larger of red and blue, which keeps more of the greens that are really there.
+    MaximumNeutral,
+}
+
+impl FromStr for Protection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "average" => Ok(Protection::AverageNeutral),
+            "maximum" => Ok(Protection::MaximumNeutral),
+            _ => Err(format!("unknown protection: {} (expected average or maximum)", s))
+        }
+    }
+}
+
+/// Subtractive chromatic noise reduction. `amount` blends between the image, at 0,
+/// and the fully corrected one, at 1.
+pub fn scnr<P: Float>(img: &OwnedImage<Rgb<P>>, cast: Cast, protection: Protection, amount: P) -> OwnedImage<Rgb<P>> {
+    let one = P::one();
+    let two = one + one;
+    let green = |p: Rgb<P>| {
+        let neutral = match protection {
+            Protection::AverageNeutral => (p.r + p.b) / two,
+            Protection::MaximumNeutral => p.r.max(p.b),
+        };
+        Rgb { r: p.r, g: p.g * (one - amount) + p.g.min(neutral) * amount, b: p.b }
+    };
+    img.clone_map(|p| match cast {
+        Cast::Green => green(p),
+        Cast::Magenta => {
+            let p = green(Rgb { r: one - p.r, g: one - p.g, b: one - p.b });
+            Rgb { r: one - p.r, g: one - p.g, b: one - p.b }
+        }
+    })
+}
+
+/// Multiplies the chroma of every pixel by `amount`: 0 makes the image gray, 1 leaves it as it is.
+/// Negative channels that come out of colors outside the RGB gamut are clipped to 0.
+pub fn saturation<P: Float>(img: &OwnedImage<Rgb<P>>, amount: f64) -> OwnedImage<Rgb<P>> {
+    img.clone_map(|p| {
+        let p = Rgb { r: p.r.to_f64().unwrap(), g: p.g.to_f64().unwrap(), b: p.b.to_f64().unwrap() };
+        let (l, c, h) = to_lch(p);
+        let p = from_lch((l, c * amount, h));
+        let f = |v: f64| P::from(v.max(0.0)).unwrap();
+        Rgb { r: f(p.r), g: f(p.g), b: f(p.b) }
+    })
+}
+
+/// D65 white point.
+const WHITE: (f64, f64, f64) = (0.95047, 1.0, 1.08883);
+
+/// CIE L*a*b* of a linear sRGB color.
+pub fn to_lab(p: Rgb<f64>) -> (f64, f64, f64) {
+    let x = 0.4124564 * p.r + 0.3575761 * p.g + 0.1804375 * p.b;
+    let y = 0.2126729 * p.r + 0.7151522 * p.g + 0.0721750 * p.b;
+    let z = 0.0193339 * p.r + 0.1191920 * p.g + 0.9503041 * p.b;
+    let f = |t: f64| if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 };
+    let (fx, fy, fz) = (f(x / WHITE.0), f(y / WHITE.1), f(z / WHITE.2));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+pub fn from_lab((l, a, b): (f64, f64, f64)) -> Rgb<f64> {
+    let fy = (l + 16.0) / 116.0;
+    let (fx, fz) = (fy + a / 500.0, fy - b / 200.0);
+    let f = |t: f64| if t.powi(3) > 216.0 / 24389.0 { t.powi(3) } else { (116.0 * t - 16.0) * 27.0 / 24389.0 };
+    let (x, y, z) = (f(fx) * WHITE.0, f(fy) * WHITE.1, f(fz) * WHITE.2);
+    Rgb {
+        r: 3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        g: -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        b: 0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    }
+}
+
+/// Lightness, chroma and hue, in radians.
+pub fn to_lch(p: Rgb<f64>) -> (f64, f64, f64) {
+    let (l, a, b) = to_lab(p);
+    (l, a.hypot(b), b.atan2(a))
+}
+
+pub fn from_lch((l, c, h): (f64, f64, f64)) -> Rgb<f64> {
+    from_lab((l, c * h.cos(), c * h.sin()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::row;
+
+    #[test]
+    fn test_scnr() {
+        let img = row(vec![Rgb { r: 0.2, g: 0.5, b: 0.4 }, Rgb { r: 0.5, g: 0.3, b: 0.4 }]);
+        let average = scnr(&img, Cast::Green, Protection::AverageNeutral, 1.0);
+        assert!((average.pixels[0].g - 0.3).abs() < 1e-12);
+        assert_eq!(average.pixels[1], img.pixels[1]);
+        let maximum = scnr(&img, Cast::Green, Protection::MaximumNeutral, 0.5);
+        assert!((maximum.pixels[0].g - 0.45).abs() < 1e-12);
+        // Magenta: green too low against the average of red and blue.
+        let magenta = scnr(&img, Cast::Magenta, Protection::AverageNeutral, 1.0);
+        assert!((magenta.pixels[1].g - 0.45).abs() < 1e-12);
+    }
+
+    #[test]
+    fn saturation_keeps_lightness_and_hue() {
+        let p = Rgb { r: 0.3, g: 0.25, b: 0.2 };
+        let q = from_lab(to_lab(p));
+        assert!((p.r - q.r).abs() < 1e-6 && (p.g - q.g).abs() < 1e-6 && (p.b - q.b).abs() < 1e-6, "{:?}", q);
+        let img = saturation(&row(vec![p, Rgb { r: 0.5, g: 0.5, b: 0.5 }]), 1.5);
+        let (l, c, h) = to_lch(p);
+        let (l2, c2, h2) = to_lch(img.pixels[0]);
+        assert!((l - l2).abs() < 1e-4 && (c * 1.5 - c2).abs() < 1e-4 && (h - h2).abs() < 1e-4);
+        let gray = img.pixels[1];
+        assert!((gray.r - 0.5).abs() < 1e-6 && (gray.g - 0.5).abs() < 1e-6 && (gray.b - 0.5).abs() < 1e-6, "{:?}", gray);
+    }
+}
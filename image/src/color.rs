@@ -0,0 +1,144 @@
+//! Color calibration: neutralizes the background and white balances with a color matrix.
+//!
+//! The background is taken from a sky region, or from the darkest background samples. The white
+//! reference is the average color of unsaturated stars, which light pollution doesn't skew like
+//! the average of the whole image, or of a chosen region, like a face-on spiral galaxy.
+
+use std::str::FromStr;
+use image::{Image, OwnedImage};
+use rgb::Rgb;
+use background;
+use stars::Star;
+use stats::median;
+
+/// A rectangle of pixels, parsed from `X,Y,W,H`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("invalid region: {} (expected X,Y,W,H)", s);
+        let v: Vec<usize> = s.split(',').map(|v| v.trim().parse()).collect::<Result<_, _>>().map_err(|_| error())?;
+        if v.len() != 4 || v[2] == 0 || v[3] == 0 {
+            return Err(error());
+        }
+        Ok(Region { x: v[0], y: v[1], width: v[2], height: v[3] })
+    }
+}
+
+/// Row major 3 × 3 matrix that maps a color to another.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorMatrix(pub [[f64; 3]; 3]);
+
+impl ColorMatrix {
+    pub fn identity() -> Self {
+        ColorMatrix([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+    }
+
+    /// Scales red and blue so that `white` comes out with equal channels, keeping green.
+    pub fn white_balance(white: Rgb<f64>) -> Self {
+        assert!(white.r > 0.0 && white.g > 0.0 && white.b > 0.0, "white reference must be positive: {:?}", white);
+        ColorMatrix([[white.g / white.r, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, white.g / white.b]])
+    }
+
+    pub fn apply(&self, p: Rgb<f64>) -> Rgb<f64> {
+        let m = &self.0;
+        Rgb {
+            r: m[0][0] * p.r + m[0][1] * p.g + m[0][2] * p.b,
+            g: m[1][0] * p.r + m[1][1] * p.g + m[1][2] * p.b,
+            b: m[2][0] * p.r + m[2][1] * p.g + m[2][2] * p.b,
+        }
+    }
+}
+
+/// Median of each channel over `region`.
+pub fn region_median(img: &OwnedImage<Rgb<f64>>, region: Region) -> Rgb<f64> {
+    let dim = img.dimensions();
+    assert!(region.x + region.width <= dim.width && region.y + region.height <= dim.height, "region outside the image: {:?}", region);
+    let (mut r, mut g, mut b) = (Vec::new(), Vec::new(), Vec::new());
+    for y in region.y..region.y + region.height {
+        for p in img.row(y)[region.x..region.x + region.width].iter() {
+            r.push(p.r);
+            g.push(p.g);
+            b.push(p.b);
+        }
+    }
+    Rgb { r: median(&mut r), g: median(&mut g), b: median(&mut b) }
+}
+
+/// The background of each channel, from the darkest quarter of the background samples,
+/// which are least likely to have nebulosity in them.
+pub fn auto_background(img: &OwnedImage<Rgb<f64>>, grid: usize) -> Rgb<f64> {
+    let mut samples = background::samples(img, grid);
+    assert!(!samples.is_empty(), "no background samples");
+    samples.sort_by(|a, b| {
+        let (a, b) = (a.value.r + a.value.g + a.value.b, b.value.r + b.value.g + b.value.b);
+        a.partial_cmp(&b).unwrap()
+    });
+    samples.truncate((samples.len() + 3) / 4);
+    let (mut r, mut g, mut b): (Vec<f64>, Vec<f64>, Vec<f64>) = (Vec::new(), Vec::new(), Vec::new());
+    for s in samples.iter() {
+        r.push(s.value.r);
+        g.push(s.value.g);
+        b.push(s.value.b);
+    }
+    Rgb { r: median(&mut r), g: median(&mut g), b: median(&mut b) }
+}
+
+/// The typical color of `stars`, as the median of their red and blue to green ratios.
+pub fn star_white(stars: &[Star]) -> Rgb<f64> {
+    assert!(!stars.is_empty(), "no stars to white balance on");
+    let mut r: Vec<f64> = stars.iter().map(|s| s.flux.r / s.flux.g).collect();
+    let mut b: Vec<f64> = stars.iter().map(|s| s.flux.b / s.flux.g).collect();
+    Rgb { r: median(&mut r), g: 1.0, b: median(&mut b) }
+}
+
+/// Applies `matrix` to the signal above `background`, and puts every channel's background
+/// at the same level, their average.
+pub fn calibrate(img: &OwnedImage<Rgb<f64>>, background: Rgb<f64>, matrix: &ColorMatrix) -> OwnedImage<Rgb<f64>> {
+    let level = (background.r + background.g + background.b) / 3.0;
+    img.clone_map(|p| {
+        let p = matrix.apply(p - background);
+        Rgb { r: p.r + level, g: p.g + level, b: p.b + level }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::ImageDimensions;
+
+    #[test]
+    fn test_region() {
+        assert_eq!("10, 20,30,40".parse(), Ok(Region { x: 10, y: 20, width: 30, height: 40 }));
+        assert!("10,20,30".parse::<Region>().is_err());
+        assert!("10,20,0,40".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn neutralizes_and_balances() {
+        // Green sky glow, and a star that is white once red is doubled and blue halved.
+        let background = Rgb { r: 0.1, g: 0.2, b: 0.1 };
+        let star = Rgb { r: 0.05, g: 0.1, b: 0.2 };
+        let img = OwnedImage {
+            dimensions: ImageDimensions { width: 2, height: 1, pitch: 2 },
+            pixels: vec![background, background + star],
+        };
+        let white = star_white(&[Star { x: 1.5, y: 0.5, flux: star, fwhm: 2.0 }]);
+        let out = calibrate(&img, background, &ColorMatrix::white_balance(white));
+        let level = 0.4 / 3.0;
+        for (a, b) in [out.pixels[0].r, out.pixels[0].g, out.pixels[0].b].iter().zip([level; 3].iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+        for (a, b) in [out.pixels[1].r, out.pixels[1].g, out.pixels[1].b].iter().zip([level + 0.1; 3].iter()) {
+            assert!((a - b).abs() < 1e-12, "{:?}", out.pixels[1]);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Richardson–Lucy deconvolution of the luminance, with a PSF measured from the image's own stars.
+//!
+//! Total variation regularization keeps the noise from being amplified, and a mask around the
+//! brightest stars, where deconvolution rings, keeps them as they were. The colors are kept by
+//! scaling every channel by the change of the luminance.
+
+use std::f64;
+use num::complex::Complex;
+use image::{Image, OwnedImage};
+use rgb::Rgb;
+use stars::Star;
+use stats::median;
+use fft;
+
+/// Floor for values that are divided by.
+const EPSILON: f64 = 1e-12;
+
+/// Point spread function, normalized to sum to 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Psf {
+    /// Width and height, odd.
+    pub size: usize,
+    /// Row major, centered.
+    pub values: Vec<f64>,
+}
+
+impl Psf {
+    pub fn gaussian(sigma: f64, size: usize) -> Self {
+        assert!(size % 2 == 1, "PSF size must be odd");
+        let r = (size / 2) as f64;
+        let values = (0..size * size).map(|i| {
+            let (x, y) = ((i % size) as f64 - r, (i / size) as f64 - r);
+            (-(x * x + y * y) / (2.0 * sigma * sigma)).exp()
+        }).collect();
+        Psf::normalized(size, values)
+    }
+
+    /// Averages the normalized cutouts of luminance around the `count` first of `stars`,
+    /// each centered on its centroid and with the median of its border subtracted.
+    pub fn from_stars(img: &OwnedImage<Rgb<f64>>, stars: &[Star], size: usize, count: usize) -> Self {
+        assert!(size % 2 == 1, "PSF size must be odd");
+        let dim = img.dimensions();
+        let r = (size / 2) as f64;
+        let lum = |x: usize, y: usize| {
+            let p = img.pixel_at(x, y);
+            (p.r + p.g + p.b) / 3.0
+        };
+        let mut sum = vec![0.0; size * size];
+        let mut used = 0;
+        for star in stars {
+            if used == count {
+                break;
+            }
+            // Pixel coordinates of the centroid, which is given with pixel centers at +0.5.
+            let (cx, cy) = (star.x - 0.5, star.y - 0.5);
+            if cx - r < 0.0 || cy - r < 0.0 || cx + r + 1.0 >= dim.width as f64 || cy + r + 1.0 >= dim.height as f64 {
+                continue;
+            }
+            let mut cutout = Vec::with_capacity(size * size);
+            for i in 0..size * size {
+                let (x, y) = (cx + (i % size) as f64 - r, cy + (i / size) as f64 - r);
+                let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+                let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+                cutout.push(lum(x0, y0) * (1.0 - fx) * (1.0 - fy) + lum(x0 + 1, y0) * fx * (1.0 - fy)
+                    + lum(x0, y0 + 1) * (1.0 - fx) * fy + lum(x0 + 1, y0 + 1) * fx * fy);
+            }
+            let mut border: Vec<f64> = (0..size * size)
+                .filter(|i| i % size == 0 || i % size == size - 1 || i / size == 0 || i / size == size - 1)
+                .map(|i| cutout[i])
+                .collect();
+            let background = median(&mut border);
+            let total: f64 = cutout.iter().map(|v| v - background).sum();
+            if !(total > 0.0) {
+                continue;
+            }
+            for (s, v) in sum.iter_mut().zip(cutout.iter()) {
+                *s += (v - background) / total;
+            }
+            used += 1;
+        }
+        assert!(used > 0, "no stars to measure the PSF on");
+        Psf::normalized(size, sum.into_iter().map(|v| v.max(0.0)).collect())
+    }
+
+    fn normalized(size: usize, values: Vec<f64>) -> Self {
+        let total: f64 = values.iter().sum();
+        Psf { size, values: values.into_iter().map(|v| v / total).collect() }
+    }
+
+    /// Standard deviation of the PSF around its center, in pixels.
+    pub fn sigma(&self) -> f64 {
+        let r = (self.size / 2) as f64;
+        let variance: f64 = self.values.iter().enumerate().map(|(i, v)| {
+            let (x, y) = ((i % self.size) as f64 - r, (i / self.size) as f64 - r);
+            v * (x * x + y * y)
+        }).sum();
+        (variance / 2.0).sqrt()
+    }
+}
+
+/// Convolves `width` × `height` arrays with a PSF through the FFT. The array is padded with
+/// copies of its edges, so the wrap around of the FFT doesn't bleed one side into the other.
+struct Convolver {
+    width: usize,
+    height: usize,
+    pad: usize,
+    fw: usize,
+    fh: usize,
+    otf: Vec<Complex<f64>>,
+}
+
+impl Convolver {
+    fn new(psf: &Psf, width: usize, height: usize) -> Self {
+        let pad = psf.size / 2;
+        let (fw, fh) = ((width + 2 * pad).next_power_of_two(), (height + 2 * pad).next_power_of_two());
+        let mut otf = vec![Complex::new(0.0, 0.0); fw * fh];
+        for (i, &v) in psf.values.iter().enumerate() {
+            let x = (i % psf.size + fw - pad) % fw;
+            let y = (i / psf.size + fh - pad) % fh;
+            otf[y * fw + x] = Complex::new(v, 0.0);
+        }
+        fft::fft2(&mut otf, fw, fh, false);
+        Convolver { width, height, pad, fw, fh, otf }
+    }
+
+    /// With `transpose`, correlates instead, that is convolves with the mirrored PSF.
+    fn convolve(&self, data: &[f64], transpose: bool) -> Vec<f64> {
+        let (w, h, pad) = (self.width, self.height, self.pad);
+        let mut padded = vec![Complex::new(0.0, 0.0); self.fw * self.fh];
+        for y in 0..h + 2 * pad {
+            let sy = (y.max(pad) - pad).min(h - 1);
+            for x in 0..w + 2 * pad {
+                let sx = (x.max(pad) - pad).min(w - 1);
+                padded[y * self.fw + x] = Complex::new(data[sy * w + sx], 0.0);
+            }
+        }
+        fft::fft2(&mut padded, self.fw, self.fh, false);
+        for (v, o) in padded.iter_mut().zip(self.otf.iter()) {
+            *v = *v * if transpose { o.conj() } else { *o };
+        }
+        fft::fft2(&mut padded, self.fw, self.fh, true);
+        let mut res = Vec::with_capacity(w * h);
+        for y in 0..h {
+            res.extend(padded[(y + pad) * self.fw + pad..(y + pad) * self.fw + pad + w].iter().map(|v| v.re));
+        }
+        res
+    }
+}
+
+/// Richardson–Lucy deconvolution of a `width` × `height` array of non negative values.
+/// `regularization` is the weight of the total variation term; 0 turns it off.
+pub fn richardson_lucy(data: &[f64], width: usize, height: usize, psf: &Psf, iterations: usize, regularization: f64) -> Vec<f64> {
+    assert_eq!(data.len(), width * height);
+    let convolver = Convolver::new(psf, width, height);
+    let observed: Vec<f64> = data.iter().map(|v| v.max(EPSILON)).collect();
+    let mut estimate = observed.clone();
+    for _ in 0..iterations {
+        let blurred = convolver.convolve(&estimate, false);
+        let ratio: Vec<f64> = observed.iter().zip(blurred.iter()).map(|(o, b)| o / b.max(EPSILON)).collect();
+        let correction = convolver.convolve(&ratio, true);
+        let tv = if regularization > 0.0 { total_variation(&estimate, width, height) } else { vec![0.0; width * height] };
+        for i in 0..estimate.len() {
+            // Limited so a strong edge can't flip the sign of the update.
+            let denominator = (1.0 - regularization * tv[i]).max(0.1);
+            estimate[i] = (estimate[i] * correction[i] / denominator).max(EPSILON);
+        }
+    }
+    estimate
+}
+
+/// div(∇u / |∇u|), with forward differences for the gradient and backward ones for the divergence.
+fn total_variation(u: &[f64], width: usize, height: usize) -> Vec<f64> {
+    let mut nx = vec![0.0; width * height];
+    let mut ny = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let dx = if x + 1 < width { u[i + 1] - u[i] } else { 0.0 };
+            let dy = if y + 1 < height { u[i + width] - u[i] } else { 0.0 };
+            let norm = (dx * dx + dy * dy).sqrt().max(EPSILON);
+            nx[i] = dx / norm;
+            ny[i] = dy / norm;
+        }
+    }
+    let mut div = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let ddx = if x > 0 { nx[i] - nx[i - 1] } else { nx[i] };
+            let ddy = if y > 0 { ny[i] - ny[i - width] } else { ny[i] };
+            div[i] = ddx + ddy;
+        }
+    }
+    div
+}
+
+/// 1 where deconvolution applies, falling to 0 towards pixels whose luminance is at or above
+/// `threshold`, within `radius` pixels of them. That keeps the cores of bright stars, and the
+/// dark rings deconvolution leaves around them, out.
+pub fn protection_mask(img: &OwnedImage<Rgb<f64>>, threshold: f64, radius: f64) -> Vec<f64> {
+    let dim = img.dimensions();
+    let (w, h) = (dim.width, dim.height);
+    let lum = luminance(img);
+    let mut mask = vec![1.0; w * h];
+    let r = radius.ceil() as isize;
+    for y in 0..h {
+        for x in 0..w {
+            if !(lum[y * w + x] >= threshold) {
+                continue;
+            }
+            for ny in (y as isize - r).max(0)..(y as isize + r + 1).min(h as isize) {
+                for nx in (x as isize - r).max(0)..(x as isize + r + 1).min(w as isize) {
+                    let d = ((nx - x as isize).pow(2) as f64 + (ny - y as isize).pow(2) as f64).sqrt();
+                    let m: &mut f64 = &mut mask[ny as usize * w + nx as usize];
+                    *m = m.min((d / radius).min(1.0));
+                }
+            }
+        }
+    }
+    mask
+}
+
+fn luminance(img: &OwnedImage<Rgb<f64>>) -> Vec<f64> {
+    let dim = img.dimensions();
+    (0..dim.height).flat_map(|y| img.row(y).iter().map(|p| {
+        let l = (p.r + p.g + p.b) / 3.0;
+        if l.is_finite() { l } else { 0.0 }
+    })).collect()
+}
+
+/// Deconvolves the luminance, blended with the original by `mask` if given, and scales
+/// each pixel's channels by how much its luminance changed.
+pub fn deconvolve(img: &OwnedImage<Rgb<f64>>, psf: &Psf, iterations: usize, regularization: f64, mask: Option<&[f64]>) -> OwnedImage<Rgb<f64>> {
+    let dim = img.dimensions();
+    let lum = luminance(img);
+    let sharp = richardson_lucy(&lum, dim.width, dim.height, psf, iterations, regularization);
+    let mut res = img.clone_map(|p| p);
+    for y in 0..dim.height {
+        for x in 0..dim.width {
+            let i = y * dim.width + x;
+            let (l, s) = (lum[i], sharp[i]);
+            let s = match mask {
+                Some(mask) => mask[i] * s + (1.0 - mask[i]) * l,
+                None => s,
+            };
+            if l > 0.0 {
+                let p = &mut res.pixels[y * dim.pitch + x];
+                *p = *p * (s / l);
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{sky, gray};
+
+    #[test]
+    fn measures_the_psf() {
+        let positions = [(20.3, 20.6), (45.5, 30.2), (30.8, 50.1)];
+        let gaussians: Vec<_> = positions.iter().map(|&(x, y)| (x, y, 1.5, gray(0.5))).collect();
+        let img = sky(64, 64, |_, _| gray(0.1), &gaussians);
+        let stars: Vec<Star> = positions.iter().map(|&(x, y)| Star { x, y, flux: gray(1.0), fwhm: 3.5 }).collect();
+        let psf = Psf::from_stars(&img, &stars, 11, 10);
+        assert!((psf.sigma() - 1.5).abs() < 0.1, "{}", psf.sigma());
+        let center = psf.values[5 * 11 + 5];
+        assert!(psf.values.iter().all(|&v| v <= center));
+    }
+
+    #[test]
+    fn sharpens_blurred_stars() {
+        let (w, h) = (40, 30);
+        let mut truth = vec![0.01; w * h];
+        truth[10 * w + 12] = 1.0;
+        truth[20 * w + 27] = 0.5;
+        let psf = Psf::gaussian(1.5, 9);
+        let blurred = Convolver::new(&psf, w, h).convolve(&truth, false);
+        let error = |a: &[f64]| a.iter().zip(truth.iter()).map(|(a, b)| (a - b).powi(2)).sum::<f64>();
+        let sharp = richardson_lucy(&blurred, w, h, &psf, 50, 0.0);
+        assert!(error(&sharp) < error(&blurred) * 0.8, "{} {}", error(&sharp), error(&blurred));
+        // Flux is conserved.
+        let total = |a: &[f64]| a.iter().sum::<f64>();
+        assert!((total(&sharp) / total(&blurred) - 1.0).abs() < 0.01);
+        let regularized = richardson_lucy(&blurred, w, h, &psf, 50, 0.002);
+        assert!(error(&regularized) < error(&blurred));
+    }
+}
@@ -0,0 +1,86 @@
+//! Radix 2 FFT, enough for convolving images padded to powers of two.
+
+use std::f64::consts::PI;
+use num::complex::Complex;
+
+/// In place FFT of `data`, whose length must be a power of two. The inverse is scaled by 1/n.
+pub fn fft(data: &mut [Complex<f64>], inverse: bool) {
+    let n = data.len();
+    assert!(n.is_power_of_two(), "FFT length must be a power of two: {}", n);
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f64;
+        let step = Complex::new(angle.cos(), angle.sin());
+        for start in (0..n).step_by(len) {
+            let mut w = Complex::new(1.0, 0.0);
+            for k in 0..len / 2 {
+                let a = data[start + k];
+                let b = data[start + k + len / 2] * w;
+                data[start + k] = a + b;
+                data[start + k + len / 2] = a - b;
+                w = w * step;
+            }
+        }
+        len <<= 1;
+    }
+    if inverse {
+        let scale = 1.0 / n as f64;
+        for v in data.iter_mut() {
+            *v = *v * scale;
+        }
+    }
+}
+
+/// 2D FFT of a row major `width` × `height` array.
+pub fn fft2(data: &mut [Complex<f64>], width: usize, height: usize, inverse: bool) {
+    assert_eq!(data.len(), width * height);
+    for row in data.chunks_mut(width) {
+        fft(row, inverse);
+    }
+    let mut column = vec![Complex::new(0.0, 0.0); height];
+    for x in 0..width {
+        for y in 0..height {
+            column[y] = data[y * width + x];
+        }
+        fft(&mut column, inverse);
+        for y in 0..height {
+            data[y * width + x] = column[y];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_dft() {
+        let input: Vec<Complex<f64>> = (0..8).map(|i| Complex::new((i * i % 5) as f64, i as f64 * 0.5)).collect();
+        let mut data = input.clone();
+        fft(&mut data, false);
+        for k in 0..8 {
+            let expected = input.iter().enumerate().fold(Complex::new(0.0, 0.0), |acc, (i, v)| {
+                let angle = -2.0 * PI * (i * k) as f64 / 8.0;
+                acc + v * Complex::new(angle.cos(), angle.sin())
+            });
+            assert!((data[k] - expected).norm() < 1e-9, "{} {} {}", k, data[k], expected);
+        }
+        fft(&mut data, true);
+        for (a, b) in data.iter().zip(input.iter()) {
+            assert!((a - b).norm() < 1e-12);
+        }
+    }
+}
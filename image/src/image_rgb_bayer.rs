@@ -56,6 +56,17 @@ impl<P: Float> Image<RgbBayer<P>> {
         })
     }
 
+    /// Returns the accumulated weight of each channel.
+    pub fn weights(&self) -> Image<Rgb<P>> {
+        self.map(|p| {
+            Rgb {
+                r: p.rc,
+                g: p.gc,
+                b: p.bc,
+            }
+        })
+    }
+
     pub fn correct_white_balance(&self) -> Self {
         let (avg_r, avg_g, avg_b) = self.avg();
         let m_r = avg_g / avg_r;
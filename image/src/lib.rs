@@ -21,10 +21,24 @@ mod image;
 //mod image_rgb_f32;
 //mod image_rgb_bayer;
 mod util;
+mod linalg;
+mod fft;
 //mod pgm;
 //mod dcraw;
 //mod image_kind;
 pub mod convert_array;
+pub mod stats;
+pub mod stretch;
+pub mod background;
+pub mod stars;
+pub mod color;
+pub mod photometry;
+pub mod chroma;
+pub mod deconvolution;
+pub mod wavelets;
+pub mod starless;
+pub mod transform;
+#[cfg(test)] mod test_support;
 
 pub use image::*;
 pub use rgb::*;
@@ -0,0 +1,63 @@
+//! The little linear algebra the fits need.
+
+/// Solves `a x = b` by Gaussian elimination with partial pivoting. `a` is n × n, row major.
+pub fn solve(mut a: Vec<f64>, mut b: Vec<f64>) -> Vec<f64> {
+    let n = b.len();
+    assert_eq!(a.len(), n * n);
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i * n + col].abs().partial_cmp(&a[j * n + col].abs()).unwrap()).unwrap();
+        assert!(a[pivot * n + col].abs() > 1e-300, "singular matrix");
+        if pivot != col {
+            for k in 0..n {
+                a.swap(pivot * n + k, col * n + k);
+            }
+            b.swap(pivot, col);
+        }
+        for row in col + 1..n {
+            let f = a[row * n + col] / a[col * n + col];
+            if f == 0.0 {
+                continue;
+            }
+            for k in col..n {
+                a[row * n + k] -= f * a[col * n + k];
+            }
+            b[row] -= f * b[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let s: f64 = (row + 1..n).map(|k| a[row * n + k] * x[k]).sum();
+        x[row] = (b[row] - s) / a[row * n + row];
+    }
+    x
+}
+
+/// Least squares solution of `a x = b`, with `a` given as its rows, through the normal equations.
+pub fn least_squares(rows: &[Vec<f64>], b: &[f64]) -> Vec<f64> {
+    let n = rows[0].len();
+    let mut ata = vec![0.0; n * n];
+    let mut atb = vec![0.0; n];
+    for (row, &v) in rows.iter().zip(b.iter()) {
+        for i in 0..n {
+            atb[i] += row[i] * v;
+            for j in 0..n {
+                ata[i * n + j] += row[i] * row[j];
+            }
+        }
+    }
+    solve(ata, atb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve() {
+        // The first pivot is 0, so this needs a row swap.
+        let x = solve(vec![0.0, 2.0, 1.0, 1.0, 1.0, 0.0, 2.0, 0.0, 1.0], vec![5.0, 3.0, 3.0]);
+        for (a, b) in x.iter().zip([1.0, 2.0, 1.0].iter()) {
+            assert!((a - b).abs() < 1e-12, "{:?}", x);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Photometric color calibration: the white balance that makes the colors of the stars
+//! match their catalog colors.
+//!
+//! A star's color is predicted from its B-V index through the black body of its temperature,
+//! seen at the typical peaks of a camera's red, green and blue filters, and relative to a
+//! star of `WHITE_BV`, which is what comes out white.
+
+use std::f64;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use byteorder::{ReadBytesExt, LittleEndian as LE};
+use rgb::Rgb;
+use stars::Star;
+use stats::median;
+
+/// B-V of the white reference, a G2V star like the sun.
+pub const WHITE_BV: f64 = 0.65;
+
+/// Wavelengths, in meters, the channels are taken to see.
+const WAVELENGTHS: (f64, f64, f64) = (600e-9, 530e-9, 460e-9);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CatalogStar {
+    /// Degrees.
+    pub ra: f64,
+    pub dec: f64,
+    /// V magnitude.
+    pub magnitude: f64,
+    /// B-V color index.
+    pub color: f64,
+}
+
+/// Reads a catalog of little endian `f32` records: RA and Dec in degrees, V magnitude and B-V.
+pub fn read_catalog<P: AsRef<Path>>(path: P) -> Vec<CatalogStar> {
+    let mut f = BufReader::new(File::open(path).unwrap());
+    let mut res = Vec::new();
+    loop {
+        let ra = match f.read_f32::<LE>() {
+            Ok(v) => v,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            e => e.unwrap()
+        };
+        let dec = f.read_f32::<LE>().unwrap();
+        let magnitude = f.read_f32::<LE>().unwrap();
+        let color = f.read_f32::<LE>().unwrap();
+        res.push(CatalogStar { ra: ra as f64, dec: dec as f64, magnitude: magnitude as f64, color: color as f64 });
+    }
+    res
+}
+
+/// Temperature of a star of the given B-V, after Ballesteros (2012).
+pub fn temperature(bv: f64) -> f64 {
+    4600.0 * (1.0 / (0.92 * bv + 1.7) + 1.0 / (0.92 * bv + 0.62))
+}
+
+/// The color a star of the given B-V should have, with green at 1.
+pub fn expected_color(bv: f64) -> Rgb<f64> {
+    let ratios = |t: f64| {
+        let g = planck(WAVELENGTHS.1, t);
+        (planck(WAVELENGTHS.0, t) / g, planck(WAVELENGTHS.2, t) / g)
+    };
+    let (r, b) = ratios(temperature(bv));
+    let (wr, wb) = ratios(temperature(WHITE_BV));
+    Rgb { r: r / wr, g: 1.0, b: b / wb }
+}
+
+fn planck(wavelength: f64, temperature: f64) -> f64 {
+    // hc/k in meter kelvin
+    let c2 = 1.4388e-2;
+    1.0 / (wavelength.powi(5) * ((c2 / (wavelength * temperature)).exp() - 1.0))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Match {
+    pub star: Star,
+    pub catalog: CatalogStar,
+}
+
+/// Pairs each star with the nearest of `catalog`, given with its position on the image,
+/// if that is within `radius` pixels and no other catalog star is, which could be confused.
+pub fn cross_match(stars: &[Star], catalog: &[(f64, f64, CatalogStar)], radius: f64) -> Vec<Match> {
+    let mut matches = Vec::new();
+    for star in stars {
+        let mut near = catalog.iter().filter(|&&(x, y, _)| (x - star.x).powi(2) + (y - star.y).powi(2) <= radius * radius);
+        if let (Some(&(_, _, catalog)), None) = (near.next(), near.next()) {
+            matches.push(Match { star: *star, catalog });
+        }
+    }
+    matches
+}
+
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    /// Color a white star is measured with, with green at 1.
+    pub white: Rgb<f64>,
+    /// Of each match, how much redder and bluer it measures than the fit predicts, in magnitudes
+    /// of the red to green and blue to green ratios. Green is 0.
+    pub residuals: Vec<Rgb<f64>>,
+    /// Root mean square of the residuals.
+    pub rms: Rgb<f64>,
+}
+
+/// Fits the white balance factors: the median over all matches of the ratio of their measured
+/// to their expected color, which isn't thrown off by variable stars or bad matches.
+pub fn calibrate(matches: &[Match]) -> Calibration {
+    assert!(!matches.is_empty(), "no stars matched the catalog");
+    let ratios: Vec<Rgb<f64>> = matches.iter().map(|m| {
+        let expected = expected_color(m.catalog.color);
+        let f = &m.star.flux;
+        Rgb { r: f.r / f.g / expected.r, g: 1.0, b: f.b / f.g / expected.b }
+    }).collect();
+    let mut r: Vec<f64> = ratios.iter().map(|p| p.r).collect();
+    let mut b: Vec<f64> = ratios.iter().map(|p| p.b).collect();
+    let white = Rgb { r: median(&mut r), g: 1.0, b: median(&mut b) };
+    let magnitudes = |ratio: f64, white: f64| -2.5 * (ratio / white).log10();
+    let residuals: Vec<Rgb<f64>> = ratios.iter().map(|p| Rgb {
+        r: magnitudes(p.r, white.r),
+        g: 0.0,
+        b: magnitudes(p.b, white.b),
+    }).collect();
+    let n = residuals.len() as f64;
+    let rms = Rgb {
+        r: (residuals.iter().map(|p| p.r * p.r).sum::<f64>() / n).sqrt(),
+        g: 0.0,
+        b: (residuals.iter().map(|p| p.b * p.b).sum::<f64>() / n).sqrt(),
+    };
+    Calibration { white, residuals, rms }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expected_color() {
+        let white = expected_color(WHITE_BV);
+        assert!((white.r - 1.0).abs() < 1e-12 && (white.b - 1.0).abs() < 1e-12);
+        // Sun-like stars are near 5800 K.
+        assert!((temperature(WHITE_BV) - 5800.0).abs() < 100.0);
+        let red = expected_color(1.5);
+        assert!(red.r > 1.2 && red.b < 0.8, "{:?}", red);
+    }
+
+    #[test]
+    fn fits_the_white_balance() {
+        // A camera that sees red at half and blue at 1.5 times the sensitivity of green.
+        let camera = Rgb { r: 0.5, g: 1.0, b: 1.5 };
+        let catalog: Vec<(f64, f64, CatalogStar)> = (0..9).map(|i| {
+            let star = CatalogStar { ra: i as f64, dec: 0.0, magnitude: 8.0, color: i as f64 * 0.2 };
+            (i as f64 * 20.0, 10.0, star)
+        }).collect();
+        let stars: Vec<Star> = catalog.iter().map(|&(x, y, c)| {
+            let color = expected_color(c.color) * camera;
+            Star { x: x + 0.7, y: y - 0.4, flux: color * 1000.0, fwhm: 2.0 }
+        }).collect();
+        let matches = cross_match(&stars, &catalog, 2.0);
+        assert_eq!(matches.len(), 9);
+        let calibration = calibrate(&matches);
+        assert!((calibration.white.r - 0.5).abs() < 1e-9 && (calibration.white.b - 1.5).abs() < 1e-9);
+        assert!(calibration.rms.r < 1e-9 && calibration.rms.b < 1e-9);
+    }
+}
@@ -41,6 +41,17 @@ impl<T: Sub<Output=T>> Sub for Rgb<T> {
     }
 }
 
+impl<T: Add<Output=T>> Add for Rgb<T> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Rgb {
+            r: self.r + rhs.r,
+            g: self.g + rhs.g,
+            b: self.b + rhs.b,
+        }
+    }
+}
+
 impl<T: AddAssign> AddAssign for Rgb<T> {
     fn add_assign(&mut self, rhs: Self) {
         self.r += rhs.r;
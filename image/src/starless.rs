@@ -0,0 +1,236 @@
+//! Star reduction and removal, so nebulosity and stars can be stretched on their own and put back
+//! together with `recombine`.
+//!
+//! The star mask covers each star out to a multiple of its FWHM, and fades out over half as much
+//! again so the edits don't leave rims.
+
+use std::f64;
+use image::{Image, OwnedImage, ImageDimensions};
+use rgb::Rgb;
+use stars::Star;
+
+/// The inpainting of a hole stops once no pixel changes by more than this in a pass,
+/// relative to the brightest pixel around it.
+const INPAINT_TOLERANCE: f64 = 1e-6;
+
+/// In case a hole doesn't get there.
+const MAX_INPAINT_PASSES: usize = 10000;
+
+/// 1 within `size` FWHMs of each star, falling to 0 at 1.5 times that.
+pub fn star_mask(dimensions: ImageDimensions, stars: &[Star], size: f64) -> Vec<f64> {
+    let (w, h) = (dimensions.width, dimensions.height);
+    let mut mask = vec![0.0; w * h];
+    for star in stars {
+        let radius = (size * star.fwhm).max(1.0);
+        let outer = radius * 1.5;
+        let (x0, x1) = ((star.x - outer).floor().max(0.0) as usize, ((star.x + outer).ceil().max(0.0) as usize).min(w));
+        let (y0, y1) = ((star.y - outer).floor().max(0.0) as usize, ((star.y + outer).ceil().max(0.0) as usize).min(h));
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let d = ((x as f64 + 0.5 - star.x).powi(2) + (y as f64 + 0.5 - star.y).powi(2)).sqrt();
+                let m: &mut f64 = &mut mask[y * w + x];
+                *m = m.max(((outer - d) / (outer - radius)).max(0.0).min(1.0));
+            }
+        }
+    }
+    mask
+}
+
+/// Shrinks stars by eroding, that is taking the darkest pixel of each 3 × 3 neighbourhood,
+/// `iterations` times, blended in by `mask`.
+pub fn reduce(img: &OwnedImage<Rgb<f64>>, mask: &[f64], iterations: usize) -> OwnedImage<Rgb<f64>> {
+    let dim = img.dimensions();
+    let (w, h) = (dim.width, dim.height);
+    let original: Vec<Rgb<f64>> = (0..h).flat_map(|y| img.row(y).iter().cloned()).collect();
+    let mut eroded = original.clone();
+    for _ in 0..iterations {
+        let prev = eroded.clone();
+        for y in 0..h {
+            for x in 0..w {
+                let mut p = prev[y * w + x];
+                for ny in y.max(1) - 1..(y + 2).min(h) {
+                    for nx in x.max(1) - 1..(x + 2).min(w) {
+                        let n = prev[ny * w + nx];
+                        p = Rgb { r: p.r.min(n.r), g: p.g.min(n.g), b: p.b.min(n.b) };
+                    }
+                }
+                eroded[y * w + x] = p;
+            }
+        }
+    }
+    OwnedImage {
+        dimensions: ImageDimensions { width: w, height: h, pitch: w },
+        pixels: (0..w * h).map(|i| eroded[i] * mask[i] + original[i] * (1.0 - mask[i])).collect(),
+    }
+}
+
+/// The image with the masked stars replaced by background, inpainted by diffusing the pixels
+/// around each hole into it, which follows gradients and nebulosity across.
+pub fn starless(img: &OwnedImage<Rgb<f64>>, mask: &[f64]) -> OwnedImage<Rgb<f64>> {
+    let dim = img.dimensions();
+    let (w, h) = (dim.width, dim.height);
+    let original: Vec<Rgb<f64>> = (0..h).flat_map(|y| img.row(y).iter().cloned()).collect();
+    let mut filled = original.clone();
+    let mut seen = vec![false; w * h];
+    let mut hole = Vec::new();
+    for start in 0..w * h {
+        if mask[start] == 0.0 || seen[start] {
+            continue;
+        }
+        // The connected hole around `start`, and the pixels around it.
+        hole.clear();
+        hole.push(start);
+        seen[start] = true;
+        let (mut sum, mut n, mut scale) = (Rgb { r: 0.0, g: 0.0, b: 0.0 }, 0.0, 0.0f64);
+        let mut k = 0;
+        while k < hole.len() {
+            let (ns, count) = neighbours(hole[k], w, h);
+            for &j in ns[..count].iter() {
+                if mask[j] == 0.0 {
+                    let p = original[j];
+                    sum += p;
+                    n += 1.0;
+                    scale = scale.max(p.r.abs()).max(p.g.abs()).max(p.b.abs());
+                } else if !seen[j] {
+                    seen[j] = true;
+                    hole.push(j);
+                }
+            }
+            k += 1;
+        }
+        // Nothing to fill it from when everything is masked.
+        if n > 0.0 {
+            inpaint(&mut filled, &hole, w, h, sum * (1.0 / n), scale.max(f64::MIN_POSITIVE));
+        }
+    }
+    OwnedImage {
+        dimensions: ImageDimensions { width: w, height: h, pitch: w },
+        pixels: (0..w * h).map(|i| filled[i] * mask[i] + original[i] * (1.0 - mask[i])).collect(),
+    }
+}
+
+/// Fills one hole, starting from `seed`, by successive over-relaxation until it settles.
+/// The relaxation factor is the optimal one for a square as wide as the hole.
+fn inpaint(filled: &mut [Rgb<f64>], hole: &[usize], w: usize, h: usize, seed: Rgb<f64>, scale: f64) {
+    let (mut x0, mut x1, mut y0, mut y1) = (w, 0, h, 0);
+    for &i in hole.iter() {
+        filled[i] = seed;
+        x0 = x0.min(i % w);
+        x1 = x1.max(i % w);
+        y0 = y0.min(i / w);
+        y1 = y1.max(i / w);
+    }
+    let size = (x1 - x0).max(y1 - y0) + 1;
+    let omega = 2.0 / (1.0 + (f64::consts::PI / (size + 1) as f64).sin());
+    for _ in 0..MAX_INPAINT_PASSES {
+        let mut change = 0.0f64;
+        for &i in hole.iter() {
+            let (ns, count) = neighbours(i, w, h);
+            let mut s = Rgb { r: 0.0, g: 0.0, b: 0.0 };
+            for &j in ns[..count].iter() {
+                s += filled[j];
+            }
+            let d = (s * (1.0 / count as f64) - filled[i]) * omega;
+            filled[i] += d;
+            change = change.max(d.r.abs()).max(d.g.abs()).max(d.b.abs());
+        }
+        if change <= INPAINT_TOLERANCE * scale {
+            break;
+        }
+    }
+}
+
+/// The 4-connected neighbours of pixel `i`, the first as many as the count.
+fn neighbours(i: usize, w: usize, h: usize) -> ([usize; 4], usize) {
+    let (x, y) = (i % w, i / w);
+    let mut res = [0; 4];
+    let mut n = 0;
+    if x > 0 { res[n] = i - 1; n += 1; }
+    if x + 1 < w { res[n] = i + 1; n += 1; }
+    if y > 0 { res[n] = i - w; n += 1; }
+    if y + 1 < h { res[n] = i + w; n += 1; }
+    (res, n)
+}
+
+/// The stars alone: the image minus its starless version.
+pub fn stars_only(img: &OwnedImage<Rgb<f64>>, starless: &OwnedImage<Rgb<f64>>) -> OwnedImage<Rgb<f64>> {
+    let dim = img.dimensions();
+    OwnedImage {
+        dimensions: ImageDimensions { width: dim.width, height: dim.height, pitch: dim.width },
+        pixels: (0..dim.height).flat_map(|y| img.row(y).iter().zip(starless.row(y).iter()).map(|(&p, &s)| {
+            let d = p - s;
+            Rgb { r: d.r.max(0.0), g: d.g.max(0.0), b: d.b.max(0.0) }
+        })).collect(),
+    }
+}
+
+/// Puts stretched stars back on a stretched starless image with a screen blend, 1 - (1 - a)(1 - b),
+/// which never goes above 1.
+pub fn recombine(starless: &OwnedImage<Rgb<f64>>, stars: &OwnedImage<Rgb<f64>>) -> OwnedImage<Rgb<f64>> {
+    let dim = starless.dimensions();
+    assert_eq!((dim.width, dim.height), (stars.dimensions().width, stars.dimensions().height), "images of different sizes");
+    let screen = |a: f64, b: f64| 1.0 - (1.0 - a) * (1.0 - b);
+    OwnedImage {
+        dimensions: ImageDimensions { width: dim.width, height: dim.height, pitch: dim.width },
+        pixels: (0..dim.height).flat_map(|y| starless.row(y).iter().zip(stars.row(y).iter())
+            .map(|(a, b)| Rgb { r: screen(a.r, b.r), g: screen(a.g, b.g), b: screen(a.b, b.b) })).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stars;
+    use test_support::{sky, gray};
+
+    /// A gradient with a faint wide nebula, at pixel centers.
+    fn nebula(x: f64, y: f64) -> f64 {
+        0.1 + x * 0.001 + 0.05 * (-((x - 40.0).powi(2) + (y - 30.0).powi(2)) / 400.0).exp()
+    }
+
+    #[test]
+    fn removes_stars() {
+        let img = sky(80, 60, |x, y| gray(nebula(x, y)), &[(25.5, 20.5, 1.5f64.sqrt(), gray(0.6)), (50.5, 40.5, 1.5f64.sqrt(), gray(0.6))]);
+        let found = stars::find(&img, 10.0, 1.0);
+        assert_eq!(found.len(), 2);
+        let mask = star_mask(img.dimensions(), &found, 1.5);
+        let starless = starless(&img, &mask);
+        for &(x, y) in [(25, 20), (50, 40)].iter() {
+            let p = starless.pixel_at(x, y);
+            assert!((p.g - nebula(x as f64 + 0.5, y as f64 + 0.5)).abs() < 0.005, "{} {} {:?}", x, y, p);
+        }
+        // Away from the stars nothing changes.
+        assert_eq!(starless.pixel_at(5, 5), img.pixel_at(5, 5));
+        let reduced = reduce(&img, &mask, 2);
+        assert!(reduced.pixel_at(25, 20).g < img.pixel_at(25, 20).g);
+        assert_eq!(reduced.pixel_at(5, 5), img.pixel_at(5, 5));
+    }
+
+    #[test]
+    fn fills_wide_holes() {
+        // A gradient is what diffusion converges to, however wide the hole.
+        let (w, h) = (100, 80);
+        let level = |x: usize, y: usize| 0.1 + 0.002 * x as f64 + 0.001 * y as f64;
+        let img = sky(w, h, |x, y| {
+            let v = level(x as usize, y as usize);
+            Rgb { r: v, g: v, b: 2.0 * v }
+        }, &[]);
+        let mut mask = vec![0.0; w * h];
+        for y in 10..70 {
+            for x in 10..70 {
+                mask[y * w + x] = 1.0;
+            }
+        }
+        for y in 30..40 {
+            for x in 85..95 {
+                mask[y * w + x] = 1.0;
+            }
+        }
+        let starless = starless(&img, &mask);
+        for &(x, y) in [(40, 40), (15, 65), (90, 35)].iter() {
+            let p = starless.pixel_at(x, y);
+            assert!((p.r - level(x, y)).abs() < 1e-4, "{} {} {:?}", x, y, p);
+            assert!((p.b - 2.0 * level(x, y)).abs() < 2e-4, "{} {} {:?}", x, y, p);
+        }
+    }
+}
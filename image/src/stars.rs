@@ -0,0 +1,139 @@
+//! Finds stars in an RGB image and measures them with aperture photometry.
+
+use std::f64;
+use image::{Image, OwnedImage};
+use rgb::Rgb;
+use stats::{median, median_mad};
+
+/// Radius of the aperture the flux is summed over.
+pub const APERTURE: f64 = 4.0;
+/// The local background is the median of the annulus between these radii.
+const ANNULUS: (f64, f64) = (6.0, 9.0);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Star {
+    /// Centroid, in pixels.
+    pub x: f64,
+    pub y: f64,
+    /// Sum over the aperture, minus the local background.
+    pub flux: Rgb<f64>,
+    /// Full width at half maximum, in pixels, from the second moment of the aperture.
+    pub fwhm: f64,
+}
+
+/// Finds the stars that peak more than `sigma` times the noise above the background, leaving
+/// out those with a pixel at or above `saturation` in any channel, whose colors are wrong.
+/// Sorted from the brightest.
+pub fn find(img: &OwnedImage<Rgb<f64>>, sigma: f64, saturation: f64) -> Vec<Star> {
+    let dim = img.dimensions();
+    let (w, h) = (dim.width, dim.height);
+    let luminance: Vec<f64> = (0..h).flat_map(|y| img.row(y).iter().map(|p| (p.r + p.g + p.b) / 3.0)).collect();
+    let (median, noise) = {
+        let mut values: Vec<f64> = luminance.iter().cloned().filter(|v| v.is_finite()).collect();
+        let (median, mad) = median_mad(&mut values);
+        (median, 1.4826 * mad)
+    };
+    let threshold = median + sigma * noise;
+    let edge = ANNULUS.1.ceil() as usize;
+    let mut stars = Vec::new();
+    if w <= 2 * edge || h <= 2 * edge {
+        return stars;
+    }
+    for y in edge..h - edge {
+        for x in edge..w - edge {
+            let l = luminance[y * w + x];
+            if !(l > threshold) || !is_peak(&luminance, w, x, y) {
+                continue;
+            }
+            if let Some(star) = measure(img, x, y, saturation) {
+                stars.push(star);
+            }
+        }
+    }
+    stars.sort_by(|a, b| total(&b.flux).partial_cmp(&total(&a.flux)).unwrap());
+    stars
+}
+
+fn total(p: &Rgb<f64>) -> f64 {
+    p.r + p.g + p.b
+}
+
+/// Highest in its 5 × 5 neighbourhood. Of equal neighbours, only the first in raster order is a peak.
+fn is_peak(luminance: &[f64], w: usize, x: usize, y: usize) -> bool {
+    let l = luminance[y * w + x];
+    for ny in y - 2..y + 3 {
+        for nx in x - 2..x + 3 {
+            let n = luminance[ny * w + nx];
+            let before = (ny, nx) < (y, x);
+            if n > l || (before && n == l) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+fn measure(img: &OwnedImage<Rgb<f64>>, cx: usize, cy: usize, saturation: f64) -> Option<Star> {
+    let r = ANNULUS.1.ceil() as usize;
+    let (mut br, mut bg, mut bb) = (Vec::new(), Vec::new(), Vec::new());
+    let mut aperture = Vec::new();
+    for y in cy - r..cy + r + 1 {
+        for x in cx - r..cx + r + 1 {
+            let p = *img.pixel_at(x, y);
+            let d = ((x as f64 - cx as f64).powi(2) + (y as f64 - cy as f64).powi(2)).sqrt();
+            if d <= APERTURE {
+                if !(p.r.is_finite() && p.g.is_finite() && p.b.is_finite()) {
+                    return None;
+                }
+                if p.r >= saturation || p.g >= saturation || p.b >= saturation {
+                    return None;
+                }
+                aperture.push((x, y, p));
+            } else if d >= ANNULUS.0 && d <= ANNULUS.1 {
+                br.push(p.r);
+                bg.push(p.g);
+                bb.push(p.b);
+            }
+        }
+    }
+    let background = Rgb { r: median(&mut br), g: median(&mut bg), b: median(&mut bb) };
+    let mut flux = Rgb { r: 0.0, g: 0.0, b: 0.0 };
+    let (mut sx, mut sy, mut sw) = (0.0, 0.0, 0.0);
+    for &(x, y, p) in aperture.iter() {
+        let p = p - background;
+        flux += p;
+        let weight = total(&p).max(0.0);
+        sx += (x as f64 + 0.5) * weight;
+        sy += (y as f64 + 0.5) * weight;
+        sw += weight;
+    }
+    if flux.r <= 0.0 || flux.g <= 0.0 || flux.b <= 0.0 || sw <= 0.0 {
+        return None;
+    }
+    let (x, y) = (sx / sw, sy / sw);
+    let moment: f64 = aperture.iter().map(|&(px, py, p)| {
+        let d2 = (px as f64 + 0.5 - x).powi(2) + (py as f64 + 0.5 - y).powi(2);
+        total(&(p - background)).max(0.0) * d2
+    }).sum();
+    // A gaussian's FWHM is 2 √(2 ln 2) σ.
+    let fwhm = 2.3548 * (moment / (2.0 * sw)).sqrt();
+    Some(Star { x, y, flux, fwhm })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{sky, gray};
+
+    #[test]
+    fn finds_unsaturated_stars() {
+        let noise = |x: f64, y: f64| gray(0.1 + ((x as usize * 7919 + y as usize * 104729) % 97) as f64 / 97.0 * 0.002);
+        let img = sky(100, 80, noise, &[(30.3, 20.5, 1.0, gray(0.5)), (70.5, 50.7, 1.0, gray(0.2)), (50.0, 60.0, 1.0, gray(5.0))]);
+        let stars = find(&img, 10.0, 0.95);
+        assert_eq!(stars.len(), 2);
+        assert!((stars[0].x - 30.3).abs() < 0.05 && (stars[0].y - 20.5).abs() < 0.05, "{:?}", stars[0]);
+        assert!((stars[0].fwhm - 2.3548).abs() < 0.1, "{:?}", stars[0]);
+        // The flux of a gaussian with σ = 1 is 2π times its peak.
+        assert!((stars[1].flux.g / (0.2 * 2.0 * f64::consts::PI) - 1.0).abs() < 0.05, "{:?}", stars[1]);
+    }
+}
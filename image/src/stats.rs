@@ -0,0 +1,49 @@
+//! Medians and median absolute deviations, the robust estimates the tools use for
+//! backgrounds, noise and scales.
+//!
+//! For an even count the median is the mean of the two middle values. Without any values
+//! it's NaN, which callers must check for, and the values must not contain NaNs.
+
+use num::Float;
+
+/// Median of `values`, reordering them.
+pub fn median<T: Float>(values: &mut [T]) -> T {
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    sorted_median(values)
+}
+
+/// Median of values already sorted in ascending order.
+pub fn sorted_median<T: Float>(values: &[T]) -> T {
+    let n = values.len();
+    if n == 0 {
+        T::nan()
+    } else if n % 2 == 1 {
+        values[n / 2]
+    } else {
+        (values[n / 2 - 1] + values[n / 2]) / T::from(2).unwrap()
+    }
+}
+
+/// Median and median absolute deviation from it, unscaled, reordering and overwriting `values`.
+pub fn median_mad<T: Float>(values: &mut [T]) -> (T, T) {
+    let m = median(values);
+    for v in values.iter_mut() {
+        *v = (*v - m).abs();
+    }
+    (m, median(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&mut [4.0f32, 1.0, 3.0, 2.0]), 2.5);
+        assert!(median::<f64>(&mut []).is_nan());
+        assert_eq!(median_mad(&mut [1.0, 2.0, 3.0, 4.0, 100.0]), (3.0, 1.0));
+        let (m, mad) = median_mad::<f64>(&mut []);
+        assert!(m.is_nan() && mad.is_nan());
+    }
+}
@@ -0,0 +1,243 @@
+//! Non-linear stretches that bring out faint detail in a linear stack.
+//!
+//! They all expect values between 0 and 1, see `normalize`.
+
+use std::f64;
+use std::str::FromStr;
+use image::{Image, OwnedImage};
+use rgb::Rgb;
+use stats::median_mad;
+
+/// Rescales the finite values of all channels to 0..1. Anything else, like the NaN of
+/// uncovered pixels, becomes 0.
+pub fn normalize(img: &OwnedImage<Rgb<f64>>) -> OwnedImage<Rgb<f64>> {
+    let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+    for p in img.pixels().iter() {
+        for &v in [p.r, p.g, p.b].iter().filter(|v| v.is_finite()) {
+            min = min.min(v);
+            max = max.max(v);
+        }
+    }
+    let d = if max > min { max - min } else { 1.0 };
+    let f = |v: f64| if v.is_finite() { (v - min) / d } else { 0.0 };
+    img.clone_map(|p| Rgb { r: f(p.r), g: f(p.g), b: f(p.b) })
+}
+
+/// Arcsinh stretch of the luminance, applied to all channels alike so that colors keep their
+/// ratios. `stretch` is how much the faint end is lifted, `black` is subtracted first.
+/// Pixels that would saturate are scaled down as a whole rather than clipped per channel.
+pub fn arcsinh(img: &OwnedImage<Rgb<f64>>, stretch: f64, black: f64) -> OwnedImage<Rgb<f64>> {
+    assert!(stretch >= 0.0, "stretch must not be negative");
+    assert!(black < 1.0, "black point must be below 1");
+    let f = |v: f64| ((v - black) / (1.0 - black)).max(0.0);
+    img.clone_map(|p| {
+        let p = Rgb { r: f(p.r), g: f(p.g), b: f(p.b) };
+        let l = (p.r + p.g + p.b) / 3.0;
+        if l <= 0.0 {
+            return Rgb { r: 0.0, g: 0.0, b: 0.0 };
+        }
+        let k = if stretch > 0.0 { (stretch * l).asinh() / (l * stretch.asinh()) } else { 1.0 };
+        let p = p * k;
+        let max = p.r.max(p.g).max(p.b);
+        if max > 1.0 { p * (1.0 / max) } else { p }
+    })
+}
+
+/// Midtones transfer function: maps 0 to 0, 1 to 1 and `m` to 0.5.
+pub fn mtf(m: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        0.0
+    } else if x >= 1.0 {
+        1.0
+    } else {
+        (m - 1.0) * x / ((2.0 * m - 1.0) * x - m)
+    }
+}
+
+/// Screen transfer function: clips below `shadows` and above `highlights`, then applies
+/// the midtones transfer function to what's in between.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stf {
+    pub shadows: f64,
+    pub midtones: f64,
+    pub highlights: f64,
+}
+
+impl Stf {
+    pub fn apply(&self, x: f64) -> f64 {
+        mtf(self.midtones, (x - self.shadows) / (self.highlights - self.shadows))
+    }
+
+    /// Estimates the function that takes the background of `values` to `target_background`.
+    /// The shadows are clipped at `shadows_clipping` (usually negative) times the normalized
+    /// MAD from the median. Non-finite values are ignored.
+    pub fn auto(values: &[f64], target_background: f64, shadows_clipping: f64) -> Self {
+        let mut finite: Vec<f64> = values.iter().cloned().filter(|v| v.is_finite()).collect();
+        let (median, mad) = if finite.is_empty() { (0.0, 0.0) } else { median_mad(&mut finite) };
+        let shadows = (median + shadows_clipping * 1.4826 * mad).max(0.0).min(1.0);
+        let background = if shadows < 1.0 { (median - shadows) / (1.0 - shadows) } else { 0.0 };
+        Stf {
+            shadows,
+            midtones: mtf(target_background, background),
+            highlights: 1.0,
+        }
+    }
+}
+
+/// `Stf::auto` for each channel. With `linked`, all channels get the average of the three,
+/// which keeps the color balance of the image.
+pub fn auto_stf(img: &OwnedImage<Rgb<f64>>, target_background: f64, shadows_clipping: f64, linked: bool) -> Rgb<Stf> {
+    let channel = |f: &Fn(&Rgb<f64>) -> f64| {
+        let values: Vec<f64> = img.pixels().iter().map(f).collect();
+        Stf::auto(&values, target_background, shadows_clipping)
+    };
+    let stf = Rgb { r: channel(&|p| p.r), g: channel(&|p| p.g), b: channel(&|p| p.b) };
+    if !linked {
+        return stf;
+    }
+    let average = Stf {
+        shadows: (stf.r.shadows + stf.g.shadows + stf.b.shadows) / 3.0,
+        midtones: (stf.r.midtones + stf.g.midtones + stf.b.midtones) / 3.0,
+        highlights: 1.0,
+    };
+    Rgb { r: average, g: average, b: average }
+}
+
+pub fn apply_stf(img: &OwnedImage<Rgb<f64>>, stf: &Rgb<Stf>) -> OwnedImage<Rgb<f64>> {
+    img.clone_map(|p| Rgb { r: stf.r.apply(p.r), g: stf.g.apply(p.g), b: stf.b.apply(p.b) })
+}
+
+/// A tone curve through control points, parsed from `X:Y,X:Y,...` with both in 0..1.
+/// It is a monotone cubic between the points, so it never overshoots them, and flat beyond the ends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve {
+    points: Vec<(f64, f64)>,
+    slopes: Vec<f64>,
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("invalid curve: {} (expected X:Y,X:Y,... with increasing X)", s);
+        let points: Vec<(f64, f64)> = s.split(',').map(|p| {
+            let v: Vec<f64> = p.split(':').map(|v| v.trim().parse()).collect::<Result<_, _>>().map_err(|_| error())?;
+            if v.len() == 2 { Ok((v[0], v[1])) } else { Err(error()) }
+        }).collect::<Result<_, _>>()?;
+        if points.len() < 2 || points.windows(2).any(|w| w[1].0 <= w[0].0) {
+            return Err(error());
+        }
+        Ok(Curve::new(points))
+    }
+}
+
+impl Curve {
+    /// `points` must have increasing X.
+    pub fn new(points: Vec<(f64, f64)>) -> Self {
+        let n = points.len();
+        let secants: Vec<f64> = points.windows(2).map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0)).collect();
+        // Fritsch–Carlson: the average of the neighbouring secants, 0 at extrema,
+        // limited so the cubic stays monotone.
+        let mut slopes: Vec<f64> = (0..n).map(|i| {
+            if i == 0 {
+                secants[0]
+            } else if i == n - 1 {
+                secants[n - 2]
+            } else if secants[i - 1] * secants[i] <= 0.0 {
+                0.0
+            } else {
+                (secants[i - 1] + secants[i]) / 2.0
+            }
+        }).collect();
+        for i in 0..n - 1 {
+            if secants[i] == 0.0 {
+                slopes[i] = 0.0;
+                slopes[i + 1] = 0.0;
+                continue;
+            }
+            let (a, b) = (slopes[i] / secants[i], slopes[i + 1] / secants[i]);
+            let h = a.hypot(b);
+            if h > 3.0 {
+                slopes[i] = 3.0 * a / h * secants[i];
+                slopes[i + 1] = 3.0 * b / h * secants[i];
+            }
+        }
+        Curve { points, slopes }
+    }
+
+    pub fn apply(&self, x: f64) -> f64 {
+        let p = &self.points;
+        if x <= p[0].0 {
+            return p[0].1;
+        }
+        if x >= p[p.len() - 1].0 {
+            return p[p.len() - 1].1;
+        }
+        let i = p.windows(2).position(|w| x < w[1].0).unwrap();
+        let ((x0, y0), (x1, y1)) = (p[i], p[i + 1]);
+        let h = x1 - x0;
+        let t = (x - x0) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * y0 + (t3 - 2.0 * t2 + t) * h * self.slopes[i]
+            + (-2.0 * t3 + 3.0 * t2) * y1 + (t3 - t2) * h * self.slopes[i + 1]
+    }
+}
+
+pub fn curves(img: &OwnedImage<Rgb<f64>>, curve: &Curve) -> OwnedImage<Rgb<f64>> {
+    img.clone_map(|p| Rgb { r: curve.apply(p.r), g: curve.apply(p.g), b: curve.apply(p.b) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stats;
+    use test_support::row;
+
+    #[test]
+    fn test_mtf() {
+        assert_eq!(mtf(0.2, 0.0), 0.0);
+        assert_eq!(mtf(0.2, 1.0), 1.0);
+        assert!((mtf(0.2, 0.2) - 0.5).abs() < 1e-12);
+        assert_eq!(mtf(0.5, 0.3), 0.3);
+    }
+
+    #[test]
+    fn arcsinh_keeps_colors() {
+        let img = arcsinh(&row(vec![Rgb { r: 0.01, g: 0.02, b: 0.03 }, Rgb { r: 1.0, g: 0.5, b: 0.0 }]), 100.0, 0.0);
+        let p = img.pixels[0];
+        assert!(p.g > 0.1, "{:?}", p);
+        assert!((p.g / p.r - 2.0).abs() < 1e-9 && (p.b / p.r - 3.0).abs() < 1e-9);
+        assert_eq!(img.pixels[1], Rgb { r: 1.0, g: 0.5, b: 0.0 });
+    }
+
+    #[test]
+    fn auto_stf_sets_the_background() {
+        let pixels = (0..101).map(|i| {
+            let v = 0.1 + 0.001 * (i % 11) as f64;
+            Rgb { r: v, g: v, b: 2.0 * v }
+        }).collect();
+        let img = row(pixels);
+        let stf = auto_stf(&img, 0.25, -2.8, false);
+        assert!(stf.r.shadows > 0.09 && stf.r.shadows < 0.105, "{:?}", stf.r);
+        let median = stats::median(&mut img.pixels.iter().map(|p| p.r).collect::<Vec<_>>());
+        assert!((stf.r.apply(median) - 0.25).abs() < 1e-9);
+        let linked = auto_stf(&img, 0.25, -2.8, true);
+        assert_eq!(linked.r, linked.b);
+    }
+
+    #[test]
+    fn curve_goes_through_its_points() {
+        let curve: Curve = "0:0, 0.25:0.4, 0.5:0.5, 1:1".parse().unwrap();
+        for &(x, y) in [(0.0, 0.0), (0.25, 0.4), (0.5, 0.5), (1.0, 1.0)].iter() {
+            assert!((curve.apply(x) - y).abs() < 1e-12);
+        }
+        let mut last = 0.0;
+        for i in 0..101 {
+            let y = curve.apply(i as f64 / 100.0);
+            assert!(y >= last && y <= 1.0);
+            last = y;
+        }
+        assert!("0:0,0:1".parse::<Curve>().is_err());
+        assert!("0:0".parse::<Curve>().is_err());
+    }
+}
@@ -0,0 +1,33 @@
+//! Images shared by the tests.
+
+use image::{OwnedImage, ImageDimensions};
+use rgb::Rgb;
+
+/// An image of one row of `pixels`.
+pub fn row(pixels: Vec<Rgb<f64>>) -> OwnedImage<Rgb<f64>> {
+    let width = pixels.len();
+    OwnedImage { dimensions: ImageDimensions { width, height: 1, pitch: width }, pixels }
+}
+
+/// `background` at each pixel center, plus gaussian `stars` of `(x, y, sigma, peak)`,
+/// clipped at 1 like a saturated sensor.
+pub fn sky<F: Fn(f64, f64) -> Rgb<f64>>(width: usize, height: usize, background: F, stars: &[(f64, f64, f64, Rgb<f64>)]) -> OwnedImage<Rgb<f64>> {
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let (cx, cy) = (x as f64 + 0.5, y as f64 + 0.5);
+            let mut p = background(cx, cy);
+            for &(sx, sy, sigma, peak) in stars {
+                let d2 = (cx - sx).powi(2) + (cy - sy).powi(2);
+                p += peak * (-d2 / (2.0 * sigma * sigma)).exp();
+            }
+            pixels.push(Rgb { r: p.r.min(1.0), g: p.g.min(1.0), b: p.b.min(1.0) });
+        }
+    }
+    OwnedImage { dimensions: ImageDimensions { width, height, pitch: width }, pixels }
+}
+
+/// The same value in all channels.
+pub fn gray(v: f64) -> Rgb<f64> {
+    Rgb { r: v, g: v, b: v }
+}
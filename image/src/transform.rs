@@ -0,0 +1,83 @@
+//! Cropping and resizing.
+
+use image::{Image, OwnedImage, ImageDimensions};
+use rgb::Rgb;
+use color::Region;
+
+pub fn crop(img: &OwnedImage<Rgb<f64>>, region: Region) -> OwnedImage<Rgb<f64>> {
+    let dim = img.dimensions();
+    assert!(region.x + region.width <= dim.width && region.y + region.height <= dim.height, "region outside the image: {:?}", region);
+    OwnedImage {
+        dimensions: ImageDimensions { width: region.width, height: region.height, pitch: region.width },
+        pixels: (region.y..region.y + region.height)
+            .flat_map(|y| img.row(y)[region.x..region.x + region.width].iter().cloned())
+            .collect(),
+    }
+}
+
+/// Resizes to `width` × `height`. Shrinking averages the pixels each output pixel covers,
+/// so stars and noise don't alias; enlarging interpolates bilinearly.
+pub fn resize(img: &OwnedImage<Rgb<f64>>, width: usize, height: usize) -> OwnedImage<Rgb<f64>> {
+    let dim = img.dimensions();
+    assert!(width > 0 && height > 0, "empty size: {}x{}", width, height);
+    let (sx, sy) = (dim.width as f64 / width as f64, dim.height as f64 / height as f64);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            pixels.push(if sx > 1.0 || sy > 1.0 {
+                area_average(img, x as f64 * sx, y as f64 * sy, sx, sy)
+            } else {
+                bilinear(img, (x as f64 + 0.5) * sx - 0.5, (y as f64 + 0.5) * sy - 0.5)
+            });
+        }
+    }
+    OwnedImage { dimensions: ImageDimensions { width, height, pitch: width }, pixels }
+}
+
+/// Average over the box from (x, y) of size w × h, weighting the pixels it partly covers.
+fn area_average(img: &OwnedImage<Rgb<f64>>, x: f64, y: f64, w: f64, h: f64) -> Rgb<f64> {
+    let dim = img.dimensions();
+    let mut sum = Rgb { r: 0.0, g: 0.0, b: 0.0 };
+    let mut total = 0.0;
+    for py in y.floor() as usize..((y + h).ceil() as usize).min(dim.height) {
+        let wy = ((py + 1) as f64).min(y + h) - (py as f64).max(y);
+        for px in x.floor() as usize..((x + w).ceil() as usize).min(dim.width) {
+            let wx = ((px + 1) as f64).min(x + w) - (px as f64).max(x);
+            sum += *img.pixel_at(px, py) * (wx * wy);
+            total += wx * wy;
+        }
+    }
+    sum * (1.0 / total)
+}
+
+fn bilinear(img: &OwnedImage<Rgb<f64>>, x: f64, y: f64) -> Rgb<f64> {
+    let dim = img.dimensions();
+    let (x, y) = (x.max(0.0).min((dim.width - 1) as f64), y.max(0.0).min((dim.height - 1) as f64));
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(dim.width - 1), (y0 + 1).min(dim.height - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    *img.pixel_at(x0, y0) * ((1.0 - fx) * (1.0 - fy)) + *img.pixel_at(x1, y0) * (fx * (1.0 - fy))
+        + *img.pixel_at(x0, y1) * ((1.0 - fx) * fy) + *img.pixel_at(x1, y1) * (fx * fy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crops_and_resizes() {
+        let (w, h) = (6, 4);
+        let img = OwnedImage {
+            dimensions: ImageDimensions { width: w, height: h, pitch: w },
+            pixels: (0..w * h).map(|i| { let v = i as f64; Rgb { r: v, g: v, b: v } }).collect(),
+        };
+        let cropped = crop(&img, Region { x: 1, y: 2, width: 3, height: 2 });
+        assert_eq!(cropped.pixels.iter().map(|p| p.r).collect::<Vec<_>>(), vec![13.0, 14.0, 15.0, 19.0, 20.0, 21.0]);
+        let half = resize(&img, 3, 2);
+        assert_eq!(half.pixels[0].r, (0.0 + 1.0 + 6.0 + 7.0) / 4.0);
+        let double = resize(&img, 12, 8);
+        assert_eq!(double.pixels[0].r, 0.0);
+        // Bilinear is exact on this linear ramp: pixel (3, 2) is at (1.25, 0.75) of the original.
+        assert!((double.pixels[2 * 12 + 3].r - (0.75 * 6.0 + 1.25)).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,246 @@
+//! À trous (starlet) wavelets: an image as the sum of detail layers at scales of 1, 2, 4... pixels
+//! and a smooth residual. Shrinking the small layers takes out noise, amplifying them sharpens,
+//! and the residual alone is the large scale background.
+
+use std::str::FromStr;
+use num::Float;
+use image::{Image, OwnedImage, ImageDimensions};
+use rgb::Rgb;
+use stats::median;
+
+/// The B3 spline kernel, applied along rows and then columns.
+const B3: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Standard deviation of each layer of the decomposition of white noise of standard deviation 1.
+const NOISE: [f64; 8] = [0.8908, 0.2007, 0.0856, 0.0413, 0.0205, 0.0103, 0.0052, 0.0026];
+
+/// Which part of the image a correction applies to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Area {
+    All,
+    /// Stars and nebulosity, well above the noise.
+    Signal,
+    /// The rest.
+    Background,
+}
+
+impl FromStr for Area {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Area::All),
+            "signal" => Ok(Area::Signal),
+            "background" => Ok(Area::Background),
+            _ => Err(format!("unknown area: {} (expected all, signal or background)", s))
+        }
+    }
+}
+
+/// One value per layer, parsed from a comma separated list, starting with the finest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PerLayer(pub Vec<f64>);
+
+impl FromStr for PerLayer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',').map(|v| v.trim().parse()).collect::<Result<_, _>>()
+            .map(PerLayer)
+            .map_err(|_| format!("invalid layer values: {} (expected numbers separated by commas)", s))
+    }
+}
+
+pub struct Decomposition<P> {
+    pub width: usize,
+    pub height: usize,
+    /// Details, from the finest scale.
+    pub layers: Vec<Vec<P>>,
+    pub residual: Vec<P>,
+}
+
+impl<P: Float> Decomposition<P> {
+    /// Decomposes a row major `width` × `height` array into `layers` layers and the residual.
+    pub fn new(data: &[P], width: usize, height: usize, layers: usize) -> Self {
+        assert_eq!(data.len(), width * height);
+        let mut smooth = data.to_vec();
+        let mut res = Vec::with_capacity(layers);
+        for j in 0..layers {
+            let next = convolve(&smooth, width, height, 1 << j);
+            res.push(smooth.iter().zip(next.iter()).map(|(&a, &b)| a - b).collect());
+            smooth = next;
+        }
+        Decomposition { width, height, layers: res, residual: smooth }
+    }
+
+    pub fn from_image(img: &OwnedImage<P>, layers: usize) -> Self where P: Default {
+        let dim = img.dimensions();
+        let data: Vec<P> = (0..dim.height).flat_map(|y| img.row(y).iter().cloned()).collect();
+        Decomposition::new(&data, dim.width, dim.height, layers)
+    }
+
+    /// The sum of the layers and the residual.
+    pub fn reconstruct(&self) -> Vec<P> {
+        let mut res = self.residual.clone();
+        for layer in self.layers.iter() {
+            for (r, &v) in res.iter_mut().zip(layer.iter()) {
+                *r = *r + v;
+            }
+        }
+        res
+    }
+
+    pub fn to_image(&self) -> OwnedImage<P> {
+        OwnedImage {
+            dimensions: ImageDimensions { width: self.width, height: self.height, pitch: self.width },
+            pixels: self.reconstruct(),
+        }
+    }
+
+    /// Standard deviation of the image's noise, from the finest layer.
+    pub fn noise(&self) -> f64 {
+        let mut values: Vec<f64> = self.layers[0].iter().map(|v| v.to_f64().unwrap().abs()).filter(|v| v.is_finite()).collect();
+        median(&mut values) / 0.6745 / NOISE[0]
+    }
+
+    /// Soft thresholds each layer at its value of `thresholds` times the noise it has.
+    /// Layers without a threshold are kept. `mask`, 0..1 per pixel, limits where this applies.
+    pub fn denoise(&mut self, thresholds: &[f64], mask: Option<&[f64]>) {
+        let noise = self.noise();
+        for (j, (layer, &k)) in self.layers.iter_mut().zip(thresholds.iter()).enumerate() {
+            let t = k * noise * layer_noise(j);
+            for (i, c) in layer.iter_mut().enumerate() {
+                let v = c.to_f64().unwrap();
+                let shrunk = v.signum() * (v.abs() - t).max(0.0);
+                let m = mask.map_or(1.0, |m| m[i]);
+                *c = P::from(m * shrunk + (1.0 - m) * v).unwrap();
+            }
+        }
+    }
+
+    /// Multiplies each layer by its value of `gains`; above 1 sharpens. Layers without a gain are kept.
+    pub fn enhance(&mut self, gains: &[f64], mask: Option<&[f64]>) {
+        for (layer, &g) in self.layers.iter_mut().zip(gains.iter()) {
+            for (i, c) in layer.iter_mut().enumerate() {
+                let m = mask.map_or(1.0, |m| m[i]);
+                *c = *c * P::from(1.0 + m * (g - 1.0)).unwrap();
+            }
+        }
+    }
+}
+
+/// Standard deviation of layer `j` of the decomposition of white noise of standard deviation 1.
+pub fn layer_noise(j: usize) -> f64 {
+    if j < NOISE.len() { NOISE[j] } else { NOISE[NOISE.len() - 1] / (1 << (j + 1 - NOISE.len())) as f64 }
+}
+
+/// Index `i` reflected back into 0..n.
+fn mirror(mut i: isize, n: usize) -> usize {
+    let n = n as isize;
+    if n == 1 {
+        return 0;
+    }
+    loop {
+        if i < 0 {
+            i = -i;
+        } else if i >= n {
+            i = 2 * (n - 1) - i;
+        } else {
+            return i as usize;
+        }
+    }
+}
+
+/// The B3 kernel with `step` - 1 holes between its taps.
+fn convolve<P: Float>(data: &[P], width: usize, height: usize, step: usize) -> Vec<P> {
+    let taps = |f: &Fn(isize) -> P| B3.iter().enumerate()
+        .fold(P::zero(), |acc, (k, &w)| acc + f((k as isize - 2) * step as isize) * P::from(w).unwrap());
+    let mut rows = vec![P::zero(); width * height];
+    for y in 0..height {
+        let row = &data[y * width..(y + 1) * width];
+        for x in 0..width {
+            rows[y * width + x] = taps(&|d| row[mirror(x as isize + d, width)]);
+        }
+    }
+    let mut res = vec![P::zero(); width * height];
+    for y in 0..height {
+        for x in 0..width {
+            res[y * width + x] = taps(&|d| rows[mirror(y as isize + d, height) * width + x]);
+        }
+    }
+    res
+}
+
+/// 0..1 per pixel: 0 at the background level, 1 from `sigma` times the noise above it, judged
+/// on the image without its two finest layers so single noisy pixels don't count.
+pub fn signal_mask<P: Float>(data: &[P], width: usize, height: usize, sigma: f64) -> Vec<f64> {
+    let d = Decomposition::new(data, width, height, 2);
+    let noise = d.noise();
+    let mut values: Vec<f64> = d.residual.iter().map(|v| v.to_f64().unwrap()).filter(|v| v.is_finite()).collect();
+    let background = median(&mut values);
+    d.residual.iter().map(|v| ((v.to_f64().unwrap() - background) / (sigma * noise)).max(0.0).min(1.0)).collect()
+}
+
+impl Area {
+    /// The mask of this area of `data`, None for all of it.
+    pub fn mask<P: Float>(&self, data: &[P], width: usize, height: usize, sigma: f64) -> Option<Vec<f64>> {
+        match *self {
+            Area::All => None,
+            Area::Signal => Some(signal_mask(data, width, height, sigma)),
+            Area::Background => Some(signal_mask(data, width, height, sigma).into_iter().map(|m| 1.0 - m).collect()),
+        }
+    }
+}
+
+/// Runs `f` on each channel of `img` as a row major array.
+pub fn map_channels<F>(img: &OwnedImage<Rgb<f64>>, mut f: F) -> OwnedImage<Rgb<f64>>
+where F: FnMut(Vec<f64>) -> Vec<f64> {
+    let dim = img.dimensions();
+    let channel = |c: &Fn(&Rgb<f64>) -> f64| (0..dim.height).flat_map(|y| img.row(y).iter().map(|p| c(p))).collect::<Vec<f64>>();
+    let r = f(channel(&|p| p.r));
+    let g = f(channel(&|p| p.g));
+    let b = f(channel(&|p| p.b));
+    OwnedImage {
+        dimensions: ImageDimensions { width: dim.width, height: dim.height, pitch: dim.width },
+        pixels: (0..r.len()).map(|i| Rgb { r: r[i], g: g[i], b: b[i] }).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{self, Rng};
+
+    #[test]
+    fn reconstructs_exactly() {
+        let mut rng = rand::thread_rng();
+        let data: Vec<f32> = (0..37 * 23).map(|_| rng.gen()).collect();
+        let d = Decomposition::new(&data, 37, 23, 5);
+        assert_eq!(d.layers.len(), 5);
+        for (a, b) in d.reconstruct().iter().zip(data.iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+        assert_eq!("3, 2,1.5".parse(), Ok(PerLayer(vec![3.0, 2.0, 1.5])));
+    }
+
+    #[test]
+    fn denoise_keeps_stars() {
+        let mut rng = rand::thread_rng();
+        let (w, h) = (64, 64);
+        let clean: Vec<f64> = (0..w * h).map(|i| {
+            let d2 = ((i % w) as f64 - 32.0).powi(2) + ((i / w) as f64 - 32.0).powi(2);
+            0.1 + 0.8 * (-d2 / 8.0).exp()
+        }).collect();
+        // Close to gaussian noise of standard deviation 0.01.
+        let noisy: Vec<f64> = clean.iter().map(|v| v + ((0..12).map(|_| rng.gen::<f64>()).sum::<f64>() - 6.0) * 0.01).collect();
+        let mut d = Decomposition::new(&noisy, w, h, 4);
+        assert!((d.noise() - 0.01).abs() < 0.001, "{}", d.noise());
+        let mask = Area::Background.mask(&noisy, w, h, 3.0).unwrap();
+        assert!(mask[0] > 0.9 && mask[32 * w + 32] == 0.0);
+        d.denoise(&[3.0, 2.0, 1.0], Some(&mask));
+        let denoised = d.reconstruct();
+        let error = |a: &[f64]| a.iter().zip(clean.iter()).map(|(a, b)| (a - b).powi(2)).sum::<f64>();
+        assert!(error(&denoised) < error(&noisy) * 0.5, "{} {}", error(&denoised), error(&noisy));
+        assert!((denoised[32 * w + 32] - noisy[32 * w + 32]).abs() < 1e-12);
+    }
+}
@@ -30,6 +30,13 @@ fn stretch(data: &[f32]) -> Vec<u16> {
     }).collect()
 }
 
+/// Maps 0..1 to the 16-bit range, clamping what's outside.
+fn unit(data: &[f32]) -> Vec<u16> {
+    data.iter().map(|&v| {
+        (v.max(0.0).min(1.0) * u16::MAX as f32).round() as u16
+    }).collect()
+}
+
 pub fn convert_open<P: AsRef<Path>>(path: P, map: &str) -> (usize, usize, Vec<f32>) {
     let out = Command::new("convert")
         .arg("-verbose")
@@ -52,14 +59,33 @@ pub fn convert_open<P: AsRef<Path>>(path: P, map: &str) -> (usize, usize, Vec<f3
 }
 
 pub fn convert_save<P: AsRef<Path>>(data: &[f32], width: usize, height: usize, format: &str, magick_type: &str, path: P) {
-    let data = stretch(data);
+    convert_save_with_comment(data, width, height, format, magick_type, None, path)
+}
+
+/// Like `convert_save`, with `comment` stored in the file, as the image description of a TIFF.
+pub fn convert_save_with_comment<P: AsRef<Path>>(data: &[f32], width: usize, height: usize, format: &str, magick_type: &str, comment: Option<&str>, path: P) {
+    save_u16(stretch(data), width, height, format, magick_type, comment, path)
+}
+
+/// Like `convert_save_with_comment`, but for data that is already stretched to 0..1: it's
+/// written as it is, with what's outside clamped, instead of rescaling its min..max.
+pub fn convert_save_unit<P: AsRef<Path>>(data: &[f32], width: usize, height: usize, format: &str, magick_type: &str, comment: Option<&str>, path: P) {
+    save_u16(unit(data), width, height, format, magick_type, comment, path)
+}
+
+fn save_u16<P: AsRef<Path>>(data: Vec<u16>, width: usize, height: usize, format: &str, magick_type: &str, comment: Option<&str>, path: P) {
     let data: Vec<u8> = convert_vec(data);
-    let child = Command::new("convert")
+    let mut command = Command::new("convert");
+    command
         .arg("-size").arg(format!("{}x{}", width, height))
         .arg("-depth").arg("16")
         //.arg("-define").arg("quantum:format=floating-point")
-        .arg(format!("{}:-", format))
+        .arg(format!("{}:-", format));
         //.arg("-depth").arg("16")
+    if let Some(comment) = comment {
+        command.arg("-set").arg("comment").arg(comment);
+    }
+    let child = command
         .arg("-type").arg(magick_type)
         .arg(path.as_ref())
         .stdin(Stdio::piped())
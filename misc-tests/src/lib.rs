@@ -14,15 +14,17 @@ use crossbeam_utils::scoped::scope;
 use crossbeam_channel::{bounded, unbounded};
 use ndarray::prelude::*;
 use image::prelude::*;
+use image::stretch::Stf;
 
 pub fn write_jpeg(img: &Array2<f64>, count: usize) {
     let mut img = img.clone();
     img /= count as f64;
     println!("minmax: {:?}", img.min_max());
-    let img = img.mapv(|v| {
-        v.powf(1.0 / 3.2)
-    });
-    println!("minmax after gamma: {:?}", img.min_max());
+    let (min, max) = img.min_max();
+    let img = img.mapv(|v| (v - min) / (max - min));
+    let stf = Stf::auto(img.as_slice().unwrap(), 0.25, -2.8);
+    let img = img.mapv(|v| stf.apply(v));
+    println!("minmax after stf {:?}: {:?}", stf, img.min_max());
     //let img = img.stretch::<u8>(0.0, 0.04, 0, 255).to_rgb();
     let img = img.stretch_to_bounds::<u8>().to_rgb();
     fs::write(format!("out/stacked_{:04}.jpg", count), turbojpeg::compress(&img).unwrap()).unwrap();
@@ -0,0 +1,99 @@
+extern crate image;
+extern crate geom;
+extern crate fits;
+extern crate convert;
+extern crate star_stuff;
+extern crate star_aligner;
+extern crate structopt;
+#[macro_use] extern crate structopt_derive;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use structopt::StructOpt;
+use image::{Image, Rgb};
+use geom::{Point, Matrix3x3};
+use convert::convert_vec;
+use star_stuff::mosaic;
+use star_stuff::resample::Interpolation;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "mosaic", about = "Assembles stacked panels into one mosaic, aligned on the stars in their overlaps")]
+struct Args {
+    #[structopt(long = "output", help = "FITS file of the mosaic")]
+    flag_output: String,
+    #[structopt(long = "max-stars", default_value = "200")]
+    flag_max_stars: usize,
+    #[structopt(long = "min-matching-stars", default_value = "10")]
+    flag_min_matching_stars: usize,
+    #[structopt(long = "threshold", help = "px", default_value = "1")]
+    flag_threshold: f64,
+    #[structopt(long = "feather", help = "Width of the blend at the seams, in pixels", default_value = "100")]
+    flag_feather: f64,
+    #[structopt(long = "interpolation", help = "nearest, bilinear, bicubic, lanczos3 or lanczos4", default_value = "lanczos3")]
+    flag_interpolation: Interpolation,
+    #[structopt(help = "Stacked panels, as written by stack with --uncovered nan so their empty edges are left out. The first one sets the orientation and levels")]
+    arg_input: Vec<String>,
+}
+
+fn main() {
+    let args = Args::from_args();
+    assert!(args.arg_input.len() >= 2, "a mosaic needs at least two panels");
+    let options = || star_aligner::Options {
+        max_stars: args.flag_max_stars,
+        min_matching_stars: args.flag_min_matching_stars,
+        threshold: args.flag_threshold,
+    };
+    let stars: Vec<Vec<Point<f64>>> = args.arg_input.iter()
+        .map(|filename| star_aligner::stars(filename, args.flag_max_stars))
+        .collect();
+
+    // Panels that don't overlap the first one are aligned on one that's already aligned.
+    let mut transforms: Vec<Option<Matrix3x3<f64>>> = vec![None; stars.len()];
+    transforms[0] = Some(Matrix3x3::identity());
+    loop {
+        let mut progress = false;
+        for i in 0..stars.len() {
+            if transforms[i].is_some() {
+                continue;
+            }
+            for j in 0..stars.len() {
+                let to_j = match transforms[j] {
+                    Some(t) => t,
+                    None => continue,
+                };
+                let reference = star_aligner::Reference::from_stars(stars[j].clone(), options());
+                if let Some(t) = reference.align_stars(&stars[i]) {
+                    println!("{}: aligned on {}", args.arg_input[i], args.arg_input[j]);
+                    transforms[i] = Some(t * to_j);
+                    progress = true;
+                    break;
+                }
+            }
+        }
+        if !progress {
+            break;
+        }
+    }
+    let transforms: Vec<Matrix3x3<f64>> = transforms.iter().zip(args.arg_input.iter())
+        .map(|(t, filename)| t.unwrap_or_else(|| panic!("{} doesn't overlap any other panel", filename)))
+        .collect();
+
+    let panels: Vec<Image<Rgb<f64>>> = args.arg_input.iter().map(|filename| open_fits_rgb(filename)).collect();
+    let assembled = mosaic::assemble(&panels, &transforms, args.flag_interpolation, args.flag_feather);
+    let img = assembled.image;
+    println!("mosaic: {}x{}", img.width, img.height);
+    for (i, levels) in assembled.levels.iter().enumerate() {
+        if let Some(levels) = *levels {
+            println!("panel {}: levels {:?}", i + 1, levels);
+        }
+    }
+
+    let shape = [3, img.width, img.height];
+    let mut f = BufWriter::new(File::create(&args.flag_output).unwrap());
+    fits::write_image(&mut f, &shape[..], &fits::Data::F64(convert_vec(img.pixels)));
+}
+
+fn open_fits_rgb(filename: &str) -> Image<Rgb<f64>> {
+    let (width, height, data) = fits::read_rgb(&mut BufReader::new(File::open(filename).unwrap()));
+    Image { width, height, pixels: convert_vec(data) }
+}
@@ -2,42 +2,486 @@ extern crate structopt;
 #[macro_use] extern crate structopt_derive;
 extern crate donuts;
 extern crate image;
+extern crate fits;
+extern crate convert;
+extern crate imagemagick;
+extern crate astrometry;
+extern crate serde_json;
 
+mod recipe;
+
+use std::env;
+use std::f64;
+use std::fs::File;
+use std::iter;
+use std::io::{BufReader, BufWriter};
 use structopt::StructOpt;
-use image::{Image, Rgb, ImageKind};
+use image::{Image, OwnedImage, ImageDimensions, Rgb};
+use image::stretch::{self, Stf, Curve};
+use image::background::{self, Surface, Correction};
+use image::color::{self, Region, ColorMatrix};
+use image::{stars, photometry};
+use image::chroma::{self, Cast, Protection};
+use image::deconvolution::{self, Psf};
+use image::wavelets::{self, Decomposition, Area, PerLayer};
+use image::{starless, transform};
+use astrometry::wcs::Wcs;
+use convert::convert_vec;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "post", about = "")]
 struct Args {
-    #[structopt(long = "output")]
+    #[structopt(long = "output", help = "Output image. FITS if it ends in .fits, otherwise anything ImageMagick writes, with 0..1 mapped to its full range")]
     flag_output: String,
-    #[structopt(long = "input")]
+    #[structopt(long = "input", help = "Mono or RGB FITS file, as written by stack. Rescaled to 0..1 for every command, unless all of it already is, like the output of another command")]
     arg_input: String,
+    #[structopt(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(StructOpt, Debug)]
+enum Cmd {
+    #[structopt(name = "gamma", about = "Plain gamma curve")]
+    Gamma {
+        #[structopt(long = "gamma", default_value = "2.2")]
+        gamma: f64,
+    },
+    #[structopt(name = "arcsinh", about = "Arcsinh stretch that keeps the colors")]
+    Arcsinh {
+        #[structopt(long = "stretch", help = "How much the faint end is lifted", default_value = "100")]
+        stretch: f64,
+        #[structopt(long = "black-point", help = "Subtracted before stretching, 0..1", default_value = "0")]
+        black_point: f64,
+    },
+    #[structopt(name = "mtf", about = "Midtones transfer function with shadows and highlights clipping")]
+    Mtf {
+        #[structopt(long = "shadows", default_value = "0")]
+        shadows: f64,
+        #[structopt(long = "midtones", help = "Value that becomes 0.5", default_value = "0.5")]
+        midtones: f64,
+        #[structopt(long = "highlights", default_value = "1")]
+        highlights: f64,
+    },
+    #[structopt(name = "auto-stf", about = "Midtones transfer function estimated from the median and MAD of each channel")]
+    AutoStf {
+        #[structopt(long = "target-background", help = "Where the background ends up, 0..1", default_value = "0.25")]
+        target_background: f64,
+        #[structopt(long = "shadows-clipping", help = "Shadows are clipped this many MADs from the median", default_value = "-2.8")]
+        shadows_clipping: f64,
+        #[structopt(long = "unlinked", help = "Stretch each channel on its own, which also neutralizes the background")]
+        unlinked: bool,
+    },
+    #[structopt(name = "background", about = "Fits a smooth background to samples between the stars and nebulosity, and removes it. Run it before stretching")]
+    Background {
+        #[structopt(long = "grid", help = "Samples per side", default_value = "16")]
+        grid: usize,
+        #[structopt(long = "tolerance", help = "Samples more than this many standard deviations above the fit are rejected", default_value = "2")]
+        tolerance: f64,
+        #[structopt(long = "degree", help = "Degree of the polynomial", default_value = "2")]
+        degree: usize,
+        #[structopt(long = "spline", help = "Fit a thin plate spline instead of a polynomial")]
+        spline: bool,
+        #[structopt(long = "smoothing", help = "Spline smoothing", default_value = "0.1")]
+        smoothing: f64,
+        #[structopt(long = "correction", help = "subtract, for light pollution, or divide, for vignetting", default_value = "subtract")]
+        correction: Correction,
+        #[structopt(long = "model", help = "Where to write the fitted background")]
+        model: Option<String>,
+    },
+    #[structopt(name = "color", about = "Neutralizes the background and white balances on the stars or a region. Run it before stretching")]
+    Color {
+        #[structopt(long = "background-region", help = "Sky region X,Y,W,H to neutralize on. Found from the darkest background samples if not given")]
+        background_region: Option<Region>,
+        #[structopt(long = "white-region", help = "Region X,Y,W,H that should come out white, instead of the average star")]
+        white_region: Option<Region>,
+        #[structopt(long = "sigma", help = "Stars must peak this many times the noise above the background", default_value = "20")]
+        sigma: f64,
+        #[structopt(long = "saturation", help = "Stars with a pixel at or above this, 0..1, are left out", default_value = "0.9")]
+        saturation: f64,
+    },
+    #[structopt(name = "pcc", about = "Photometric color calibration: white balances so the stars get their catalog colors. Run it before stretching")]
+    Pcc {
+        #[structopt(long = "catalog", help = "Star catalog: little endian f32 RA, Dec, V and B-V per star")]
+        catalog: String,
+        #[structopt(long = "wcs", help = "WCS of the input, as written by solve-field --wcs. Otherwise the input is solved with solve-field")]
+        wcs: Option<String>,
+        #[structopt(long = "field-width", help = "Approximate width of the field in degrees, for solving")]
+        field_width: Option<f64>,
+        #[structopt(long = "background-region", help = "Sky region X,Y,W,H to neutralize on. Found from the darkest background samples if not given")]
+        background_region: Option<Region>,
+        #[structopt(long = "sigma", help = "Stars must peak this many times the noise above the background", default_value = "20")]
+        sigma: f64,
+        #[structopt(long = "saturation", help = "Stars with a pixel at or above this, 0..1, are left out", default_value = "0.9")]
+        saturation: f64,
+        #[structopt(long = "match-radius", help = "Pixels between a star and its catalog position", default_value = "3")]
+        match_radius: f64,
+    },
+    #[structopt(name = "scnr", about = "Removes a green or magenta cast")]
+    Scnr {
+        #[structopt(long = "cast", help = "green or magenta", default_value = "green")]
+        cast: Cast,
+        #[structopt(long = "protection", help = "Green is limited to the average or the maximum of red and blue", default_value = "average")]
+        protection: Protection,
+        #[structopt(long = "amount", help = "0..1", default_value = "1")]
+        amount: f64,
+    },
+    #[structopt(name = "saturation", about = "Scales the chroma in LCh, keeping lightness and hue")]
+    Saturation {
+        #[structopt(long = "amount", help = "0 is gray, 1 leaves the colors as they are", default_value = "1.5")]
+        amount: f64,
+    },
+    #[structopt(name = "deconvolve", about = "Richardson-Lucy deconvolution of the luminance, with a PSF measured from the stars. Run it before stretching")]
+    Deconvolve {
+        #[structopt(long = "iterations", default_value = "20")]
+        iterations: usize,
+        #[structopt(long = "regularization", help = "Weight of the total variation regularization, against noise. 0 turns it off", default_value = "0.002")]
+        regularization: f64,
+        #[structopt(long = "psf-size", help = "Width of the PSF in pixels, odd", default_value = "15")]
+        psf_size: usize,
+        #[structopt(long = "psf-stars", help = "How many of the brightest stars the PSF is measured on", default_value = "20")]
+        psf_stars: usize,
+        #[structopt(long = "sigma", help = "Stars must peak this many times the noise above the background", default_value = "20")]
+        sigma: f64,
+        #[structopt(long = "saturation", help = "Stars with a pixel at or above this, 0..1, are left out", default_value = "0.9")]
+        saturation: f64,
+        #[structopt(long = "protect", help = "Luminance, 0..1, of star cores that are left as they are, with their surroundings, against ringing")]
+        protect: Option<f64>,
+        #[structopt(long = "protect-radius", help = "Pixels around protected star cores", default_value = "8")]
+        protect_radius: f64,
+        #[structopt(long = "psf", help = "Where to write the measured PSF")]
+        psf: Option<String>,
+    },
+    #[structopt(name = "wavelets", about = "Noise reduction and sharpening on the layers of an a trous wavelet decomposition")]
+    Wavelets {
+        #[structopt(long = "layers", help = "Layers at scales of 1, 2, 4... pixels", default_value = "4")]
+        layers: usize,
+        #[structopt(long = "denoise", help = "Per layer thresholds, in standard deviations of the noise, like 3,2,1")]
+        denoise: Option<PerLayer>,
+        #[structopt(long = "denoise-area", help = "all, signal or background", default_value = "background")]
+        denoise_area: Area,
+        #[structopt(long = "sharpen", help = "Per layer gains, like 1.5,1.2")]
+        sharpen: Option<PerLayer>,
+        #[structopt(long = "sharpen-area", help = "all, signal or background", default_value = "signal")]
+        sharpen_area: Area,
+        #[structopt(long = "mask-sigma", help = "Signal is this many standard deviations of the noise above the background", default_value = "3")]
+        mask_sigma: f64,
+    },
+    #[structopt(name = "star-reduction", about = "Shrinks the stars by erosion within a star mask")]
+    StarReduction {
+        #[structopt(long = "sigma", help = "Stars must peak this many times the noise above the background", default_value = "10")]
+        sigma: f64,
+        #[structopt(long = "mask-size", help = "Radius of the star mask in FWHMs", default_value = "1.5")]
+        mask_size: f64,
+        #[structopt(long = "iterations", help = "Erosion passes", default_value = "1")]
+        iterations: usize,
+        #[structopt(long = "mask", help = "Where to write the star mask")]
+        mask: Option<String>,
+    },
+    #[structopt(name = "starless", about = "Replaces the stars with inpainted background, so the nebulosity can be stretched on its own. Run it before stretching")]
+    Starless {
+        #[structopt(long = "sigma", help = "Stars must peak this many times the noise above the background", default_value = "10")]
+        sigma: f64,
+        #[structopt(long = "mask-size", help = "Radius of the star mask in FWHMs", default_value = "2")]
+        mask_size: f64,
+        #[structopt(long = "stars", help = "Where to write the stars alone, to stretch and recombine")]
+        stars: Option<String>,
+        #[structopt(long = "mask", help = "Where to write the star mask")]
+        mask: Option<String>,
+    },
+    #[structopt(name = "recombine", about = "Screens stretched stars back onto the stretched starless input")]
+    Recombine {
+        #[structopt(long = "stars", help = "Stars alone, as written by starless --stars and stretched")]
+        stars: String,
+    },
+    #[structopt(name = "curves", about = "Tone curve through control points, applied to every channel")]
+    Curves {
+        #[structopt(long = "points", help = "X:Y,X:Y,... with both in 0..1")]
+        points: Curve,
+    },
+    #[structopt(name = "crop", about = "Keeps a region of the image")]
+    Crop {
+        #[structopt(long = "region", help = "X,Y,W,H")]
+        region: Region,
+    },
+    #[structopt(name = "resize", about = "Resizes the image, keeping its aspect ratio if only one side is given")]
+    Resize {
+        #[structopt(long = "width")]
+        width: Option<usize>,
+        #[structopt(long = "height")]
+        height: Option<usize>,
+    },
+    #[structopt(name = "recipe", about = "Runs the steps of a JSON recipe, see recipe.rs, and records them in the output")]
+    Recipe {
+        #[structopt(long = "file")]
+        file: String,
+    },
+}
+
+/// A recipe step, parsed like a command line.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "step")]
+struct Step {
+    #[structopt(subcommand)]
+    cmd: Cmd,
 }
 
 fn main() {
     let args = Args::from_args();
-    let img = ImageKind::open_fits(&args.arg_input);
-    let img = if let ImageKind::RgbF64(v) = img {
-        v
-    } else {
-        panic!("wrong image type: {:?}", img);
+    let img = open_input(&args.arg_input);
+
+    let (img, format, history) = match args.cmd {
+        Cmd::Recipe { file } => {
+            let recipe = recipe::open(&file);
+            let steps: Vec<(String, Cmd)> = recipe.steps.iter().enumerate().map(|(i, step)| {
+                let Step { cmd } = Step::from_iter(iter::once("step".to_string()).chain(step.iter().cloned()));
+                (format!("step {}: {}", i + 1, step.join(" ")), cmd)
+            }).collect();
+            // pcc solves the input file, which doesn't match the image any more once it's cropped or resized.
+            let mut geometry = None;
+            for &(ref line, ref cmd) in steps.iter() {
+                match *cmd {
+                    Cmd::Crop { .. } | Cmd::Resize { .. } => geometry = Some(line),
+                    Cmd::Pcc { .. } => if let Some(geometry) = geometry {
+                        panic!("{}: pcc has to come before {}", line, geometry);
+                    },
+                    _ => {}
+                }
+            }
+            let mut history = vec![format!("post recipe {}", file)];
+            let mut img = img;
+            for (line, cmd) in steps.into_iter() {
+                println!("{}", line);
+                history.push(line);
+                img = run(img, cmd, &args.arg_input);
+            }
+            (img, recipe.format, history)
+        }
+        cmd => {
+            let line = format!("post {}", env::args().skip(1).collect::<Vec<_>>().join(" "));
+            (run(img, cmd, &args.arg_input), None, vec![line])
+        }
     };
 
-    img
-        //.remove_background(0.97)
-        //.map(|&p| {
-            ////(p * 9.0).truncate(0.0, 1.0)
-            //p * Rgb {
-                //r: 0.5,
-                //g: 1.0,
-                //b: 1.0,
-            //}
-        //})
-        .gamma(1.0 / 2.2)
-        .stretch(0.0, 1.0)
-        .to_f32()
-        .save(&args.flag_output);
-        //.to_u8()
-        //.save_jpeg_file(&args.flag_output);
+    save_with_history(&img, &args.flag_output, format.as_ref().map(|f| &f[..]), &history);
+}
+
+/// Runs one command. `input` is the file the image came from, for solving.
+fn run(img: OwnedImage<Rgb<f64>>, cmd: Cmd, input: &str) -> OwnedImage<Rgb<f64>> {
+    match cmd {
+        Cmd::Gamma { gamma } => {
+            img.clone_map(|p| Rgb { r: p.r.powf(1.0 / gamma), g: p.g.powf(1.0 / gamma), b: p.b.powf(1.0 / gamma) })
+        }
+        Cmd::Arcsinh { stretch, black_point } => {
+            stretch::arcsinh(&img, stretch, black_point)
+        }
+        Cmd::Mtf { shadows, midtones, highlights } => {
+            let stf = Stf { shadows, midtones, highlights };
+            stretch::apply_stf(&img, &Rgb { r: stf, g: stf, b: stf })
+        }
+        Cmd::AutoStf { target_background, shadows_clipping, unlinked } => {
+            let stf = stretch::auto_stf(&img, target_background, shadows_clipping, !unlinked);
+            println!("stf: {:?}", stf);
+            stretch::apply_stf(&img, &stf)
+        }
+        Cmd::Background { grid, tolerance, degree, spline, smoothing, correction, model } => {
+            let surface = if spline { Surface::Spline(smoothing) } else { Surface::Polynomial(degree) };
+            let extraction = background::extract(&img, surface, grid, tolerance).unwrap_or_else(|e| panic!("{}", e));
+            for (i, pass) in extraction.passes.iter().enumerate() {
+                if pass.stopped {
+                    println!("background pass {}: {} samples, keeping the previous fit as only {} would be left",
+                             i + 1, pass.samples, pass.samples - pass.rejected);
+                } else {
+                    println!("background pass {}: {} samples, {} rejected", i + 1, pass.samples, pass.rejected);
+                }
+            }
+            println!("background: {} of {} samples used", extraction.samples.len(), grid * grid);
+            if let Some(ref filename) = model {
+                save(&extraction.background, filename);
+            }
+            background::correct(&img, &extraction.background, correction)
+        }
+        Cmd::Color { background_region, white_region, sigma, saturation } => {
+            let background = match background_region {
+                Some(region) => color::region_median(&img, region),
+                None => color::auto_background(&img, 16),
+            };
+            let white = match white_region {
+                Some(region) => color::region_median(&img, region) - background,
+                None => {
+                    let stars = stars::find(&img, sigma, saturation);
+                    println!("{} stars", stars.len());
+                    color::star_white(&stars)
+                }
+            };
+            let matrix = ColorMatrix::white_balance(white);
+            println!("background: {:?}, white: {:?}, matrix: {:?}", background, white, matrix);
+            color::calibrate(&img, background, &matrix)
+        }
+        Cmd::Pcc { catalog, wcs, field_width, background_region, sigma, saturation, match_radius } => {
+            let wcs = match wcs {
+                Some(filename) => Wcs::open(filename),
+                None => {
+                    let width = field_width.expect("--field-width is needed to solve the input, or pass --wcs");
+                    astrometry::solve_wcs(input, (width * 0.9, width * 1.1)).unwrap()
+                }
+            };
+            let background = match background_region {
+                Some(region) => color::region_median(&img, region),
+                None => color::auto_background(&img, 16),
+            };
+            let dim = img.dimensions();
+            let (width, height) = (dim.width as f64, dim.height as f64);
+            let catalog: Vec<_> = photometry::read_catalog(&catalog).into_iter()
+                .filter_map(|star| wcs.to_pixel(star.ra, star.dec).map(|(x, y)| (x, y, star)))
+                .filter(|&(x, y, _)| x >= 0.0 && y >= 0.0 && x < width && y < height)
+                .collect();
+            let stars = stars::find(&img, sigma, saturation);
+            let matches = photometry::cross_match(&stars, &catalog, match_radius);
+            println!("{} stars, {} catalog stars in the field, {} matched", stars.len(), catalog.len(), matches.len());
+            let calibration = photometry::calibrate(&matches);
+            println!("{:>10} {:>10} {:>8} {:>8} {:>6} {:>6} {:>8} {:>8}", "ra", "dec", "x", "y", "V", "B-V", "r-g", "b-g");
+            for (m, residual) in matches.iter().zip(calibration.residuals.iter()) {
+                println!("{:>10.5} {:>10.5} {:>8.1} {:>8.1} {:>6.2} {:>6.2} {:>8.3} {:>8.3}",
+                         m.catalog.ra, m.catalog.dec, m.star.x, m.star.y, m.catalog.magnitude, m.catalog.color, residual.r, residual.b);
+            }
+            println!("rms residual: r-g {:.3} mag, b-g {:.3} mag", calibration.rms.r, calibration.rms.b);
+            let matrix = ColorMatrix::white_balance(calibration.white);
+            println!("background: {:?}, white: {:?}, matrix: {:?}", background, calibration.white, matrix);
+            color::calibrate(&img, background, &matrix)
+        }
+        Cmd::Scnr { cast, protection, amount } => {
+            chroma::scnr(&img, cast, protection, amount)
+        }
+        Cmd::Saturation { amount } => {
+            chroma::saturation(&img, amount)
+        }
+        Cmd::Deconvolve { iterations, regularization, psf_size, psf_stars, sigma, saturation, protect, protect_radius, psf: psf_filename } => {
+            let stars = stars::find(&img, sigma, saturation);
+            let psf = Psf::from_stars(&img, &stars, psf_size, psf_stars);
+            println!("{} stars, PSF sigma: {:.2} pixels", stars.len(), psf.sigma());
+            if let Some(ref filename) = psf_filename {
+                let peak = psf.values.iter().cloned().fold(0.0, f64::max);
+                let psf_img = OwnedImage {
+                    dimensions: ImageDimensions { width: psf.size, height: psf.size, pitch: psf.size },
+                    pixels: psf.values.iter().map(|&v| Rgb { r: v / peak, g: v / peak, b: v / peak }).collect(),
+                };
+                save(&psf_img, filename);
+            }
+            let mask = protect.map(|threshold| deconvolution::protection_mask(&img, threshold, protect_radius));
+            deconvolution::deconvolve(&img, &psf, iterations, regularization, mask.as_ref().map(|m| &m[..]))
+        }
+        Cmd::Wavelets { layers, denoise, denoise_area, sharpen, sharpen_area, mask_sigma } => {
+            let dim = img.dimensions();
+            let luminance: Vec<f64> = (0..dim.height).flat_map(|y| img.row(y).iter().map(|p| (p.r + p.g + p.b) / 3.0)).collect();
+            let denoise_mask = denoise_area.mask(&luminance, dim.width, dim.height, mask_sigma);
+            let sharpen_mask = sharpen_area.mask(&luminance, dim.width, dim.height, mask_sigma);
+            wavelets::map_channels(&img, |data| {
+                let mut d = Decomposition::new(&data, dim.width, dim.height, layers);
+                if let Some(PerLayer(ref thresholds)) = denoise {
+                    d.denoise(thresholds, denoise_mask.as_ref().map(|m| &m[..]));
+                }
+                if let Some(PerLayer(ref gains)) = sharpen {
+                    d.enhance(gains, sharpen_mask.as_ref().map(|m| &m[..]));
+                }
+                d.reconstruct()
+            })
+        }
+        Cmd::StarReduction { sigma, mask_size, iterations, mask: mask_filename } => {
+            let found = stars::find(&img, sigma, f64::INFINITY);
+            println!("{} stars", found.len());
+            let mask = starless::star_mask(img.dimensions(), &found, mask_size);
+            if let Some(ref filename) = mask_filename {
+                save(&mask_image(&img, &mask), filename);
+            }
+            starless::reduce(&img, &mask, iterations)
+        }
+        Cmd::Starless { sigma, mask_size, stars: stars_filename, mask: mask_filename } => {
+            let found = stars::find(&img, sigma, f64::INFINITY);
+            println!("{} stars", found.len());
+            let mask = starless::star_mask(img.dimensions(), &found, mask_size);
+            if let Some(ref filename) = mask_filename {
+                save(&mask_image(&img, &mask), filename);
+            }
+            let res = starless::starless(&img, &mask);
+            if let Some(ref filename) = stars_filename {
+                save(&starless::stars_only(&img, &res), filename);
+            }
+            res
+        }
+        Cmd::Recombine { stars } => {
+            starless::recombine(&img, &open_input(&stars))
+        }
+        Cmd::Curves { points } => {
+            stretch::curves(&img, &points)
+        }
+        Cmd::Crop { region } => {
+            transform::crop(&img, region)
+        }
+        Cmd::Resize { width, height } => {
+            let dim = img.dimensions();
+            let aspect = dim.width as f64 / dim.height as f64;
+            let (width, height) = match (width, height) {
+                (Some(w), Some(h)) => (w, h),
+                (Some(w), None) => (w, ((w as f64 / aspect).round() as usize).max(1)),
+                (None, Some(h)) => (((h as f64 * aspect).round() as usize).max(1), h),
+                (None, None) => panic!("resize needs --width or --height"),
+            };
+            transform::resize(&img, width, height)
+        }
+        Cmd::Recipe { .. } => panic!("recipes can't run other recipes"),
+    }
+}
+
+/// Opens an RGB FITS file, normalized to 0..1 unless it already is, like the output of
+/// another command. A linear stack that happens to be within 0..1 is used as it is too,
+/// rather than stretched to the full range as it used to be.
+fn open_input(filename: &str) -> OwnedImage<Rgb<f64>> {
+    let img = open_fits_rgb(filename);
+    let in_range = |v: f64| v >= 0.0 && v <= 1.0;
+    if img.pixels.iter().all(|p| in_range(p.r) && in_range(p.g) && in_range(p.b)) {
+        img
+    } else {
+        stretch::normalize(&img)
+    }
+}
+
+fn open_fits_rgb(filename: &str) -> OwnedImage<Rgb<f64>> {
+    let (width, height, data) = fits::read_rgb(&mut BufReader::new(File::open(filename).unwrap()));
+    OwnedImage {
+        dimensions: ImageDimensions { width, height, pitch: width },
+        pixels: convert_vec(data),
+    }
+}
+
+fn mask_image(img: &OwnedImage<Rgb<f64>>, mask: &[f64]) -> OwnedImage<Rgb<f64>> {
+    let dim = img.dimensions();
+    OwnedImage {
+        dimensions: ImageDimensions { width: dim.width, height: dim.height, pitch: dim.width },
+        pixels: mask.iter().map(|&m| Rgb { r: m, g: m, b: m }).collect(),
+    }
+}
+
+fn save(img: &OwnedImage<Rgb<f64>>, filename: &str) {
+    save_with_history(img, filename, None, &[]);
+}
+
+/// Writes `format`, or what the extension of `filename` says, recording `history` as FITS
+/// HISTORY, or as the comment of other formats, which TIFF keeps as its image description.
+fn save_with_history(img: &OwnedImage<Rgb<f64>>, filename: &str, format: Option<&str>, history: &[String]) {
+    let dim = img.dimensions();
+    if format.map_or(filename.ends_with(".fits"), |f| f == "fits") {
+        let mut f = BufWriter::new(File::create(filename).unwrap());
+        let shape = [3, dim.width, dim.height];
+        let records: Vec<_> = history.iter().flat_map(|h| fits::history(h)).collect();
+        fits::write_image_with_header(&mut f, &shape[..], &fits::Data::F64(convert_vec(img.pixels.clone())), &records);
+    } else {
+        let data: Vec<f32> = img.pixels.iter().flat_map(|p| vec![p.r as f32, p.g as f32, p.b as f32]).collect();
+        let path = match format {
+            Some(f) => format!("{}:{}", f, filename),
+            None => filename.to_string(),
+        };
+        let comment = history.join("\n");
+        let comment = if history.is_empty() { None } else { Some(&comment[..]) };
+        imagemagick::convert_save_unit(&data, dim.width, dim.height, "rgb", "truecolor", comment, path);
+    }
 }
@@ -0,0 +1,97 @@
+//! Recipes: JSON files that list the commands post runs one after the other, like
+//!
+//! ```json
+//! {
+//!     "format": "tiff",
+//!     "steps": [
+//!         { "op": "background", "degree": 3 },
+//!         { "op": "color" },
+//!         { "op": "auto-stf", "target-background": 0.2 },
+//!         { "op": "curves", "points": [[0, 0], [0.3, 0.4], [1, 1]] },
+//!         { "op": "scnr", "amount": 0.8 },
+//!         { "op": "resize", "width": 1920 }
+//!     ]
+//! }
+//! ```
+//!
+//! `op` is the command and every other key one of its options, with the same names and defaults
+//! as on the command line. `true` sets a flag, and lists are joined with commas, or with colons
+//! for lists in lists. `format` is what the output is written as, instead of guessing it from
+//! the file name.
+//!
+//! Like for single commands, the input must be a mono or RGB FITS file. `pcc` solves the input
+//! file, so it can't come after a `crop` or `resize`.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use serde_json::{self, Value};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipe {
+    pub format: Option<String>,
+    /// The command line of each step, starting with the command.
+    pub steps: Vec<Vec<String>>,
+}
+
+pub fn open<P: AsRef<Path>>(path: P) -> Recipe {
+    let mut s = String::new();
+    File::open(path).unwrap().read_to_string(&mut s).unwrap();
+    parse(&s)
+}
+
+pub fn parse(json: &str) -> Recipe {
+    let value: Value = serde_json::from_str(json).unwrap();
+    let format = value.get("format").map(|f| f.as_str().expect("format must be a string").to_string());
+    let steps = value.get("steps").and_then(|s| s.as_array()).expect("a recipe needs a list of steps");
+    Recipe { format, steps: steps.iter().map(step_args).collect() }
+}
+
+fn step_args(step: &Value) -> Vec<String> {
+    let options = step.as_object().expect("steps must be objects");
+    let op = options.get("op").and_then(|op| op.as_str()).expect("steps need an op");
+    let mut args = vec![op.to_string()];
+    for (name, value) in options.iter().filter(|&(name, _)| name != "op") {
+        match *value {
+            Value::Null | Value::Bool(false) => {}
+            Value::Bool(true) => args.push(format!("--{}", name)),
+            // One argument, so values starting with a minus aren't taken for flags.
+            _ => args.push(format!("--{}={}", name, arg(value, ","))),
+        }
+    }
+    args
+}
+
+fn arg(value: &Value, separator: &str) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        Value::Array(ref values) => values.iter().map(|v| arg(v, ":")).collect::<Vec<_>>().join(separator),
+        ref v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let recipe = parse(r#"{
+            "format": "tiff",
+            "steps": [
+                { "op": "background", "degree": 3, "spline": false, "model": null },
+                { "op": "auto-stf", "unlinked": true, "shadows-clipping": -2 },
+                { "op": "curves", "points": [[0, 0], [0.3, 0.4], [1, 1]] },
+                { "op": "crop", "region": "10,20,300,200" }
+            ]
+        }"#);
+        assert_eq!(recipe.format, Some("tiff".to_string()));
+        let steps: Vec<String> = recipe.steps.iter().map(|s| s.join(" ")).collect();
+        assert_eq!(steps, vec![
+            "background --degree=3",
+            "auto-stf --shadows-clipping=-2 --unlinked",
+            "curves --points=0:0,0.3:0.4,1:1",
+            "crop --region=10,20,300,200",
+        ]);
+    }
+}
@@ -10,6 +10,11 @@ use std::io::BufReader;
 use regex::Regex;
 use tempdir::TempDir;
 
+/// The SExtractor configuration used by `extract`.
+pub const DEFAULT_SEX: &'static [u8] = include_bytes!("config/default.sex");
+/// The output columns requested from SExtractor.
+pub const DEFAULT_PARAM: &'static [u8] = include_bytes!("config/default.param");
+
 #[derive(Debug)]
 pub struct Object {
     pub flux: f32,
@@ -22,11 +27,11 @@ pub fn extract<P: AsRef<Path>>(path: P) -> Vec<Object> {
 
     {
         let mut f = File::create(temp_dir.path().join("default.sex")).unwrap();
-        f.write_all(include_bytes!("config/default.sex")).unwrap();
+        f.write_all(DEFAULT_SEX).unwrap();
     }
     {
         let mut f = File::create(temp_dir.path().join("default.param")).unwrap();
-        f.write_all(include_bytes!("config/default.param")).unwrap();
+        f.write_all(DEFAULT_PARAM).unwrap();
     }
 
     let mut status = Command::new("sex")
@@ -0,0 +1,113 @@
+//! Saves the running stack and the frames in it, so a long run can be resumed after a crash,
+//! or extended later with frames added to the alignment file.
+//!
+//! The file is a few lines of text, the list of frames, then the pixels as little endian
+//! `f64`s in `RgbBayer` field order.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use image::{Image, RgbBayer};
+
+const MAGIC: &'static str = "stack checkpoint 1";
+
+/// Where and how often the running stack is saved.
+pub struct Checkpointer {
+    pub filename: String,
+    /// Frames between saves, or 0 to only save at the end.
+    pub every: usize,
+    /// Everything that changes what the stack adds up to. A checkpoint made with other settings isn't resumed.
+    pub settings: String,
+}
+
+pub struct Checkpoint {
+    pub files: Vec<String>,
+    pub stack: Image<RgbBayer<f64>>,
+}
+
+impl Checkpointer {
+    /// Loads the checkpoint, if there is one yet.
+    pub fn load(&self) -> Option<Checkpoint> {
+        if !Path::new(&self.filename).exists() {
+            return None;
+        }
+        let (settings, checkpoint) = read(&mut BufReader::new(File::open(&self.filename).unwrap()));
+        if settings != self.settings {
+            panic!("{} was made with other settings:\n  {}\nnot\n  {}", self.filename, settings, self.settings);
+        }
+        Some(checkpoint)
+    }
+
+    /// Saves `stack`, replacing the previous checkpoint only once it's completely written.
+    pub fn save(&self, files: &[String], stack: &Image<RgbBayer<f64>>) {
+        let tmp = format!("{}.tmp", self.filename);
+        {
+            let mut w = BufWriter::new(File::create(&tmp).unwrap());
+            write(&mut w, &self.settings, files, stack);
+            w.flush().unwrap();
+        }
+        fs::rename(&tmp, &self.filename).unwrap();
+        println!("checkpoint: {} frames saved to {}", files.len(), self.filename);
+    }
+}
+
+fn write<W: Write>(w: &mut W, settings: &str, files: &[String], stack: &Image<RgbBayer<f64>>) {
+    writeln!(w, "{}", MAGIC).unwrap();
+    writeln!(w, "{}", settings).unwrap();
+    writeln!(w, "{} {} {}", stack.width, stack.height, files.len()).unwrap();
+    for file in files {
+        writeln!(w, "{}", file).unwrap();
+    }
+    for p in stack.pixels.iter() {
+        for &v in [p.r, p.g, p.b, p.rc, p.gc, p.bc].iter() {
+            w.write_f64::<LittleEndian>(v).unwrap();
+        }
+    }
+}
+
+fn read<R: BufRead>(r: &mut R) -> (String, Checkpoint) {
+    let mut line = || {
+        let mut line = String::new();
+        r.read_line(&mut line).unwrap();
+        line.trim_right_matches('\n').to_string()
+    };
+    assert_eq!(line(), MAGIC, "not a stack checkpoint");
+    let settings = line();
+    let sizes: Vec<usize> = line().split(' ').map(|v| v.parse().unwrap()).collect();
+    let (width, height, count) = (sizes[0], sizes[1], sizes[2]);
+    let files = (0..count).map(|_| line()).collect();
+    let mut pixels = Vec::with_capacity(width * height);
+    for _ in 0..width * height {
+        let mut v = [0.0; 6];
+        for v in v.iter_mut() {
+            *v = r.read_f64::<LittleEndian>().unwrap();
+        }
+        pixels.push(RgbBayer { r: v[0], g: v[1], b: v[2], rc: v[3], gc: v[4], bc: v[5] });
+    }
+    let mut rest = Vec::new();
+    r.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty(), "checkpoint is longer than expected");
+    (settings, Checkpoint { files, stack: Image { width, height, pixels } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut stack = Image::new(3, 2);
+        for (i, p) in stack.pixels.iter_mut().enumerate() {
+            *p = RgbBayer { r: i as f64, g: 0.5, b: -1.0, rc: 1.0, gc: 2.0, bc: 0.25 };
+        }
+        let files = vec!["a.CR2".to_string(), "b c.CR2".to_string()];
+        let mut data = Vec::new();
+        write(&mut data, "Average", &files, &stack);
+        let (settings, checkpoint) = read(&mut &data[..]);
+        assert_eq!(settings, "Average");
+        assert_eq!(checkpoint.files, files);
+        assert_eq!((checkpoint.stack.width, checkpoint.stack.height), (3, 2));
+        assert!(checkpoint.stack.pixels == stack.pixels);
+    }
+}
@@ -0,0 +1,269 @@
+//! Stacking on a comet, or anything else that moves against the stars.
+//!
+//! The comet's track is a straight line on the reference, at constant speed, through its
+//! position in two frames. Each frame's star transform is then shifted by how far the comet
+//! moved since the first frame, which holds the comet still.
+
+use std::f64;
+use std::fs;
+use std::str::FromStr;
+use std::process::Command;
+use std::time::UNIX_EPOCH;
+use image::Image;
+use image::stats::median;
+use donuts::remove_background::tile_medians;
+use geom::{Point, Vector, Matrix3x3};
+
+/// The comet's position in one frame, in that frame's pixels.
+#[derive(Clone, Debug)]
+pub struct Mark {
+    pub filename: String,
+    pub position: Point<f64>,
+}
+
+impl FromStr for Mark {
+    type Err = String;
+
+    /// Parses `FILE@X,Y`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("invalid mark: {} (expected FILE@X,Y)", s);
+        let mut parts = s.rsplitn(2, '@');
+        let (coords, filename) = match (parts.next(), parts.next()) {
+            (Some(coords), Some(filename)) => (coords, filename),
+            _ => return Err(error()),
+        };
+        let coords: Vec<f64> = coords.split(',').map(|c| c.trim().parse()).collect::<Result<_, _>>().map_err(|_| error())?;
+        if coords.len() != 2 {
+            return Err(error());
+        }
+        Ok(Mark {
+            filename: filename.to_string(),
+            position: Point { x: coords[0], y: coords[1] },
+        })
+    }
+}
+
+/// Where the comet is on the reference at any time.
+#[derive(Copy, Clone, Debug)]
+pub struct Track {
+    time: f64,
+    position: Point<f64>,
+    /// Reference pixels per second.
+    velocity: Vector<f64>,
+}
+
+impl Track {
+    /// From the comet's position in two frames taken at different times.
+    /// The positions are in the frames' pixels, the transforms map the reference onto them.
+    pub fn new(a: (f64, Matrix3x3<f64>, Point<f64>), b: (f64, Matrix3x3<f64>, Point<f64>)) -> Self {
+        let (ta, pa) = (a.0, a.1.inverse() * a.2);
+        let (tb, pb) = (b.0, b.1.inverse() * b.2);
+        assert!(tb != ta, "the comet must be marked in frames taken at different times");
+        Track {
+            time: ta,
+            position: pa,
+            velocity: (pb - pa) * (1.0 / (tb - ta)),
+        }
+    }
+
+    pub fn at(&self, time: f64) -> Point<f64> {
+        self.position + self.velocity * (time - self.time)
+    }
+
+    /// Star transform shifted so that the comet is where it was at `epoch`.
+    pub fn hold(&self, transform: Matrix3x3<f64>, time: f64, epoch: f64) -> Matrix3x3<f64> {
+        let moved = self.at(time) - self.at(epoch);
+        transform * Matrix3x3::translation(moved.x, moved.y)
+    }
+}
+
+/// When the frame was taken, in seconds: the EXIF time if `exiftool` can read it,
+/// otherwise the modification time of the file.
+pub fn timestamp(filename: &str) -> f64 {
+    let exif = Command::new("exiftool")
+        .args(&["-DateTimeOriginal", "-d", "%s", "-s3", filename])
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|s| s.trim().parse::<f64>().ok());
+    // Sub-second times are in their own tag, and usually missing.
+    let subsec = Command::new("exiftool")
+        .args(&["-SubSecTimeOriginal", "-s3", filename])
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|s| format!("0.{}", s.trim()).parse::<f64>().ok())
+        .unwrap_or(0.0);
+    match exif {
+        Some(seconds) => seconds + subsec,
+        None => {
+            println!("{}: no EXIF time, using the modification time", filename);
+            let modified = fs::metadata(filename).unwrap().modified().unwrap();
+            let d = modified.duration_since(UNIX_EPOCH).unwrap();
+            d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+        }
+    }
+}
+
+/// Half the width of the box the frame is blurred with when looking for the nucleus, in 2×2 superpixels.
+const NUCLEUS_RADIUS: usize = 8;
+
+/// The background is the median of each tile of this many × this many, interpolated in between.
+/// The tiles have to be much larger than the coma, or it's taken for background.
+const BACKGROUND_TILES: usize = 4;
+
+/// Half the width of the median filter that takes out the stars, in superpixels. Anything
+/// about as small as the filter is gone, the coma isn't.
+const STAR_RADIUS: usize = 2;
+
+/// Finds the comet's nucleus in a flat fielded CFA frame, as the brightest spot once the
+/// background is subtracted, the stars are filtered out and the frame is blurred.
+/// Returns it in the frame's pixels.
+pub fn detect_nucleus(img: &Image<f64>) -> Point<f64> {
+    let (w, h) = (img.width / 2, img.height / 2);
+    let r = NUCLEUS_RADIUS;
+    assert!(w > 2 * r && h > 2 * r, "frame too small to look for a comet");
+    let mut lum = Image::new(w, h);
+    for y in 0..h {
+        for x in 0..w {
+            *lum.pixel_at_mut(x, y) = (*img.pixel_at(2 * x, 2 * y) + *img.pixel_at(2 * x + 1, 2 * y)
+                + *img.pixel_at(2 * x, 2 * y + 1) + *img.pixel_at(2 * x + 1, 2 * y + 1)) as f32;
+        }
+    }
+    let background = local_background(&lum, BACKGROUND_TILES);
+    let starless = median_filter(&lum, STAR_RADIUS);
+
+    // A summed area table of what's above the background.
+    let mut table = vec![0.0; (w + 1) * (h + 1)];
+    for y in 0..h {
+        let mut row = 0.0;
+        for x in 0..w {
+            row += (*starless.pixel_at(x, y) - *background.pixel_at(x, y)) as f64;
+            table[(y + 1) * (w + 1) + x + 1] = table[y * (w + 1) + x + 1] + row;
+        }
+    }
+    let mut best = (f64::NEG_INFINITY, 0, 0);
+    for y in r..h - r {
+        for x in r..w - r {
+            let (x1, y1, x2, y2) = (x - r, y - r, x + r + 1, y + r + 1);
+            let sum = table[y2 * (w + 1) + x2] - table[y1 * (w + 1) + x2] - table[y2 * (w + 1) + x1] + table[y1 * (w + 1) + x1];
+            if sum > best.0 {
+                best = (sum, x, y);
+            }
+        }
+    }
+    Point { x: 2.0 * best.1 as f64 + 1.0, y: 2.0 * best.2 as f64 + 1.0 }
+}
+
+/// The tile medians of `img`, interpolated bilinearly between the tile centers.
+fn local_background(img: &Image<f32>, tiles: usize) -> Image<f32> {
+    let medians = tile_medians(img, tiles);
+    let (tile_w, tile_h) = (img.width as f64 / tiles as f64, img.height as f64 / tiles as f64);
+    let at = |t: f64| {
+        let t = t.max(0.0).min((tiles - 1) as f64);
+        let i = (t as usize).min(tiles.saturating_sub(2));
+        (i, (t - i as f64) as f32)
+    };
+    let mut out = Image::new(img.width, img.height);
+    for y in 0..img.height {
+        let (j, fy) = at((y as f64 + 0.5) / tile_h - 0.5);
+        for x in 0..img.width {
+            let (i, fx) = at((x as f64 + 0.5) / tile_w - 0.5);
+            let m = |i: usize, j: usize| *medians.pixel_at(i.min(tiles - 1), j.min(tiles - 1));
+            let top = m(i, j) * (1.0 - fx) + m(i + 1, j) * fx;
+            let bottom = m(i, j + 1) * (1.0 - fx) + m(i + 1, j + 1) * fx;
+            *out.pixel_at_mut(x, y) = top * (1.0 - fy) + bottom * fy;
+        }
+    }
+    out
+}
+
+/// Median of the `2 * radius + 1` square around each pixel, cut off at the edges.
+fn median_filter(img: &Image<f32>, radius: usize) -> Image<f32> {
+    let mut out = Image::new(img.width, img.height);
+    let mut window = Vec::with_capacity((2 * radius + 1) * (2 * radius + 1));
+    for y in 0..img.height {
+        for x in 0..img.width {
+            window.clear();
+            for wy in y.saturating_sub(radius)..(y + radius + 1).min(img.height) {
+                for wx in x.saturating_sub(radius)..(x + radius + 1).min(img.width) {
+                    window.push(*img.pixel_at(wx, wy));
+                }
+            }
+            *out.pixel_at_mut(x, y) = median(&mut window);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mark() {
+        let mark: Mark = "/data/IMG_0001.CR2@1204.5, 880".parse().unwrap();
+        assert_eq!(mark.filename, "/data/IMG_0001.CR2");
+        assert_eq!((mark.position.x, mark.position.y), (1204.5, 880.0));
+        assert!("IMG_0001.CR2".parse::<Mark>().is_err());
+        assert!("IMG_0001.CR2@12".parse::<Mark>().is_err());
+    }
+
+    #[test]
+    fn holds_the_comet_still() {
+        // Frame b is shifted by (5, 0) against the reference, and the comet moved by (10, -4) in 100 s.
+        let shifted = Matrix3x3::translation(5.0, 0.0);
+        let track = Track::new(
+            (1000.0, Matrix3x3::identity(), Point { x: 50.0, y: 40.0 }),
+            (1100.0, shifted, Point { x: 65.0, y: 36.0 }));
+        let p = track.at(1050.0);
+        assert!((p.x - 55.0).abs() < 1e-9 && (p.y - 38.0).abs() < 1e-9);
+        // The comet at time 1100 is found where it was at 1000 on the output.
+        let transform = track.hold(shifted, 1100.0, 1000.0);
+        let q = transform * Point { x: 50.0, y: 40.0 };
+        assert!((q.x - 65.0).abs() < 1e-9 && (q.y - 36.0).abs() < 1e-9);
+    }
+
+    /// A 200 × 160 frame with a coma at (121, 61) on `background`.
+    fn coma<F: Fn(f64, f64) -> f64>(background: F) -> Image<f64> {
+        let mut img = Image::new(200, 160);
+        for y in 0..160 {
+            for x in 0..200 {
+                let d2 = (x as f64 - 121.0).powi(2) + (y as f64 - 61.0).powi(2);
+                *img.pixel_at_mut(x, y) = background(x as f64, y as f64) + 50.0 * (-d2 / 400.0).exp();
+            }
+        }
+        img
+    }
+
+    fn assert_near_coma(p: Point<f64>) {
+        assert!((p.x - 121.0).abs() <= 2.0 && (p.y - 61.0).abs() <= 2.0, "{:?}", p);
+    }
+
+    #[test]
+    fn finds_the_coma() {
+        let mut img = coma(|_, _| 100.0);
+        // A star: bright, but small.
+        *img.pixel_at_mut(40, 40) = 5000.0;
+        assert_near_coma(detect_nucleus(&img));
+    }
+
+    #[test]
+    fn finds_the_coma_on_a_gradient() {
+        // The right edge is brighter than the coma's peak.
+        assert_near_coma(detect_nucleus(&coma(|x, _| 100.0 + x)));
+    }
+
+    #[test]
+    fn finds_the_coma_next_to_a_brighter_star() {
+        // More light in the star than in the coma, in a box the size of the blur.
+        let mut img = coma(|_, _| 100.0);
+        for y in 0..160 {
+            for x in 0..200 {
+                let d2 = (x as f64 - 50.0).powi(2) + (y as f64 - 110.0).powi(2);
+                *img.pixel_at_mut(x, y) += 10000.0 * (-d2 / 4.5).exp();
+            }
+        }
+        assert_near_coma(detect_nucleus(&img));
+    }
+}
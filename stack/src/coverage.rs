@@ -0,0 +1,168 @@
+//! How well each channel of a Bayer drizzled stack is covered, and what to do where it isn't.
+
+use std::f64;
+use std::str::FromStr;
+use image::{Image, Rgb, RgbBayer};
+use image::stats::sorted_median;
+
+/// What the output gets where a channel has no samples.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Uncovered {
+    Zero,
+    Nan,
+    /// The average of the covered neighbours, growing into the hole a pixel at a time.
+    /// Pixels further than `MAX_FILL` from any sample, and those without samples in any
+    /// channel, like the empty edges of a canvas, are left at 0.
+    Interpolate,
+}
+
+impl FromStr for Uncovered {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Uncovered::Zero),
+            "nan" => Ok(Uncovered::Nan),
+            "interpolate" => Ok(Uncovered::Interpolate),
+            _ => Err(format!("unknown uncovered: {} (expected zero, nan or interpolate)", s))
+        }
+    }
+}
+
+const MAX_FILL: usize = 8;
+
+/// Coverage of one channel, over the pixels where any channel has samples,
+/// so the empty edges of a canvas don't count.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelCoverage {
+    pub min: f64,
+    pub median: f64,
+    pub max: f64,
+    /// Share of the pixels without samples.
+    pub holes: f64,
+}
+
+pub fn channel_coverage(img: &Image<RgbBayer<f64>>) -> [ChannelCoverage; 3] {
+    let covered: Vec<&RgbBayer<f64>> = img.pixels.iter().filter(|p| p.rc > 0.0 || p.gc > 0.0 || p.bc > 0.0).collect();
+    let stats = |weight: &Fn(&RgbBayer<f64>) -> f64| {
+        let mut weights: Vec<f64> = covered.iter().map(|p| weight(p)).collect();
+        if weights.is_empty() {
+            return ChannelCoverage { min: 0.0, median: 0.0, max: 0.0, holes: 1.0 };
+        }
+        weights.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        ChannelCoverage {
+            min: weights[0],
+            median: sorted_median(&weights),
+            max: weights[weights.len() - 1],
+            holes: weights.iter().filter(|&&w| w == 0.0).count() as f64 / weights.len() as f64,
+        }
+    };
+    [stats(&|p| p.rc), stats(&|p| p.gc), stats(&|p| p.bc)]
+}
+
+/// The weight of each channel relative to its median, so 1 is typical coverage and 0 a hole.
+pub fn coverage_map(img: &Image<RgbBayer<f64>>, coverage: &[ChannelCoverage; 3]) -> Image<Rgb<f64>> {
+    let relative = |w: f64, c: &ChannelCoverage| if c.median > 0.0 { w / c.median } else { 0.0 };
+    img.map(|p| Rgb {
+        r: relative(p.rc, &coverage[0]),
+        g: relative(p.gc, &coverage[1]),
+        b: relative(p.bc, &coverage[2]),
+    })
+}
+
+/// Divides out the weights, like `to_rgb`, but with `uncovered` where a channel has none.
+pub fn to_rgb(img: &Image<RgbBayer<f64>>, uncovered: Uncovered) -> Image<Rgb<f64>> {
+    let r = channel(img, |p| (p.r, p.rc), uncovered);
+    let g = channel(img, |p| (p.g, p.gc), uncovered);
+    let b = channel(img, |p| (p.b, p.bc), uncovered);
+    let pixels = r.into_iter().zip(g.into_iter()).zip(b.into_iter()).map(|((r, g), b)| Rgb { r, g, b }).collect();
+    Image { width: img.width, height: img.height, pixels }
+}
+
+fn channel<F>(img: &Image<RgbBayer<f64>>, value: F, uncovered: Uncovered) -> Vec<f64>
+where F: Fn(&RgbBayer<f64>) -> (f64, f64) {
+    let mut values: Vec<f64> = img.pixels.iter().map(|p| {
+        let (v, w) = value(p);
+        if w > 0.0 { v / w } else { f64::NAN }
+    }).collect();
+    if uncovered == Uncovered::Interpolate {
+        let inside: Vec<bool> = img.pixels.iter().map(|p| p.rc > 0.0 || p.gc > 0.0 || p.bc > 0.0).collect();
+        fill(&mut values, &inside, img.width, img.height);
+    }
+    if uncovered != Uncovered::Nan {
+        for v in values.iter_mut().filter(|v| v.is_nan()) {
+            *v = 0.0;
+        }
+    }
+    values
+}
+
+/// Replaces NaNs that have finite neighbours with their average, up to `MAX_FILL` times,
+/// but only `inside`.
+fn fill(values: &mut Vec<f64>, inside: &[bool], width: usize, height: usize) {
+    for _ in 0..MAX_FILL {
+        let mut filled = values.clone();
+        let mut changed = false;
+        for y in 0..height {
+            for x in 0..width {
+                if !values[y * width + x].is_nan() || !inside[y * width + x] {
+                    continue;
+                }
+                let (mut sum, mut n) = (0.0, 0);
+                for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                        let v = values[ny * width + nx];
+                        if !v.is_nan() {
+                            sum += v;
+                            n += 1;
+                        }
+                    }
+                }
+                if n > 0 {
+                    filled[y * width + x] = sum / n as f64;
+                    changed = true;
+                }
+            }
+        }
+        *values = filled;
+        if !changed {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stack() -> Image<RgbBayer<f64>> {
+        let mut img = Image::new(4, 3);
+        for p in img.pixels.iter_mut() {
+            *p = RgbBayer { r: 2.0, g: 6.0, b: 1.0, rc: 1.0, gc: 2.0, bc: 0.5 };
+        }
+        // a red hole, and a pixel without any samples
+        img.pixel_at_mut(1, 1).rc = 0.0;
+        img.pixel_at_mut(1, 1).r = 0.0;
+        *img.pixel_at_mut(3, 2) = RgbBayer { r: 0.0, g: 0.0, b: 0.0, rc: 0.0, gc: 0.0, bc: 0.0 };
+        img
+    }
+
+    #[test]
+    fn test_channel_coverage() {
+        let coverage = channel_coverage(&stack());
+        assert_eq!(coverage[0], ChannelCoverage { min: 0.0, median: 1.0, max: 1.0, holes: 1.0 / 11.0 });
+        assert_eq!(coverage[1], ChannelCoverage { min: 2.0, median: 2.0, max: 2.0, holes: 0.0 });
+    }
+
+    #[test]
+    fn test_uncovered() {
+        let img = stack();
+        assert_eq!(to_rgb(&img, Uncovered::Zero).pixel_at(1, 1).r, 0.0);
+        assert!(to_rgb(&img, Uncovered::Nan).pixel_at(1, 1).r.is_nan());
+        let filled = to_rgb(&img, Uncovered::Interpolate);
+        assert_eq!(filled.pixel_at(1, 1).r, 2.0);
+        assert_eq!(filled.pixel_at(1, 1).g, 3.0);
+        // Outside the canvas.
+        assert_eq!(filled.pixel_at(3, 2).b, 0.0);
+    }
+}
@@ -0,0 +1,180 @@
+//! Registered frames kept in a memory-mapped scratch file, so they can be combined
+//! one tile at a time without holding every frame in memory.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::slice;
+use memmap::{MmapMut, MmapOptions};
+use image::{Image, RgbBayer};
+
+type Pixel = RgbBayer<f32>;
+
+/// A rectangle of the output, and where its pixels start in the scratch file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    offset: usize,
+}
+
+impl Tile {
+    pub fn len(&self) -> usize {
+        self.width * self.height
+    }
+}
+
+/// The scratch file holds the tiles one after the other, and within a tile, the pixels
+/// of each frame one after the other, as raw `RgbBayer<f32>` in native byte order.
+/// So all frames of a tile are contiguous, and the tile size bounds how much of the file
+/// has to be paged in at once. The file is removed when the store is dropped.
+pub struct FrameStore {
+    pub width: usize,
+    pub height: usize,
+    capacity: usize,
+    len: usize,
+    weights: Vec<f64>,
+    tiles: Vec<Tile>,
+    path: PathBuf,
+    map: MmapMut,
+}
+
+impl FrameStore {
+    /// Creates a store for up to `capacity` frames in `dir`, or in the system's temporary directory.
+    /// Tiles are sized so that one tile of all frames takes at most `memory_limit` bytes.
+    pub fn new(dir: Option<&str>, width: usize, height: usize, capacity: usize, memory_limit: usize) -> Self {
+        let path = dir.map(PathBuf::from).unwrap_or_else(env::temp_dir)
+            .join(format!("stack-{}.frames", process::id()));
+        let tile_size = tile_size(width, height, capacity, memory_limit);
+        let mut tiles = Vec::new();
+        let mut offset = 0;
+        for y in (0..height).filter(|y| y % tile_size == 0) {
+            for x in (0..width).filter(|x| x % tile_size == 0) {
+                let tile = Tile {
+                    x,
+                    y,
+                    width: tile_size.min(width - x),
+                    height: tile_size.min(height - y),
+                    offset,
+                };
+                offset += tile.len() * capacity;
+                tiles.push(tile);
+            }
+        }
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.set_len((offset * mem::size_of::<Pixel>()) as u64).unwrap();
+        let map = unsafe { MmapOptions::new().map_mut(&file).unwrap() };
+        FrameStore { width, height, capacity, len: 0, weights: Vec::new(), tiles, path, map }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The weight of each frame, as passed to `push`.
+    pub fn weights(&self) -> &[f64] {
+        &self.weights
+    }
+
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    pub fn push(&mut self, image: &Image<RgbBayer<f64>>, weight: f64) {
+        assert_eq!((image.width, image.height), (self.width, self.height));
+        assert!(self.len < self.capacity, "frame store is full");
+        let frame = self.len;
+        let pixels = as_pixels_mut(&mut self.map);
+        for tile in self.tiles.iter() {
+            let start = tile.offset + frame * tile.len();
+            let dst = &mut pixels[start..start + tile.len()];
+            for y in 0..tile.height {
+                let src = &image.pixels[(tile.y + y) * image.width + tile.x..][..tile.width];
+                for (d, p) in dst[y * tile.width..][..tile.width].iter_mut().zip(src.iter()) {
+                    *d = RgbBayer {
+                        r: p.r as f32,
+                        g: p.g as f32,
+                        b: p.b as f32,
+                        rc: p.rc as f32,
+                        gc: p.gc as f32,
+                        bc: p.bc as f32,
+                    };
+                }
+            }
+        }
+        self.weights.push(weight);
+        self.len += 1;
+    }
+
+    /// The pixels of every frame within `tile`: pixel `i` of frame `f` is at `f * tile.len() + i`.
+    pub fn tile(&self, tile: &Tile) -> &[Pixel] {
+        &as_pixels(&self.map)[tile.offset..tile.offset + self.len * tile.len()]
+    }
+}
+
+/// The map is page aligned, so it's aligned for `Pixel` too.
+fn as_pixels(bytes: &[u8]) -> &[Pixel] {
+    unsafe { slice::from_raw_parts(bytes.as_ptr() as *const Pixel, bytes.len() / mem::size_of::<Pixel>()) }
+}
+
+fn as_pixels_mut(bytes: &mut [u8]) -> &mut [Pixel] {
+    unsafe { slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut Pixel, bytes.len() / mem::size_of::<Pixel>()) }
+}
+
+impl Drop for FrameStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Side of the largest square tile whose pixels of all frames fit in `memory_limit` bytes.
+fn tile_size(width: usize, height: usize, frames: usize, memory_limit: usize) -> usize {
+    let pixels = memory_limit / (frames.max(1) * mem::size_of::<Pixel>());
+    let size = (pixels as f64).sqrt() as usize;
+    size.max(16).min(width.max(height).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_size() {
+        assert_eq!(tile_size(1000, 1000, 10, 10 * 24 * 100 * 100), 100);
+        assert_eq!(tile_size(1000, 1000, 10, 0), 16);
+        assert_eq!(tile_size(10, 5, 10, 1 << 30), 10);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (width, height) = (40, 23);
+        let mut store = FrameStore::new(None, width, height, 3, 2 * 16 * 16 * 24);
+        assert_eq!(store.tiles().len(), 3 * 2);
+        for f in 0..2 {
+            let mut img = Image::new(width, height);
+            for (i, p) in img.pixels.iter_mut().enumerate() {
+                *p = RgbBayer { r: (i + f) as f64, g: 0.0, b: 0.0, rc: 1.0, gc: 0.0, bc: 0.0 };
+            }
+            store.push(&img, 1.0);
+        }
+        assert_eq!(store.len(), 2);
+        for tile in store.tiles().iter() {
+            let pixels = store.tile(tile);
+            assert_eq!(pixels.len(), 2 * tile.len());
+            for f in 0..2 {
+                for i in 0..tile.len() {
+                    let (x, y) = (tile.x + i % tile.width, tile.y + i / tile.width);
+                    assert_eq!(pixels[f * tile.len() + i].r, (y * width + x + f) as f32);
+                }
+            }
+        }
+        let path = store.path.clone();
+        drop(store);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,170 @@
+//! Live stacking: watches a directory for new captures and adds each one to the stack
+//! as soon as it's complete, writing the current FITS and a stretched preview every time.
+
+use std::collections::{HashMap, HashSet};
+use std::f64;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use image::{Image, OwnedImage, ImageDimensions, Rgb, RgbBayer};
+use image::stretch;
+use geom::Matrix3x3;
+use align_api::AlignedImage;
+use star_aligner;
+use imagemagick;
+use stack_methods::{self, StackMethod, Registration};
+use pool;
+use {Opt, Preprocessing, save_fits_with_weights};
+
+pub struct Live<'a> {
+    pub opt: &'a Opt,
+    pub watch: &'a str,
+    pub extension: &'a str,
+    pub preview: &'a str,
+    pub poll_interval: Duration,
+    pub registration: Registration,
+    pub max_stars: usize,
+    pub min_matching_stars: usize,
+    pub threshold: f64,
+}
+
+impl<'a> Live<'a> {
+    /// Runs until killed.
+    pub fn run(&self) {
+        let mut watcher = Watcher::new(self.watch, self.extension);
+        let mut reference: Option<(star_aligner::Reference, Preprocessing)> = None;
+        let method = stack_methods::Average { registration: self.registration };
+        let mut stack: Option<Image<RgbBayer<f64>>> = None;
+        let (mut stacked, mut skipped) = (0, 0);
+        println!("watching {} for new *.{} files", self.watch, self.extension);
+        loop {
+            for path in watcher.poll() {
+                let filename = path.to_string_lossy().into_owned();
+                // A corrupt or half written capture only costs that frame.
+                let prepared = panic::catch_unwind(AssertUnwindSafe(|| self.prepare(&filename, &mut reference)))
+                    .map_err(pool::panic_message)
+                    .and_then(|result| result);
+                let (img, transform, weight) = match prepared {
+                    Ok(prepared) => prepared,
+                    Err(error) => {
+                        skipped += 1;
+                        println!("{}: {}, skipping ({} skipped so far)", filename, error, skipped);
+                        continue;
+                    }
+                };
+                stack = method.stack(stack, img, transform, weight);
+                stacked += 1;
+                println!("{}: stacked, {} frames so far", filename, stacked);
+
+                let img = stack.as_ref().unwrap();
+                let tmp = format!("{}.tmp", self.opt.output);
+                save_fits_with_weights(img, &[], self.opt.uncovered, &tmp);
+                fs::rename(&tmp, &self.opt.output).unwrap();
+                save_preview(&img.to_rgb(), self.preview);
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+
+    /// Aligns and loads a new frame, which becomes the reference if there is none yet.
+    fn prepare(&self, filename: &str, reference: &mut Option<(star_aligner::Reference, Preprocessing)>)
+        -> Result<(Image<f64>, Matrix3x3<f64>, f64), String> {
+        let stars = star_aligner::stars(filename, self.max_stars);
+        // Matching needs triangles of stars.
+        if stars.len() < 3 {
+            return Err(format!("only {} stars found", stars.len()));
+        }
+        let transform = match *reference {
+            Some((ref reference, _)) => reference.align_stars(&stars),
+            None if stars.len() >= self.min_matching_stars => {
+                println!("{}: using as the reference", filename);
+                let reference_file = aligned(filename, Matrix3x3::identity());
+                *reference = Some((
+                    star_aligner::Reference::from_stars(stars, star_aligner::Options {
+                        max_stars: self.max_stars,
+                        min_matching_stars: self.min_matching_stars,
+                        threshold: self.threshold,
+                    }),
+                    Preprocessing::new(self.opt, &[reference_file]),
+                ));
+                Some(Matrix3x3::identity())
+            }
+            None => None,
+        };
+        let transform = transform.ok_or_else(|| "failed to align".to_string())?;
+        let preprocessing = &reference.as_ref().unwrap().1;
+        let (img, weight) = preprocessing.load(&aligned(filename, transform))?;
+        Ok((img, transform, weight))
+    }
+}
+
+fn aligned(filename: &str, transform: Matrix3x3<f64>) -> AlignedImage {
+    AlignedImage {
+        filename: filename.to_string(),
+        transform,
+        stamp: None,
+        weight: None,
+    }
+}
+
+/// Finds new files in a directory. A file is only returned once its size
+/// stayed the same between two polls, so captures still being written are left alone.
+struct Watcher {
+    dir: PathBuf,
+    extension: String,
+    seen: HashSet<PathBuf>,
+    sizes: HashMap<PathBuf, u64>,
+}
+
+impl Watcher {
+    fn new<P: AsRef<Path>>(dir: P, extension: &str) -> Self {
+        Watcher {
+            dir: dir.as_ref().to_path_buf(),
+            extension: extension.to_lowercase(),
+            seen: HashSet::new(),
+            sizes: HashMap::new(),
+        }
+    }
+
+    /// Returns the files that are complete since the last poll, sorted by name.
+    fn poll(&mut self) -> Vec<PathBuf> {
+        let mut ready = Vec::new();
+        for entry in fs::read_dir(&self.dir).unwrap() {
+            let path = entry.unwrap().path();
+            let matches = path.extension()
+                .map(|e| e.to_string_lossy().to_lowercase() == self.extension)
+                .unwrap_or(false);
+            if !matches || self.seen.contains(&path) {
+                continue;
+            }
+            let len = match fs::metadata(&path) {
+                Ok(metadata) => metadata.len(),
+                Err(_) => continue,
+            };
+            if len > 0 && self.sizes.get(&path) == Some(&len) {
+                self.sizes.remove(&path);
+                self.seen.insert(path.clone());
+                ready.push(path);
+            } else {
+                self.sizes.insert(path, len);
+            }
+        }
+        ready.sort();
+        ready
+    }
+}
+
+/// Stretches like `post auto-stf --unlinked` after `normalize`, so uncovered pixels (NaN)
+/// end up black.
+fn save_preview(img: &Image<Rgb<f64>>, filename: &str) {
+    let view = OwnedImage {
+        dimensions: ImageDimensions { width: img.width, height: img.height, pitch: img.width },
+        pixels: img.pixels.clone(),
+    };
+    let normalized = stretch::normalize(&view);
+    let stretched = stretch::apply_stf(&normalized, &stretch::auto_stf(&normalized, 0.25, -2.8, false));
+    let data: Vec<f32> = stretched.pixels.iter().flat_map(|p| vec![p.r as f32, p.g as f32, p.b as f32]).collect();
+    imagemagick::convert_save_unit(&data, img.width, img.height, "rgb", "TrueColor", None, filename);
+}
@@ -0,0 +1,94 @@
+//! Local normalization of CFA frames: `donuts::local_normalization` on each Bayer sub-plane.
+//!
+//! The tile maps are estimated on the frame's sub-planes registered onto the reference,
+//! then applied to the unregistered frame, so it can be registered as usual afterwards.
+
+use std::f32;
+use image::Image;
+use geom::{Point, Matrix3x3};
+use donuts::local_normalization::{TileStats, Maps};
+use star_stuff::normalization::{Normalization, SUB_PLANES, corresponding_pixel};
+
+pub struct LocalReference {
+    width: usize,
+    height: usize,
+    tiles: usize,
+    planes: Vec<TileStats>,
+}
+
+impl LocalReference {
+    /// Takes the flat-fielded reference frame, and the number of tiles across each sub-plane.
+    pub fn new(reference: &Image<f64>, tiles: usize) -> Self {
+        let planes = SUB_PLANES.iter().map(|&(dx, dy)| {
+            TileStats::new(&registered_sub_plane(reference, Matrix3x3::identity(), reference.width, reference.height, dx, dy), tiles)
+        }).collect();
+        LocalReference {
+            width: reference.width,
+            height: reference.height,
+            tiles,
+            planes,
+        }
+    }
+
+    /// Maps `image` onto the reference's levels, in place.
+    /// `transform` maps reference coordinates to `image` coordinates.
+    pub fn normalize(&self, image: &mut Image<f64>, transform: Matrix3x3<f64>, normalization: Normalization) {
+        let (fit_offset, fit_gain) = match normalization {
+            Normalization::None => return,
+            Normalization::Additive => (true, false),
+            Normalization::Multiplicative => (false, true),
+            Normalization::Both => (true, true),
+        };
+        let inverse = transform.inverse();
+        for (reference, &(dx, dy)) in self.planes.iter().zip(SUB_PLANES.iter()) {
+            let registered = registered_sub_plane(image, transform, self.width, self.height, dx, dy);
+            let maps = Maps::new(reference, &TileStats::new(&registered, self.tiles), fit_offset, fit_gain);
+            for y in (dy..image.height).filter(|y| y % 2 == dy) {
+                for x in (dx..image.width).filter(|x| x % 2 == dx) {
+                    // Where this pixel is on the reference's sub-plane.
+                    let p = inverse * Point { x: x as f64 + 0.5, y: y as f64 + 0.5 };
+                    let (offset, gain) = maps.at(((p.x - dx as f64 + 0.5) / 2.0) as f32, ((p.y - dy as f64 + 0.5) / 2.0) as f32);
+                    let v = image.pixel_at_mut(x, y);
+                    *v = (*v - offset as f64) / gain as f64;
+                }
+            }
+        }
+    }
+}
+
+/// Sub-plane `(dx, dy)` of `image`, resampled onto the sub-plane of a `width` × `height` reference.
+/// Pixels that fall outside of `image` are NaN.
+fn registered_sub_plane(image: &Image<f64>, transform: Matrix3x3<f64>, width: usize, height: usize, dx: usize, dy: usize) -> Image<f32> {
+    let (w, h) = (width / 2, height / 2);
+    let mut pixels = Vec::with_capacity(w * h);
+    for y in 0..h {
+        for x in 0..w {
+            pixels.push(match corresponding_pixel(transform, 2 * x + dx, 2 * y + dy, image.width, image.height) {
+                Some((sx, sy)) => *image.pixel_at(sx, sy) as f32,
+                None => f32::NAN,
+            });
+        }
+    }
+    Image { width: w, height: h, pixels }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use star_stuff::test_support::{sky, image};
+
+    #[test]
+    fn removes_a_gradient() {
+        let (w, h) = (128, 96);
+        let reference = image(w, h, |x, y| sky(x, y) + (x % 2) as f64 * 30.0);
+        let mut frame = image(w, h, |x, y| *reference.pixel_at(x, y) + 0.5 * x as f64);
+        LocalReference::new(&reference, 8).normalize(&mut frame, Matrix3x3::identity(), Normalization::Additive);
+        // Away from the edge tiles, where smoothing bends the maps, the gradient is gone.
+        for y in 32..h - 32 {
+            for x in 32..w - 32 {
+                let d = *frame.pixel_at(x, y) - *reference.pixel_at(x, y);
+                assert!(d.abs() < 2.0, "{} at {}, {}", d, x, y);
+            }
+        }
+    }
+}
@@ -3,134 +3,688 @@ extern crate image;
 extern crate align_api;
 extern crate crossbeam;
 extern crate geom;
+extern crate fits;
+extern crate convert;
 extern crate structopt;
+extern crate memmap;
+extern crate donuts;
+extern crate star_aligner;
+extern crate imagemagick;
+extern crate num_cpus;
+extern crate byteorder;
 #[macro_use] extern crate structopt_derive;
 
-use std::sync::mpsc::sync_channel;
-use image::{Image, Rgb, ImageKind};
-use crossbeam::sync::chase_lev;
+mod frame_store;
+mod local_normalization;
+mod live;
+mod pool;
+mod comet;
+mod checkpoint;
+mod coverage;
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::str::FromStr;
+use std::time::Duration;
+use image::{Image, Rgb, RgbBayer, ImageKind};
+use image::stats::median;
 use structopt::StructOpt;
-use stack_methods::StackMethod;
+use star_stuff::drizzle::Kernel;
+use star_stuff::resample::Interpolation;
+use star_stuff::rejection::{self, Rejection, Combination, Sample, Combined};
+use star_stuff::quality::{self, NoiseEstimator};
+use star_stuff::normalization::{self, Normalization, Estimator};
+use star_stuff::footprint::{self, Canvas};
+use star_aligner::catalog;
+use convert::convert_vec;
+use stack_methods::{StackMethod, Registration};
+use align_api::AlignedImage;
+use frame_store::FrameStore;
+use local_normalization::LocalReference;
+use pool::{Pool, for_each_image};
+use comet::{Mark, Track};
+use checkpoint::{Checkpointer, Checkpoint};
+use coverage::Uncovered;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "stack", about = "")]
 struct Opt {
-    #[structopt(long = "alignment", help = "Alignment json file")]
-    alignment: String,
+    #[structopt(long = "alignment", help = "Alignment json file. Not used by live")]
+    alignment: Option<String>,
     #[structopt(long = "flat", help = "FITS file of flat field")]
     flat: String,
-    #[structopt(long = "output", help = "Filename of output FITS file")]
+    #[structopt(long = "output", help = "Filename of output FITS file. The weight of each channel is written as a second HDU")]
     output: String,
+    #[structopt(long = "weighting", help = "Frame weights: equal, noise (inverse variance, 1 / σ² of the noise estimate), fwhm (inverse square of the star FWHM) or file (from the alignment file). Frames without a positive, finite weight are left out", default_value = "noise")]
+    weighting: Weighting,
+    #[structopt(long = "noise", help = "Noise estimator for noise weighting: mad or wavelet", default_value = "wavelet")]
+    noise: NoiseEstimator,
+    #[structopt(long = "normalization", help = "Match each frame to the first one of the alignment file: none, additive, multiplicative or both", default_value = "none")]
+    normalization: Normalization,
+    #[structopt(long = "normalization-estimator", help = "median-mad or fit", default_value = "median-mad")]
+    normalization_estimator: Estimator,
+    #[structopt(long = "local-normalization", help = "Normalize on a grid of this many tiles across, instead of the whole frame")]
+    local_normalization: Option<usize>,
+    #[structopt(long = "canvas", help = "Output area: reference (the first frame), intersection (cropped to what every frame covers) or union (everything, for mosaics). Sigma-kappa needs an average stacked with the same canvas", default_value = "reference")]
+    canvas: Canvas,
+    #[structopt(long = "min-coverage", help = "With the intersection canvas, crop edges covered by less than this fraction of the median coverage", default_value = "0.9")]
+    min_coverage: f64,
+    #[structopt(long = "uncovered", help = "Output where a channel has no samples: zero, nan or interpolate (from the neighbours, where other channels have samples)", default_value = "zero")]
+    uncovered: Uncovered,
+    #[structopt(long = "coverage", help = "FITS file of each channel's coverage, relative to its median")]
+    coverage: Option<String>,
+    #[structopt(long = "checkpoint", help = "Save the running stack and the frames in it to this file, every --checkpoint-every frames and at the end. Average and sigma-kappa only")]
+    checkpoint: Option<String>,
+    #[structopt(long = "checkpoint-every", help = "Frames between checkpoints, 0 for only the one at the end", default_value = "10")]
+    checkpoint_every: usize,
+    #[structopt(long = "resume", help = "Start from the --checkpoint, if there is one, and only stack the frames of the alignment file that aren't in it")]
+    resume: bool,
+    #[structopt(long = "threads", help = "Frames processed in parallel. Defaults to the number of CPUs")]
+    threads: Option<usize>,
+    #[structopt(long = "queue", help = "Processed frames that may wait to be stacked", default_value = "2")]
+    queue: usize,
     #[structopt(subcommand)]
     cmd: Cmd,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Weighting {
+    Equal,
+    Noise,
+    Fwhm,
+    File,
+}
+
+impl FromStr for Weighting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "equal" => Ok(Weighting::Equal),
+            "noise" => Ok(Weighting::Noise),
+            "fwhm" => Ok(Weighting::Fwhm),
+            "file" => Ok(Weighting::File),
+            _ => Err(format!("unknown weighting: {} (expected equal, noise, fwhm or file)", s))
+        }
+    }
+}
+
 #[derive(StructOpt, Debug)]
 enum Cmd {
     #[structopt(name = "average", about = "Averages images")]
     Average {
         #[structopt(long = "pixel-aperture")]
         pixel_aperture: f64,
+        #[structopt(long = "factor", help = "Drizzle scale factor", default_value = "1")]
+        factor: f64,
+        #[structopt(long = "kernel", help = "Drizzle kernel: square, point, gaussian or turbo", default_value = "square")]
+        kernel: Kernel,
+        #[structopt(long = "interpolation", help = "Resample with nearest, bilinear, bicubic, lanczos3 or lanczos4 instead of drizzling")]
+        interpolation: Option<Interpolation>,
     },
     #[structopt(name = "sigma-kappa")]
     SigmaKappa {
         #[structopt(long = "pixel-aperture")]
         pixel_aperture: f64,
+        #[structopt(long = "factor", help = "Drizzle scale factor", default_value = "1")]
+        factor: f64,
+        #[structopt(long = "kernel", help = "Drizzle kernel: square, point, gaussian or turbo", default_value = "square")]
+        kernel: Kernel,
+        #[structopt(long = "interpolation", help = "Resample with nearest, bilinear, bicubic, lanczos3 or lanczos4 instead of drizzling")]
+        interpolation: Option<Interpolation>,
         #[structopt(long = "average", help = "FITS file of average")]
         average: String,
         #[structopt(long = "kappa")]
         kappa: f64,
+    },
+    #[structopt(name = "reject", about = "Combines images pixel by pixel, rejecting outliers across all frames. Registered frames are kept in a scratch file")]
+    Reject {
+        #[structopt(long = "pixel-aperture")]
+        pixel_aperture: f64,
+        #[structopt(long = "factor", help = "Drizzle scale factor", default_value = "1")]
+        factor: f64,
+        #[structopt(long = "kernel", help = "Drizzle kernel: square, point, gaussian or turbo", default_value = "square")]
+        kernel: Kernel,
+        #[structopt(long = "interpolation", help = "Resample with nearest, bilinear, bicubic, lanczos3 or lanczos4 instead of drizzling")]
+        interpolation: Option<Interpolation>,
+        #[structopt(long = "rejection", help = "none, min-max, percentile, sigma-clip, winsorized or linear-fit", default_value = "winsorized")]
+        rejection: Rejection,
+        #[structopt(long = "low", help = "Lower limit, in sigmas (fraction of the median for percentile, frame count for min-max)", default_value = "4")]
+        low: f64,
+        #[structopt(long = "high", help = "Upper limit, in sigmas (fraction of the median for percentile, frame count for min-max)", default_value = "3")]
+        high: f64,
+        #[structopt(long = "combine", help = "How the frames left are combined: mean or median", default_value = "mean")]
+        combination: Combination,
+        #[structopt(long = "memory-limit", help = "Memory used for combining, in MiB", default_value = "1024")]
+        memory_limit: usize,
+        #[structopt(long = "scratch", help = "Directory for the registered frames. Defaults to the temporary directory")]
+        scratch: Option<String>,
+    },
+    #[structopt(name = "comet", about = "Stacks on a moving comet, rejecting the stars that trail behind it. Optionally also stacks on the stars, rejecting the comet, and adds the two. Nothing is masked: the stars and the comet are only left out by the rejection, so they need to move by more than their size over enough frames")]
+    Comet {
+        #[structopt(long = "pixel-aperture")]
+        pixel_aperture: f64,
+        #[structopt(long = "factor", help = "Drizzle scale factor", default_value = "1")]
+        factor: f64,
+        #[structopt(long = "kernel", help = "Drizzle kernel: square, point, gaussian or turbo", default_value = "square")]
+        kernel: Kernel,
+        #[structopt(long = "interpolation", help = "Resample with nearest, bilinear, bicubic, lanczos3 or lanczos4 instead of drizzling")]
+        interpolation: Option<Interpolation>,
+        #[structopt(long = "mark", help = "Position of the comet's nucleus in a frame, as FILE@X,Y. Give two, far apart in time, or none to detect it in the first and last frames")]
+        marks: Vec<Mark>,
+        #[structopt(long = "rejection", help = "none, min-max, percentile, sigma-clip, winsorized or linear-fit", default_value = "winsorized")]
+        rejection: Rejection,
+        #[structopt(long = "low", help = "Lower limit, in sigmas (fraction of the median for percentile, frame count for min-max)", default_value = "4")]
+        low: f64,
+        #[structopt(long = "high", help = "Upper limit, in sigmas (fraction of the median for percentile, frame count for min-max)", default_value = "3")]
+        high: f64,
+        #[structopt(long = "combine", help = "How the frames left are combined: mean or median", default_value = "mean")]
+        combination: Combination,
+        #[structopt(long = "memory-limit", help = "Memory used for combining, in MiB", default_value = "1024")]
+        memory_limit: usize,
+        #[structopt(long = "scratch", help = "Directory for the registered frames. Defaults to the temporary directory")]
+        scratch: Option<String>,
+        #[structopt(long = "stars-output", help = "Also stack on the stars, with the comet rejected, into this FITS file")]
+        stars_output: Option<String>,
+        #[structopt(long = "combined-output", help = "FITS file of the star stack with the comet stack added on top. Implies stacking on the stars")]
+        combined_output: Option<String>,
+    },
+    #[structopt(name = "live", about = "Watches a directory, aligning and averaging each new frame as it arrives. The output FITS and a preview are rewritten after every frame")]
+    Live {
+        #[structopt(long = "watch", help = "Directory the captures are written to")]
+        watch: String,
+        #[structopt(long = "extension", help = "Extension of the captures", default_value = "CR2")]
+        extension: String,
+        #[structopt(long = "preview", help = "Auto-stretched preview, JPEG or PNG", default_value = "preview.jpg")]
+        preview: String,
+        #[structopt(long = "poll-interval", help = "Seconds between looks at the directory", default_value = "2")]
+        poll_interval: u64,
+        #[structopt(long = "pixel-aperture")]
+        pixel_aperture: f64,
+        #[structopt(long = "factor", help = "Drizzle scale factor", default_value = "1")]
+        factor: f64,
+        #[structopt(long = "kernel", help = "Drizzle kernel: square, point, gaussian or turbo", default_value = "square")]
+        kernel: Kernel,
+        #[structopt(long = "interpolation", help = "Resample with nearest, bilinear, bicubic, lanczos3 or lanczos4 instead of drizzling")]
+        interpolation: Option<Interpolation>,
+        #[structopt(long = "max-stars", default_value = "50")]
+        max_stars: usize,
+        #[structopt(long = "min-matching-stars", default_value = "10")]
+        min_matching_stars: usize,
+        #[structopt(long = "threshold", help = "px", default_value = "1")]
+        threshold: f64,
     }
 }
 
 fn main() {
     let opt = Opt::from_args();
     //println!("{:?}", opt);
+    if let Cmd::Live { ref watch, ref extension, ref preview, poll_interval, pixel_aperture, factor, kernel, interpolation, max_stars, min_matching_stars, threshold } = opt.cmd {
+        live::Live {
+            opt: &opt,
+            watch,
+            extension,
+            preview,
+            poll_interval: Duration::from_secs(poll_interval),
+            registration: Registration { pixel_aperture, factor, kernel, interpolation, area: None, crop: None },
+            max_stars,
+            min_matching_stars,
+            threshold,
+        }.run();
+        return;
+    }
+    let alignment = align_api::read(opt.alignment.as_ref().expect("--alignment is required"));
+    let preprocessing = Preprocessing::new(&opt, &alignment);
+    let pool = Pool::new(opt.threads, opt.queue);
+    let checkpointer = opt.checkpoint.as_ref().map(|filename| Checkpointer {
+        filename: filename.clone(),
+        every: opt.checkpoint_every,
+        settings: format!("{:?} flat={} weighting={:?} noise={:?} normalization={:?} {:?} {:?} canvas={:?} {}",
+                          opt.cmd, opt.flat, opt.weighting, opt.noise, opt.normalization, opt.normalization_estimator,
+                          opt.local_normalization, opt.canvas, opt.min_coverage),
+    });
+    let resumed = if opt.resume {
+        let resumed = checkpointer.as_ref().expect("--resume needs a --checkpoint").load();
+        if resumed.is_none() {
+            println!("no checkpoint yet, starting from scratch");
+        }
+        resumed
+    } else {
+        None
+    };
+    // A resumed stack keeps its canvas, even if more frames were aligned since.
+    let transforms: Vec<_> = alignment.iter()
+        .filter(|file| resumed.as_ref().map(|r| r.files.contains(&file.filename)).unwrap_or(true))
+        .map(|file| file.transform.to_f64())
+        .collect();
+    let area = footprint::area(opt.canvas, &transforms, preprocessing.flat.width, preprocessing.flat.height);
+    assert!(!area.is_empty(), "the frames don't overlap");
+    println!("canvas: {:?}", area);
+    let crop = if opt.canvas == Canvas::Intersection { Some(opt.min_coverage) } else { None };
+    let registration = |pixel_aperture, factor, kernel, interpolation| {
+        Registration { pixel_aperture, factor, kernel, interpolation, area: Some(area), crop }
+    };
     match opt.cmd {
-        Cmd::Average { pixel_aperture } => {
-            stack(
-                &opt.alignment,
-                &opt.flat,
-                stack_methods::Average { pixel_aperture },
-                &opt.output);
-        }
-        Cmd::SigmaKappa { pixel_aperture, average, kappa } => {
-            stack(
-                &opt.alignment,
-                &opt.flat,
+        Cmd::Average { .. } | Cmd::SigmaKappa { .. } => {}
+        _ => assert!(checkpointer.is_none(), "checkpoints only work with average and sigma-kappa"),
+    }
+    let output = Output { uncovered: opt.uncovered, coverage: opt.coverage.clone() };
+    match opt.cmd {
+        Cmd::Average { pixel_aperture, factor, kernel, interpolation } => {
+            let registration = registration(pixel_aperture, factor, kernel, interpolation);
+            let img = stack(
+                &pool,
+                alignment,
+                &preprocessing,
+                stack_methods::Average { registration },
+                checkpointer.as_ref(),
+                resumed);
+            output.report(&img, &registration);
+            output.save(&img, &[], &opt.output);
+        }
+        Cmd::SigmaKappa { pixel_aperture, factor, kernel, interpolation, average, kappa } => {
+            let registration = registration(pixel_aperture, factor, kernel, interpolation);
+            let img = stack(
+                &pool,
+                alignment,
+                &preprocessing,
                 stack_methods::SigmaKappa {
-                    pixel_aperture,
+                    registration,
                     average: open_fits_rgb(&average),
                     kappa
                 },
-                &opt.output);
+                checkpointer.as_ref(),
+                resumed);
+            output.report(&img, &registration);
+            output.save(&img, &[], &opt.output);
+        }
+        Cmd::Reject { pixel_aperture, factor, kernel, interpolation, rejection, low, high, combination, memory_limit, scratch } => {
+            let registration = registration(pixel_aperture, factor, kernel, interpolation);
+            let (img, rejected) = stack_with_rejection(
+                &pool,
+                alignment,
+                &preprocessing,
+                registration,
+                rejection,
+                low,
+                high,
+                combination,
+                memory_limit << 20,
+                scratch.as_ref().map(|s| s.as_str()));
+            output.report(&img, &registration);
+            output.save(&img, &[("REJECTED", rejected)], &opt.output);
+        }
+        Cmd::Comet { pixel_aperture, factor, kernel, interpolation, marks, rejection, low, high, combination, memory_limit, scratch, stars_output, combined_output } => {
+            let registration = registration(pixel_aperture, factor, kernel, interpolation);
+            let scratch = scratch.as_ref().map(|s| s.as_str());
+            let comet_alignment = comet_aligned(&alignment, &marks, &preprocessing);
+            println!("stacking on the comet");
+            let (comet, rejected) = stack_with_rejection(
+                &pool, comet_alignment, &preprocessing, registration, rejection, low, high, combination, memory_limit << 20, scratch);
+            output.report(&comet, &registration);
+            output.save(&comet, &[("REJECTED", rejected)], &opt.output);
+            if stars_output.is_none() && combined_output.is_none() {
+                return;
+            }
+            println!("stacking on the stars");
+            let (stars, rejected) = stack_with_rejection(
+                &pool, alignment, &preprocessing, registration, rejection, low, high, combination, memory_limit << 20, scratch);
+            if let Some(ref filename) = stars_output {
+                output.save(&stars, &[("REJECTED", rejected)], filename);
+            }
+            if let Some(ref filename) = combined_output {
+                output.save(&add_comet(&stars, &comet), &[], filename);
+            }
+        }
+        Cmd::Live { .. } => unreachable!(),
+    }
+}
+
+/// What's done to each frame before it's registered.
+struct Preprocessing {
+    flat: Image<f64>,
+    weighting: Weighting,
+    noise: NoiseEstimator,
+    normalization: Normalization,
+    normalization_estimator: Estimator,
+    /// Only loaded when normalizing, one or the other.
+    reference: Option<normalization::Reference>,
+    local_reference: Option<LocalReference>,
+}
+
+impl Preprocessing {
+    fn new(opt: &Opt, alignment: &[AlignedImage]) -> Self {
+        let mut preprocessing = Preprocessing {
+            flat: open_fits_gray(&opt.flat),
+            weighting: opt.weighting,
+            noise: opt.noise,
+            normalization: opt.normalization,
+            normalization_estimator: opt.normalization_estimator,
+            reference: None,
+            local_reference: None,
+        };
+        if opt.normalization != Normalization::None {
+            let reference = preprocessing.flat_fielded(&alignment[0]);
+            if let Some(tiles) = opt.local_normalization {
+                preprocessing.local_reference = Some(LocalReference::new(&reference, tiles));
+            } else {
+                preprocessing.reference = Some(normalization::Reference::new(reference));
+            }
         }
+        preprocessing
+    }
+
+    fn flat_fielded(&self, file: &AlignedImage) -> Image<f64> {
+        let mut img = Image::<u16>::open_raw(&file.filename).to_f32().to_f64();
+        img /= &self.flat;
+        img
+    }
+
+    /// Returns the flat fielded and normalized frame, and its weight.
+    fn load(&self, file: &AlignedImage) -> Result<(Image<f64>, f64), String> {
+        let mut img = self.flat_fielded(file);
+        if let Some(ref reference) = self.reference {
+            let transform = file.transform.to_f64();
+            let scales = reference.estimate(&img, transform, self.normalization, self.normalization_estimator);
+            println!("{}: normalization {:?}", file.filename, scales);
+            normalization::apply(&mut img, &scales);
+        }
+        if let Some(ref reference) = self.local_reference {
+            reference.normalize(&mut img, file.transform.to_f64(), self.normalization);
+        }
+        let weight = frame_weight(file, &img, self.weighting, self.noise)?;
+        Ok((img, weight))
     }
 }
 
-fn stack<S>(alignment: &str, flat: &str, stack_method: S, output: &str)
+/// Adds every frame to the stack, or only those that aren't in it yet if `resumed`.
+fn stack<S>(
+    pool: &Pool,
+    alignment: Vec<AlignedImage>,
+    preprocessing: &Preprocessing,
+    stack_method: S,
+    checkpointer: Option<&Checkpointer>,
+    resumed: Option<Checkpoint>
+) -> Image<RgbBayer<f64>>
 where S: StackMethod {
-    let flat = open_fits_gray(flat);
-    let alignment = align_api::read(alignment);
-    let img = for_each_image(
+    let (init, files) = match resumed {
+        Some(checkpoint) => (Some(checkpoint.stack), checkpoint.files),
+        None => (None, Vec::new()),
+    };
+    let alignment: Vec<_> = alignment.into_iter().filter(|file| !files.contains(&file.filename)).collect();
+    if !files.is_empty() {
+        println!("resuming with {} frames stacked, {} to go", files.len(), alignment.len());
+    }
+    let files = RefCell::new(files);
+    let (img, failures) = for_each_image(
+        pool,
         alignment,
-        || |file| {
-            (Image::<u16>::open_raw(&file.filename), file.transform.to_f64())
+        init,
+        || |file: AlignedImage| {
+            let (img, weight) = preprocessing.load(&file)?;
+            Ok(((file.filename, img, file.transform.to_f64(), weight), format!("weight {}", weight)))
         },
-        |stack, (img, transform)| {
-            let mut img = img.to_f32().to_f64();
-            img /= &flat;
-            stack_method.stack(stack, img, transform)
-        }
-    ).unwrap();
-    img.to_rgb().save_fits(output);
-
-    //let holes = img.center_crop(900, 900).holes();
-    //println!("holes min/max: {:?}", holes.min_max());
-    //holes.to_u8().save_jpeg_file("holes.jpg");
-}
-
-fn for_each_image<Item,MapFnFactory,MapFn,MappedItem,ReduceFn, ReducedItem>(
-    items: Vec<Item>, map: MapFnFactory, reduce: ReduceFn) -> Option<ReducedItem>
-where
-    Item: Send,
-    MappedItem: Send,
-    MapFnFactory: Fn() -> MapFn,
-    MapFn: Fn(Item) -> MappedItem, MapFn: Send,
-    ReduceFn: Fn(Option<ReducedItem>, MappedItem) -> Option<ReducedItem>
-{
-    let (tx, rx) = sync_channel(0);
-    let (worker, stealer) = chase_lev::deque();
-    let total = items.len();
-    for item in items.into_iter() {
-        worker.push(item);
-    }
-
-    crossbeam::scope(|scope| {
-        for _ in 0..2 {
-            let tx = tx.clone();
-            let stealer = stealer.clone();
-            let map = map();
-            scope.spawn(move || {
-                loop {
-                    let item = match stealer.steal() {
-                        chase_lev::Steal::Data(d) => d,
-                        chase_lev::Steal::Abort => continue,
-                        chase_lev::Steal::Empty => break
-                    };
-                    let mapped_item = map(item);
-                    tx.send(mapped_item).unwrap();
+        |stack, (filename, img, transform, weight)| {
+            let stack = stack_method.stack(stack, img, transform, weight);
+            let mut files = files.borrow_mut();
+            files.push(filename);
+            if let Some(checkpointer) = checkpointer {
+                if checkpointer.every > 0 && files.len() % checkpointer.every == 0 {
+                    checkpointer.save(&files, stack.as_ref().unwrap());
                 }
-                println!("thread done.");
-            });
+            }
+            stack
+        },
+        |event| println!("{}", event)
+    );
+    pool::report(&failures);
+    let img = img.expect("no frame could be stacked");
+    if let Some(checkpointer) = checkpointer {
+        checkpointer.save(&files.borrow(), &img);
+    }
+    match stack_method.registration().crop_bounds(&img) {
+        Some(bounds) => crop(&img, bounds),
+        None => img,
+    }
+}
+
+/// Registers every frame into a `FrameStore`, then combines them one tile at a time,
+/// rejecting outliers of each pixel. Returns the stack and the number of frames rejected at each pixel.
+fn stack_with_rejection(
+    pool: &Pool,
+    alignment: Vec<AlignedImage>,
+    preprocessing: &Preprocessing,
+    registration: Registration,
+    rejection: Rejection,
+    low: f64,
+    high: f64,
+    combination: Combination,
+    memory_limit: usize,
+    scratch: Option<&str>
+) -> (Image<RgbBayer<f64>>, Image<Rgb<f64>>) {
+    let frames = alignment.len();
+    let (store, failures) = for_each_image(
+        pool,
+        alignment,
+        None,
+        || |file: AlignedImage| {
+            let (img, weight) = preprocessing.load(&file)?;
+            let registered = registration.add(None, &img.to_rggb(), file.transform.to_f64(), 1.0, |_,_,_| true);
+            Ok(((registered, weight), format!("weight {}", weight)))
+        },
+        |store, (registered, weight)| {
+            let mut store = store.unwrap_or_else(|| FrameStore::new(scratch, registered.width, registered.height, frames, memory_limit));
+            store.push(&registered, weight);
+            Some(store)
+        },
+        |event| println!("{}", event)
+    );
+    pool::report(&failures);
+    let store = store.expect("no frame could be stacked");
+
+    let (img, rejected) = combine_tiles(&store, rejection, low, high, combination);
+    match registration.crop_bounds(&img) {
+        Some(bounds) => (crop(&img, bounds), crop(&rejected, bounds)),
+        None => (img, rejected),
+    }
+}
+
+/// The alignment, with each transform shifted to hold the comet where it was in the first frame.
+fn comet_aligned(alignment: &[AlignedImage], marks: &[Mark], preprocessing: &Preprocessing) -> Vec<AlignedImage> {
+    let times: Vec<f64> = alignment.iter().map(|file| comet::timestamp(&file.filename)).collect();
+    let transform_of = |filename: &str| {
+        alignment.iter().position(|file| file.filename == filename)
+            .unwrap_or_else(|| panic!("{} is not in the alignment file", filename))
+    };
+    let track = match marks.len() {
+        2 => {
+            let mark = |m: &Mark| {
+                let i = transform_of(&m.filename);
+                (times[i], alignment[i].transform.to_f64(), m.position)
+            };
+            Track::new(mark(&marks[0]), mark(&marks[1]))
+        }
+        0 => {
+            let first = (0..alignment.len()).min_by(|&a, &b| times[a].partial_cmp(&times[b]).unwrap()).unwrap();
+            let last = (0..alignment.len()).max_by(|&a, &b| times[a].partial_cmp(&times[b]).unwrap()).unwrap();
+            let detect = |i: usize| {
+                let position = comet::detect_nucleus(&preprocessing.flat_fielded(&alignment[i]));
+                println!("{}: comet at {:.1}, {:.1}", alignment[i].filename, position.x, position.y);
+                (times[i], alignment[i].transform.to_f64(), position)
+            };
+            Track::new(detect(first), detect(last))
+        }
+        n => panic!("the comet must be marked in two frames, or none, not {}", n),
+    };
+    alignment.iter().zip(times.iter()).map(|(file, &time)| {
+        AlignedImage {
+            transform: track.hold(file.transform.to_f64(), time, times[0]),
+            ..file.clone()
         }
-        drop(tx);
+    }).collect()
+}
+
+/// The star stack with the comet stack's signal added, the comet stack's background taken out.
+/// Each pixel's weight is the smaller of the two.
+fn add_comet(stars: &Image<RgbBayer<f64>>, comet: &Image<RgbBayer<f64>>) -> Image<RgbBayer<f64>> {
+    assert!(stars.width == comet.width && stars.height == comet.height,
+            "the comet and star stacks were cropped differently, use the reference or union canvas");
+    let background = |channel: &Fn(&RgbBayer<f64>) -> (f64, f64)| {
+        let mut values: Vec<f64> = comet.pixels.iter().map(|p| { let (v, w) = channel(p); v / w }).filter(|v| v.is_finite()).collect();
+        if values.is_empty() { 0.0 } else { median(&mut values) }
+    };
+    let (br, bg, bb) = (background(&|p| (p.r, p.rc)), background(&|p| (p.g, p.gc)), background(&|p| (p.b, p.bc)));
+    // Weighted sum of a channel, left empty where either stack has no samples.
+    let add = |s: f64, sw: f64, c: f64, cw: f64, background: f64, weight: f64| {
+        if weight > 0.0 { (s / sw + c / cw - background) * weight } else { 0.0 }
+    };
+    let mut combined = Image::new(stars.width, stars.height);
+    for (out, (s, c)) in combined.pixels.iter_mut().zip(stars.pixels.iter().zip(comet.pixels.iter())) {
+        let (rc, gc, bc) = (s.rc.min(c.rc), s.gc.min(c.gc), s.bc.min(c.bc));
+        *out = RgbBayer {
+            r: add(s.r, s.rc, c.r, c.rc, br, rc),
+            g: add(s.g, s.gc, c.g, c.gc, bg, gc),
+            b: add(s.b, s.bc, c.b, c.bc, bb, bc),
+            rc,
+            gc,
+            bc,
+        };
+    }
+    combined
+}
+
+/// Frames covering less of a pixel than this don't count as a sample of it.
+const MIN_COVERAGE: f32 = 0.01;
+
+fn combine_tiles(store: &FrameStore, rejection: Rejection, low: f64, high: f64, combination: Combination)
+    -> (Image<RgbBayer<f64>>, Image<Rgb<f64>>) {
+    let mut img = Image::new(store.width, store.height);
+    let mut rejected = Image::new(store.width, store.height);
+    let mut samples = Vec::with_capacity(store.len());
+    let tiles = store.tiles();
+    for (t, tile) in tiles.iter().enumerate() {
+        println!("combining tile {} of {}", t + 1, tiles.len());
+        let pixels = store.tile(tile);
+        for i in 0..tile.len() {
+            let mut channel = |value: &Fn(&RgbBayer<f32>) -> (f32, f32)| -> Combined {
+                samples.clear();
+                for (frame, &weight) in pixels.chunks(tile.len()).zip(store.weights().iter()) {
+                    let (sum, coverage) = value(&frame[i]);
+                    if coverage > MIN_COVERAGE {
+                        samples.push(Sample { value: (sum / coverage) as f64, weight: coverage as f64 * weight });
+                    }
+                }
+                rejection::combine(&mut samples, rejection, low, high, combination)
+            };
+            let r = channel(&|p| (p.r, p.rc));
+            let g = channel(&|p| (p.g, p.gc));
+            let b = channel(&|p| (p.b, p.bc));
+            let (x, y) = (tile.x + i % tile.width, tile.y + i / tile.width);
+            *img.pixel_at_mut(x, y) = RgbBayer {
+                r: r.value * r.weight,
+                g: g.value * g.weight,
+                b: b.value * b.weight,
+                rc: r.weight,
+                gc: g.weight,
+                bc: b.weight,
+            };
+            *rejected.pixel_at_mut(x, y) = Rgb {
+                r: r.rejected as f64,
+                g: g.rejected as f64,
+                b: b.rejected as f64,
+            };
+        }
+    }
+    (img, rejected)
+}
+
+/// Weight of a flat fielded frame. Only the ratios between frames matter.
+/// Frames whose weight can't be measured, or isn't positive and finite, fail.
+fn frame_weight(file: &AlignedImage, img: &Image<f64>, weighting: Weighting, noise: NoiseEstimator) -> Result<f64, String> {
+    let weight = match weighting {
+        Weighting::Equal => 1.0,
+        Weighting::Noise => {
+            let sigma = quality::cfa_noise(img, noise);
+            1.0 / (sigma * sigma)
+        }
+        Weighting::Fwhm => {
+            // Measure at the stars `align` cached for this frame, if it has them.
+            let fwhm = match catalog::load(&file.filename) {
+                Some(catalog) => quality::cfa_fwhm_at(img, &catalog.stars),
+                None => quality::cfa_fwhm(img)
+            };
+            match fwhm {
+                Some(fwhm) => 1.0 / (fwhm * fwhm),
+                None => return Err("no stars found to measure the FWHM".to_string())
+            }
+        }
+        Weighting::File => {
+            file.weight.ok_or_else(|| "no weight in the alignment file".to_string())?
+        }
+    };
+    if weight.is_finite() && weight > 0.0 {
+        Ok(weight)
+    } else {
+        Err(format!("invalid weight {}", weight))
+    }
+}
 
-        rx.iter().enumerate().fold(None, |acc, (i, mapped_item)| {
-            println!("processing image {} of {}", i, total);
-            reduce(acc, mapped_item)
-        })
-    })
+fn crop<P: Copy>(img: &Image<P>, (x, y, width, height): (usize, usize, usize, usize)) -> Image<P> {
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in y..y + height {
+        for x in x..x + width {
+            pixels.push(*img.pixel_at(x, y));
+        }
+    }
+    Image { width, height, pixels }
+}
+
+/// Share of pixels without samples in a channel above which the drizzle is too sparse.
+const MAX_HOLES: f64 = 0.001;
+
+/// How the stack is written out.
+struct Output {
+    uncovered: Uncovered,
+    /// Where the coverage map goes, if anywhere.
+    coverage: Option<String>,
+}
+
+impl Output {
+    /// Prints the coverage of each channel, and warns if drizzling left holes.
+    fn report(&self, img: &Image<RgbBayer<f64>>, registration: &Registration) {
+        let coverage = coverage::channel_coverage(img);
+        for (name, c) in ["red", "green", "blue"].iter().zip(coverage.iter()) {
+            println!("{} coverage: min {:.3}, median {:.3}, max {:.3}, {:.2}% holes", name, c.min, c.median, c.max, c.holes * 100.0);
+            if c.holes > MAX_HOLES && registration.interpolation.is_none() {
+                println!("warning: {:.2}% of the pixels have no {} samples, too sparse for --factor {} and --pixel-aperture {}. \
+                          Lower the factor, raise the pixel aperture or stack more frames",
+                         c.holes * 100.0, name, registration.factor, registration.pixel_aperture);
+            }
+        }
+        if let Some(ref filename) = self.coverage {
+            let map = coverage::coverage_map(img, &coverage);
+            let shape = [3, map.width, map.height];
+            let mut f = BufWriter::new(File::create(filename).unwrap());
+            fits::write_image(&mut f, &shape[..], &fits::Data::F64(convert_vec(map.pixels)));
+        }
+    }
+
+    fn save(&self, img: &Image<RgbBayer<f64>>, extensions: &[(&str, Image<Rgb<f64>>)], filename: &str) {
+        save_fits_with_weights(img, extensions, self.uncovered, filename);
+    }
+}
+
+/// Saves the image as the primary HDU, the weight of each channel as a `WEIGHT` extension,
+/// and then `extensions`, which must be the same size as the image.
+fn save_fits_with_weights(img: &Image<RgbBayer<f64>>, extensions: &[(&str, Image<Rgb<f64>>)], uncovered: Uncovered, filename: &str) {
+    let shape = [3, img.width, img.height];
+    let mut f = BufWriter::new(File::create(filename).unwrap());
+    fits::write_image(&mut f, &shape[..], &fits::Data::F64(convert_vec(coverage::to_rgb(img, uncovered).pixels)));
+    fits::write_extension(&mut f, "WEIGHT", &shape[..], &fits::Data::F64(convert_vec(img.weights().pixels)));
+    for &(name, ref extension) in extensions.iter() {
+        fits::write_extension(&mut f, name, &shape[..], &fits::Data::F64(convert_vec(extension.pixels.clone())));
+    }
 }
 
 fn open_fits_gray(filename: &str) -> Image<f64> {
@@ -142,58 +696,128 @@ fn open_fits_gray(filename: &str) -> Image<f64> {
 }
 
 fn open_fits_rgb(filename: &str) -> Image<Rgb<f64>> {
-    if let ImageKind::RgbF64(v) = ImageKind::open_fits(filename) {
-        v
-    } else {
-        panic!("wrong fits type")
-    }
+    let (width, height, data) = fits::read_rgb(&mut BufReader::new(File::open(filename).unwrap()));
+    Image { width, height, pixels: convert_vec(data) }
 }
 
 pub mod stack_methods {
+    use std::f64;
     use image::{Image, Rgb, RgbBayer};
-    use star_stuff::drizzle;
+    use image::stats::median;
+    use star_stuff::drizzle::{self, Kernel};
+    use star_stuff::resample::{self, Interpolation};
+    use star_stuff::footprint::{self, Bounds};
     use geom::Matrix3x3;
 
     pub trait StackMethod {
-        fn stack(&self, stack: Option<Image<RgbBayer<f64>>>, img: Image<f64>, transform: Matrix3x3<f64>) -> Option<Image<RgbBayer<f64>>>;
+        fn registration(&self) -> &Registration;
+        fn stack(&self, stack: Option<Image<RgbBayer<f64>>>, img: Image<f64>, transform: Matrix3x3<f64>, weight: f64) -> Option<Image<RgbBayer<f64>>>;
     }
 
-    pub struct Average {
+    /// How each frame is mapped onto the output.
+    #[derive(Copy, Clone, Debug)]
+    pub struct Registration {
         pub pixel_aperture: f64,
+        pub factor: f64,
+        pub kernel: Kernel,
+        /// If set, frames are resampled with it instead of drizzled.
+        pub interpolation: Option<Interpolation>,
+        /// Part of the reference covered by the output, the whole first frame if not set.
+        pub area: Option<Bounds>,
+        /// If set, the stack is cropped to where the coverage is at least this fraction of the median.
+        pub crop: Option<f64>,
+    }
+
+    impl Registration {
+        /// Adds `img` to `stack` with `weight`, creating the stack if this is the first frame.
+        /// `filter` sees the pixels before they are weighted.
+        pub fn add<FilterFn>(
+            &self,
+            stack: Option<Image<RgbBayer<f64>>>,
+            img: &Image<RgbBayer<f64>>,
+            transform: Matrix3x3<f64>,
+            weight: f64,
+            filter: FilterFn
+        ) -> Image<RgbBayer<f64>>
+        where FilterFn: Fn(usize, usize, RgbBayer<f64>) -> bool {
+            let (mut stack, transform) = match self.area {
+                Some(area) => {
+                    let size = |v: f64| (v * self.factor).round() as usize;
+                    let stack = stack.unwrap_or_else(|| Image::new(size(area.width()), size(area.height())));
+                    (stack, transform * Matrix3x3::translation(area.left, area.top))
+                }
+                None => (stack.unwrap_or_else(|| drizzle::canvas(img, self.factor)), transform),
+            };
+            let weighted = img.map(|&p| p * weight);
+            let filter = |x, y, p: RgbBayer<f64>| filter(x, y, p * (1.0 / weight));
+            if let Some(interpolation) = self.interpolation {
+                resample::add(&mut stack, &weighted, transform, self.factor, &interpolation, filter);
+            } else {
+                drizzle::add(&mut stack, &weighted, transform, self.factor, self.pixel_aperture, self.kernel, filter);
+            }
+            stack
+        }
+
+        /// The part of `stack` to keep, as `(x, y, width, height)`, if it's to be cropped.
+        pub fn crop_bounds(&self, stack: &Image<RgbBayer<f64>>) -> Option<(usize, usize, usize, usize)> {
+            let min = match self.crop {
+                Some(min) => min,
+                None => return None,
+            };
+            let weights = stack.weights();
+            let channel_median = |channel: &Fn(&Rgb<f64>) -> f64| median(&mut weights.pixels.iter().map(|p| channel(p)).collect::<Vec<_>>());
+            let (r, g, b) = (channel_median(&|p| p.r), channel_median(&|p| p.g), channel_median(&|p| p.b));
+            if !(r > 0.0 || g > 0.0 || b > 0.0) {
+                println!("warning: the stack has no coverage to crop to, keeping all of it");
+                return None;
+            }
+            // Channels without a median coverage, with too few frames, don't limit the crop.
+            let relative = |w: f64, median: f64| if median > 0.0 { w / median } else { f64::INFINITY };
+            let coverage = weights.map(|p| relative(p.r, r).min(relative(p.g, g)).min(relative(p.b, b)));
+            let bounds = footprint::crop_to_coverage(&coverage, min);
+            if bounds.2 == 0 || bounds.3 == 0 {
+                println!("warning: no part of the stack has {} of the median coverage, keeping all of it", min);
+                return None;
+            }
+            println!("cropping to {:?}", bounds);
+            Some(bounds)
+        }
+    }
+
+    pub struct Average {
+        pub registration: Registration,
     }
 
     impl StackMethod for Average {
-        fn stack(&self, stack: Option<Image<RgbBayer<f64>>>, img: Image<f64>, transform: Matrix3x3<f64>) -> Option<Image<RgbBayer<f64>>> {
+        fn registration(&self) -> &Registration {
+            &self.registration
+        }
+
+        fn stack(&self, stack: Option<Image<RgbBayer<f64>>>, img: Image<f64>, transform: Matrix3x3<f64>, weight: f64) -> Option<Image<RgbBayer<f64>>> {
              let img = img.to_rggb();
-             if let Some(mut stack) = stack {
-                 drizzle::add(&mut stack, &img, transform, 1.0, self.pixel_aperture, |_,_,_| true);
-                 Some(stack)
-             } else {
-                 Some(img)
-             }
+             Some(self.registration.add(stack, &img, transform, weight, |_,_,_| true))
         }
     }
 
     pub struct SigmaKappa {
-        pub pixel_aperture: f64,
+        pub registration: Registration,
         pub kappa: f64,
         pub average: Image<Rgb<f64>>,
     }
 
     impl StackMethod for SigmaKappa {
-        fn stack(&self, stack: Option<Image<RgbBayer<f64>>>, img: Image<f64>, transform: Matrix3x3<f64>) -> Option<Image<RgbBayer<f64>>> {
+        fn registration(&self) -> &Registration {
+            &self.registration
+        }
+
+        fn stack(&self, stack: Option<Image<RgbBayer<f64>>>, img: Image<f64>, transform: Matrix3x3<f64>, weight: f64) -> Option<Image<RgbBayer<f64>>> {
              let img = img.to_rggb();
-             if let Some(mut stack) = stack {
-                 drizzle::add(&mut stack, &img, transform, 1.0, self.pixel_aperture, |x, y, p| {
-                     let avg = self.average.pixel_at(x, y);
-                     (p.rc < 0.2 || (p.r / p.rc - avg.r).abs() < self.kappa) &&
-                     (p.gc < 0.2 || (p.g / p.gc - avg.g).abs() < self.kappa) &&
-                     (p.bc < 0.2 || (p.b / p.bc - avg.b).abs() < self.kappa)
-                 });
-                 Some(stack)
-             } else {
-                 Some(img)
-             }
+             Some(self.registration.add(stack, &img, transform, weight, |x, y, p| {
+                 let avg = self.average.pixel_at(x, y);
+                 (p.rc < 0.2 || (p.r / p.rc - avg.r).abs() < self.kappa) &&
+                 (p.gc < 0.2 || (p.g / p.gc - avg.g).abs() < self.kappa) &&
+                 (p.bc < 0.2 || (p.b / p.bc - avg.b).abs() < self.kappa)
+             }))
         }
     }
 }
@@ -0,0 +1,202 @@
+//! Runs the per-frame work on a pool of threads, handing the results to a single
+//! reducer as they finish.
+
+use std::fmt;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::sync_channel;
+use std::time::{Duration, Instant};
+use crossbeam;
+use crossbeam::sync::chase_lev;
+use num_cpus;
+use align_api::AlignedImage;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Pool {
+    pub threads: usize,
+    /// Results that may wait for the reducer. With one frame being worked on by each
+    /// thread, at most `threads + queue` frames are in memory at once.
+    pub queue: usize,
+}
+
+impl Pool {
+    /// Uses as many threads as there are CPUs unless `threads` is given.
+    pub fn new(threads: Option<usize>, queue: usize) -> Self {
+        Pool {
+            threads: threads.unwrap_or_else(num_cpus::get).max(1),
+            queue,
+        }
+    }
+}
+
+/// What progress and errors are reported against.
+pub trait Named {
+    fn name(&self) -> &str;
+}
+
+impl Named for AlignedImage {
+    fn name(&self) -> &str {
+        &self.filename
+    }
+}
+
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// `time` is how long the frame took on its thread, `eta` is estimated from the
+    /// overall rate so far. `note` is what the map function had to say about it.
+    Done { name: &'a str, done: usize, total: usize, time: Duration, eta: Duration, note: &'a str },
+    Failed { name: &'a str, done: usize, total: usize, error: &'a str },
+}
+
+impl<'a> fmt::Display for Event<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Done { name, done, total, time, eta, note } => {
+                write!(f, "{} of {}: {} took {:.1}s, {:.0}s left", done, total, name, secs(time), secs(eta))?;
+                if !note.is_empty() {
+                    write!(f, ", {}", note)?;
+                }
+                Ok(())
+            }
+            Event::Failed { name, done, total, error } =>
+                write!(f, "{} of {}: {} failed: {}", done, total, name, error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub name: String,
+    pub error: String,
+}
+
+/// Maps every item on the pool's threads and folds the results with `reduce`, starting from
+/// `init`, in the order they finish. `map` returns each mapped item with a note for the progress,
+/// or an error. An error or a panic while mapping an item is reported as a failure of that item;
+/// the others carry on. `map` is called once per thread to create its map function.
+pub fn for_each_image<Item, MapFnFactory, MapFn, MappedItem, ReduceFn, ReducedItem, ProgressFn>(
+    pool: &Pool, items: Vec<Item>, init: Option<ReducedItem>, map: MapFnFactory, reduce: ReduceFn, mut progress: ProgressFn)
+    -> (Option<ReducedItem>, Vec<Failure>)
+where
+    Item: Send + Named,
+    MappedItem: Send,
+    MapFnFactory: Fn() -> MapFn,
+    MapFn: Fn(Item) -> Result<(MappedItem, String), String>, MapFn: Send,
+    ReduceFn: Fn(Option<ReducedItem>, MappedItem) -> Option<ReducedItem>,
+    ProgressFn: FnMut(&Event)
+{
+    let (tx, rx) = sync_channel(pool.queue);
+    let (worker, stealer) = chase_lev::deque();
+    let total = items.len();
+    for item in items.into_iter() {
+        worker.push(item);
+    }
+
+    crossbeam::scope(|scope| {
+        for _ in 0..pool.threads {
+            let tx = tx.clone();
+            let stealer = stealer.clone();
+            let map = map();
+            scope.spawn(move || {
+                loop {
+                    let item = match stealer.steal() {
+                        chase_lev::Steal::Data(d) => d,
+                        chase_lev::Steal::Abort => continue,
+                        chase_lev::Steal::Empty => break
+                    };
+                    let name = item.name().to_string();
+                    let start = Instant::now();
+                    let mapped_item = panic::catch_unwind(AssertUnwindSafe(|| map(item)))
+                        .map_err(panic_message)
+                        .and_then(|result| result);
+                    // The reducer only goes away if it panicked itself.
+                    if tx.send((name, start.elapsed(), mapped_item)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let start = Instant::now();
+        let mut failures = Vec::new();
+        let mut acc = init;
+        for (done, (name, time, mapped_item)) in rx.iter().enumerate().map(|(i, r)| (i + 1, r)) {
+            match mapped_item {
+                Ok((mapped_item, note)) => {
+                    acc = reduce(acc, mapped_item);
+                    let eta = start.elapsed() / done as u32 * (total - done) as u32;
+                    progress(&Event::Done { name: &name, done, total, time, eta, note: &note });
+                }
+                Err(error) => {
+                    progress(&Event::Failed { name: &name, done, total, error: &error });
+                    failures.push(Failure { name, error });
+                }
+            }
+        }
+        (acc, failures)
+    })
+}
+
+/// Prints the frames that failed, if any.
+pub fn report(failures: &[Failure]) {
+    if failures.is_empty() {
+        return;
+    }
+    println!("{} frames failed and were left out:", failures.len());
+    for failure in failures {
+        println!("  {}: {}", failure.name, failure.error);
+    }
+}
+
+pub fn panic_message(payload: Box<Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "panicked".to_string()
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Frame(usize, String);
+
+    impl Named for Frame {
+        fn name(&self) -> &str {
+            &self.1
+        }
+    }
+
+    #[test]
+    fn bad_frame_is_left_out() {
+        let frames = (0..10).map(|i| Frame(i, format!("{}.cr2", i))).collect();
+        let mut events = 0;
+        let (sum, failures) = for_each_image(
+            &Pool::new(Some(3), 1),
+            frames,
+            None,
+            || |frame: Frame| {
+                assert!(frame.0 != 4, "can't read {}", frame.1);
+                if frame.0 == 7 {
+                    return Err("no stars".to_string());
+                }
+                Ok((frame.0, String::new()))
+            },
+            |sum, i| Some(sum.unwrap_or(0) + i),
+            |_| events += 1);
+        assert_eq!(sum, Some(45 - 4 - 7));
+        assert_eq!(events, 10);
+        assert_eq!(failures.len(), 2);
+        let mut failures: Vec<_> = failures.iter().map(|f| (f.name.as_str(), f.error.as_str())).collect();
+        failures.sort();
+        assert_eq!(failures, vec![("4.cr2", "can't read 4.cr2"), ("7.cr2", "no stars")]);
+    }
+}
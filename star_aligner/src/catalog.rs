@@ -0,0 +1,180 @@
+//! Star lists cached in a sidecar file next to each image (`IMG_1234.CR2.stars.json`).
+//!
+//! The sidecar holds every detected star, sorted by flux, so changing `max_stars`
+//! or the matching threshold doesn't require running the detector again.
+//! It's only used if both the image contents and the detector settings match. The contents are
+//! only hashed again when the image's size or modification time changed.
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use align_api::FileStamp;
+use geom::Point;
+use serde_json;
+use sextractor;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StarCatalog {
+    /// Hash of the image file contents.
+    pub hash: String,
+    /// Size and modification time of the image when it was hashed.
+    #[serde(default)]
+    pub stamp: Option<FileStamp>,
+    /// Hash of the detector configuration.
+    pub detector: String,
+    /// All detected stars, brightest first.
+    pub stars: Vec<Point<f64>>,
+}
+
+pub fn sidecar_path<P: AsRef<Path>>(image: P) -> PathBuf {
+    let mut name = image.as_ref().as_os_str().to_owned();
+    name.push(".stars.json");
+    PathBuf::from(name)
+}
+
+/// Identifies the detector settings that produced a star list.
+pub fn detector_settings() -> String {
+    let mut h = Fnv::new();
+    h.write(sextractor::DEFAULT_SEX);
+    h.write(sextractor::DEFAULT_PARAM);
+    format!("sextractor-{:016x}", h.finish())
+}
+
+pub fn hash_file<P: AsRef<Path>>(path: P) -> String {
+    let mut r = BufReader::new(File::open(path).unwrap());
+    let mut h = Fnv::new();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let len = r.read(&mut buf).unwrap();
+        if len == 0 {
+            break;
+        }
+        h.write(&buf[..len]);
+    }
+    format!("{:016x}", h.finish())
+}
+
+/// Returns the cached stars for `image`, if the sidecar exists and is still valid.
+pub fn load<P: AsRef<Path>>(image: P) -> Option<StarCatalog> {
+    let path = sidecar_path(&image);
+    let f = match File::open(&path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => panic!("failed to open {:?}: {}", path, e)
+    };
+    let catalog: StarCatalog = match serde_json::from_reader(BufReader::new(f)) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("ignoring unreadable star catalog {:?}: {}", path, e);
+            return None;
+        }
+    };
+    if catalog.detector != detector_settings() {
+        info!("detector settings changed, ignoring {:?}", path);
+        return None;
+    }
+    if catalog.stamp == Some(FileStamp::of(&image)) {
+        return Some(catalog);
+    }
+    if catalog.hash != hash_file(&image) {
+        info!("image changed, ignoring {:?}", path);
+        return None;
+    }
+    // Same contents, touched or copied: restamp so it isn't hashed again next time.
+    save(&image, &catalog.stars);
+    Some(catalog)
+}
+
+/// Writes the sidecar of `image`. Failing to, for example in a read-only directory, only
+/// means the stars will be detected again next time.
+pub fn save<P: AsRef<Path>>(image: P, stars: &[Point<f64>]) {
+    let catalog = StarCatalog {
+        hash: hash_file(&image),
+        stamp: Some(FileStamp::of(&image)),
+        detector: detector_settings(),
+        stars: stars.to_vec(),
+    };
+    let path = sidecar_path(&image);
+    let result = File::create(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|f| serde_json::to_writer(&mut BufWriter::new(f), &catalog).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        warn!("couldn't write star catalog {:?}: {}", path, e);
+    }
+}
+
+/// Returns the `max_count` brightest stars of `image`, from its sidecar if possible.
+/// Otherwise runs `detect`, which must return all stars sorted by flux, and caches the result.
+pub fn load_or_detect<P, F>(image: P, max_count: usize, detect: F) -> Vec<Point<f64>>
+where P: AsRef<Path>, F: FnOnce() -> Vec<Point<f64>> {
+    let mut stars = if let Some(catalog) = load(&image) {
+        catalog.stars
+    } else {
+        let stars = detect();
+        save(&image, &stars);
+        stars
+    };
+    stars.truncate(max_count);
+    stars
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher`, it's stable across compiler versions,
+/// so the hashes can be written to disk.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes.iter() {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn write_file(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn test_fnv() {
+        let mut h = Fnv::new();
+        h.write(b"a");
+        assert_eq!(h.finish(), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let image = env::temp_dir().join("star_aligner_catalog_test.fits");
+        write_file(&image, b"not really a fits file");
+        let stars = vec![Point { x: 1.0, y: 2.0 }, Point { x: 3.0, y: 4.0 }];
+        save(&image, &stars);
+        assert_eq!(load(&image).unwrap().stars, stars);
+        assert_eq!(load_or_detect(&image, 1, || panic!("should use the sidecar")), &stars[..1]);
+
+        write_file(&image, b"a different image");
+        assert_eq!(load(&image), None);
+        fs::remove_file(sidecar_path(&image)).unwrap();
+
+        // When the sidecar can't be written, the stars are still returned.
+        fs::create_dir(sidecar_path(&image)).unwrap();
+        assert_eq!(load_or_detect(&image, 1, || stars.clone()), &stars[..1]);
+        fs::remove_dir(sidecar_path(&image)).unwrap();
+
+        fs::remove_file(&image).unwrap();
+    }
+}
@@ -4,18 +4,24 @@
 
 extern crate sextractor;
 extern crate geom;
+extern crate align_api;
 extern crate imagemagick;
 extern crate simd;
 //extern crate ndarray;
 //extern crate ndarray_linalg;
 extern crate rulinalg;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate tempfile;
 #[macro_use] extern crate log;
-#[cfg(test)] extern crate serde_json;
 #[cfg(test)] extern crate test;
 
 mod rigid_body;
+pub mod catalog;
 
 use std::path::Path;
+use std::process::Command;
 use std::f64;
 use std::iter;
 use geom::{Point, Matrix3x3};
@@ -65,8 +71,9 @@ pub struct Reference {
 }
 
 impl Reference {
+    /// Uses the star catalog sidecar of `path` if it's present and valid.
     pub fn from_image<P: AsRef<Path>>(path: P, options: Options) -> Self {
-        let stars = extract(path, options.max_stars);
+        let stars = extract_cached(path, options.max_stars);
         Self::from_stars(stars, options)
     }
 
@@ -79,8 +86,9 @@ impl Reference {
         }
     }
 
+    /// Uses the star catalog sidecar of `sample` if it's present and valid.
     pub fn align_image<P: AsRef<Path>>(&self, sample: P) -> Option<Matrix3x3<f64>> {
-        self.align_stars(&extract(sample, self.options.max_stars))
+        self.align_stars(&extract_cached(sample, self.options.max_stars))
     }
 
     pub fn align_stars(&self, sample_objects: &[Point<f64>]) -> Option<Matrix3x3<f64>> {
@@ -123,17 +131,52 @@ impl Reference {
 }
 
 pub fn extract<P: AsRef<Path>>(path: P, max_count: usize) -> Vec<Point<f64>> {
+    let mut stars = extract_all(path);
+    stars.truncate(max_count);
+    stars
+}
+
+/// Returns all stars in the image, brightest first.
+pub fn extract_all<P: AsRef<Path>>(path: P) -> Vec<Point<f64>> {
     let image_info = imagemagick::identify(path.as_ref());
     let mut objects = sextractor::extract(path);
     // sort by flux, descending
     objects.sort_by(|a,b| b.flux.partial_cmp(&a.flux).unwrap());
     objects
         .into_iter()
-        .take(max_count)
         .map(|o| Point { x: o.x as f64, y: image_info.height as f64 - o.y as f64 })
         .collect()
 }
 
+/// Like `extract`, but reads and writes the star catalog sidecar of `path`.
+pub fn extract_cached<P: AsRef<Path>>(path: P, max_count: usize) -> Vec<Point<f64>> {
+    catalog::load_or_detect(&path, max_count, || extract_all(&path))
+}
+
+/// Calls `f` with a temporary grayscale FITS copy of `src`, which can be any format
+/// ImageMagick reads, raw files included.
+pub fn with_fits<F,R,P>(src: P, mut f: F) -> R
+where P: AsRef<Path>, F: FnMut(&Path) -> R {
+    let out = tempfile::NamedTempFileOptions::new().suffix(".fits").create().unwrap();
+    let status = Command::new("convert")
+        .arg(src.as_ref())
+        .arg("-colorspace")
+        .arg("gray")
+        .arg(out.path())
+        .status()
+        .expect("failed to run convert");
+    assert!(status.success());
+    f(out.path())
+}
+
+/// Reads the stars from the sidecar next to `filename`, only converting
+/// the image to FITS if they need to be detected again.
+pub fn stars<P: AsRef<Path>>(filename: P, max_stars: usize) -> Vec<Point<f64>> {
+    catalog::load_or_detect(&filename, max_stars, || {
+        with_fits(&filename, |fits_filename| extract_all(fits_filename))
+    })
+}
+
 
 #[inline]
 fn angle(stars: [Point<f64>; 3]) -> f64 {
@@ -1,126 +1,325 @@
+//! Forward-mapping drizzle (Fruchter & Hook, 2002).
+//!
+//! Each input pixel is shrunk by `pixel_aperture` (pixfrac) into a "drop", mapped onto
+//! the output grid, and its value is added to every output pixel it lands on, weighted
+//! by how much of the drop landed there. A drop that lands entirely inside the output
+//! contributes a total weight of 1, so the accumulated weight is the number of input
+//! pixels that contributed to an output pixel.
+//!
+//! `transform` maps reference coordinates to sample coordinates, like the transforms
+//! in the alignment file. Pixel `(x, y)` covers `[x, x + 1) × [y, y + 1)`.
+
 use std::default::Default;
+use std::fmt::Display;
 use std::ops::{AddAssign, DivAssign, Mul};
+use std::str::FromStr;
 use image::Image;
-use geom::{Point, Matrix3x3, Matrix3x1};
+use geom::{Point, Matrix3x3};
 use num::{Float, FromPrimitive};
 
-#[inline(always)]
-fn positive<T: Float>(v: T) -> T {
-    if v > T::zero() { v } else { T::zero() }
+/// The shape of the drop.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Kernel {
+    /// The input pixel shrunk by pixfrac, transformed exactly (including rotation).
+    Square,
+    /// The whole value goes to the output pixel under the drop's center.
+    Point,
+    /// A gaussian with a FWHM of pixfrac input pixels.
+    Gaussian,
+    /// Like `Square`, but ignores rotation. Faster, and good enough for small angles.
+    Turbo,
+}
+
+impl FromStr for Kernel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(Kernel::Square),
+            "point" => Ok(Kernel::Point),
+            "gaussian" => Ok(Kernel::Gaussian),
+            "turbo" => Ok(Kernel::Turbo),
+            _ => Err(format!("unknown kernel: {} (expected square, point, gaussian or turbo)", s))
+        }
+    }
 }
 
 pub struct ImageStack<P> {
     image: Image<P>,
-    count: usize,
+    weights: Image<f32>,
     factor: f32,
     pixel_aperture: f32,
+    kernel: Kernel,
 }
 
 impl<P: Copy + Clone + AddAssign + DivAssign<f32> + Mul<f32, Output=P> + Default> ImageStack<P> {
-    pub fn new(width: usize, height: usize, factor: f32, pixel_aperture: f32) -> Self {
-        let w = (width as f32 * factor) as usize;
-        let h = (height as f32 * factor) as usize;
+    /// `width` and `height` are the size of the reference image; the output is `factor` times bigger.
+    pub fn new(width: usize, height: usize, factor: f32, pixel_aperture: f32, kernel: Kernel) -> Self {
+        let w = (width as f32 * factor).round() as usize;
+        let h = (height as f32 * factor).round() as usize;
         ImageStack {
             image: Image::new(w, h),
-            count: 0,
+            weights: Image::new(w, h),
             factor: factor,
             pixel_aperture: pixel_aperture,
+            kernel: kernel,
         }
     }
 
     pub fn add(&mut self, image: &Image<P>, transform: Matrix3x3<f32>) {
-        add(&mut self.image, image, transform, self.factor, self.pixel_aperture, |_,_,_| true);
-        self.count += 1;
+        let (w, h) = (self.image.width, self.image.height);
+        let stack = &mut self.image;
+        let weights = &mut self.weights;
+        drizzle(image, transform, w, h, self.factor, self.pixel_aperture, self.kernel, |x, y, p, weight| {
+            *stack.pixel_at_mut(x, y) += p * weight;
+            *weights.pixel_at_mut(x, y) += weight;
+        });
     }
 
-    pub fn finish(mut self) -> Image<P> {
-        let count = self.count as f32;
-        for pixel in self.image.pixels.iter_mut() {
-            *pixel /= count;
+    /// Returns the weighted average, and the weight of each output pixel.
+    /// Pixels with no weight are left at zero.
+    pub fn finish(mut self) -> (Image<P>, Image<f32>) {
+        for (pixel, &weight) in self.image.pixels.iter_mut().zip(self.weights.pixels.iter()) {
+            if weight > 0.0 {
+                *pixel /= weight;
+            }
         }
-        self.image
+        (self.image, self.weights)
     }
+}
 
+/// Returns an empty output image for drizzling images the size of `image` with `factor`.
+pub fn canvas<P, Q, F>(image: &Image<Q>, factor: F) -> Image<P>
+where P: Copy + Default, F: Float {
+    let w = (F::from(image.width).unwrap() * factor).round().to_usize().unwrap();
+    let h = (F::from(image.height).unwrap() * factor).round().to_usize().unwrap();
+    Image::new(w, h)
 }
 
+/// Drizzles `image` onto `stack`. The weight is carried by the pixel type
+/// (see `RgbBayer`), so each drop is added as `pixel * weight`.
+/// Drops for which `filter` returns false are left out.
 pub fn add<P,F,FilterFn>(
     stack: &mut Image<P>,
     image: &Image<P>,
     transform: Matrix3x3<F>,
     factor: F,
     pixel_aperture: F,
+    kernel: Kernel,
     filter: FilterFn
 )
 where
-    P: Copy + Clone + AddAssign + DivAssign<F> + Mul<F, Output=P> + Default,
-    F: Float + FromPrimitive,
+    P: Copy + Clone + AddAssign + Mul<F, Output=P> + Default,
+    F: Float + FromPrimitive + Display,
     FilterFn: Fn(usize, usize, P) -> bool
 {
-    for y in 0..stack.height {
-        for x in 0..stack.width {
-            let src_pos = transform * Point {
-                x: F::from_usize(x).unwrap() / factor,
-                y: F::from_usize(y).unwrap() / factor
-            };
-            let dst_pixel = stack.pixel_at_mut(x, y);
-            let src_pixel = resample(image, src_pos.x, src_pos.y, factor,
-                                                       pixel_aperture);
-            if filter(x, y, src_pixel) {
-                *dst_pixel += src_pixel;
+    let (w, h) = (stack.width, stack.height);
+    drizzle(image, transform, w, h, factor, pixel_aperture, kernel, |x, y, p, weight| {
+        let p = p * weight;
+        if filter(x, y, p) {
+            *stack.pixel_at_mut(x, y) += p;
+        }
+    });
+}
+
+/// Calls `deposit(x, y, pixel, weight)` for every output pixel that each
+/// pixel of `image` lands on, within a `width` × `height` output.
+pub fn drizzle<P,F,DropFn>(
+    image: &Image<P>,
+    transform: Matrix3x3<F>,
+    width: usize,
+    height: usize,
+    factor: F,
+    pixel_aperture: F,
+    kernel: Kernel,
+    mut deposit: DropFn
+)
+where
+    P: Copy,
+    F: Float + FromPrimitive + Display,
+    DropFn: FnMut(usize, usize, P, F)
+{
+    let inverse = transform.inverse();
+    let to_output = |x: F, y: F| {
+        let p = inverse * Point { x: x, y: y };
+        Point { x: p.x * factor, y: p.y * factor }
+    };
+    let half = F::from_f64(0.5).unwrap();
+    let margin = (F::one() - pixel_aperture) * half;
+    // linear size of an input pixel in the output, in case the transform scales
+    let scale = (inverse.v11 * inverse.v22 - inverse.v12 * inverse.v21).abs().sqrt() * factor;
+    let drop_size = pixel_aperture * scale;
+    let bounds = Bounds { width: width, height: height };
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let p = *image.pixel_at(x, y);
+            let x = F::from_usize(x).unwrap();
+            let y = F::from_usize(y).unwrap();
+            match kernel {
+                Kernel::Square => {
+                    let (x1, y1) = (x + margin, y + margin);
+                    let (x2, y2) = (x + F::one() - margin, y + F::one() - margin);
+                    let quad = [to_output(x1, y1), to_output(x2, y1), to_output(x2, y2), to_output(x1, y2)];
+                    let area = polygon_area(&quad);
+                    for_each_covered(&bounds, &quad, |ox, oy| {
+                        let a = overlap(&quad, F::from_usize(ox).unwrap(), F::from_usize(oy).unwrap());
+                        if a > F::zero() {
+                            deposit(ox, oy, p, a / area);
+                        }
+                    });
+                }
+                Kernel::Turbo => {
+                    let c = to_output(x + half, y + half);
+                    let r = drop_size * half;
+                    let area = drop_size * drop_size;
+                    let quad = [
+                        Point { x: c.x - r, y: c.y - r },
+                        Point { x: c.x + r, y: c.y - r },
+                        Point { x: c.x + r, y: c.y + r },
+                        Point { x: c.x - r, y: c.y + r },
+                    ];
+                    for_each_covered(&bounds, &quad, |ox, oy| {
+                        let ox_f = F::from_usize(ox).unwrap();
+                        let oy_f = F::from_usize(oy).unwrap();
+                        let w = (c.x + r).min(ox_f + F::one()) - (c.x - r).max(ox_f);
+                        let h = (c.y + r).min(oy_f + F::one()) - (c.y - r).max(oy_f);
+                        if w > F::zero() && h > F::zero() {
+                            deposit(ox, oy, p, w * h / area);
+                        }
+                    });
+                }
+                Kernel::Point => {
+                    let c = to_output(x + half, y + half);
+                    if let Some((ox, oy)) = bounds.pixel(c.x, c.y) {
+                        deposit(ox, oy, p, F::one());
+                    }
+                }
+                Kernel::Gaussian => {
+                    let c = to_output(x + half, y + half);
+                    // FWHM = 2.3548 sigma
+                    let sigma = drop_size / F::from_f64(2.3548).unwrap();
+                    let r = (sigma * F::from_f64(3.0).unwrap()).max(half);
+                    let quad = [
+                        Point { x: c.x - r, y: c.y - r },
+                        Point { x: c.x + r, y: c.y - r },
+                        Point { x: c.x + r, y: c.y + r },
+                        Point { x: c.x - r, y: c.y + r },
+                    ];
+                    let gauss = |dx: F, dy: F| {
+                        (-(dx * dx + dy * dy) / (sigma * sigma * (F::one() + F::one()))).exp()
+                    };
+                    let at = |ox: usize, oy: usize| {
+                        gauss(F::from_usize(ox).unwrap() + half - c.x, F::from_usize(oy).unwrap() + half - c.y)
+                    };
+                    // normalize over the whole footprint, including the part outside the output
+                    let mut total = F::zero();
+                    let mut oy = (c.y - r).floor();
+                    while oy < c.y + r {
+                        let mut ox = (c.x - r).floor();
+                        while ox < c.x + r {
+                            total = total + gauss(ox + half - c.x, oy + half - c.y);
+                            ox = ox + F::one();
+                        }
+                        oy = oy + F::one();
+                    }
+                    if total > F::zero() {
+                        for_each_covered(&bounds, &quad, |ox, oy| {
+                            deposit(ox, oy, p, at(ox, oy) / total);
+                        });
+                    }
+                }
             }
         }
     }
 }
 
-fn resample<P,F>(image: &Image<P>, x: F, y: F, factor: F, pixel_aperture: F) -> P
-where P: Copy + Clone + AddAssign + DivAssign<F> + Mul<F, Output=P> + Default, F: Float {
-    // `src` refers to `image`, `dst` refers to `self.image`.
-    // `x` and `y` above are the origin of the `dst` pixel in the `src` coordinate system.
-
-    let mut src_val: P = Default::default();
-    let two = F::one() + F::one();
-    let src_margin = (F::one() - pixel_aperture) / two; // margin around src pixel
-    let dst_size = F::one() / factor; // width & height of dst pixel (in src coords)
-
-    // east, or right
-    let e = positive((x + dst_size) - x.ceil() - src_margin);
-    // south, or bottom
-    let s = positive((y + dst_size) - y.ceil() - src_margin);
-    // west, or left
-    let w = positive((x + dst_size).min(x.ceil()) - x - src_margin);
-    // north, or top
-    let n = positive((y + dst_size).min(y.ceil()) - y - src_margin);
-
-    // areas of intersection of the four `src` pixels with the `dst` pixel.
-    let nw = n * w;
-    let se = s * e;
-    let sw = s * w;
-    let ne = n * e;
-
-    // integer coords of the four `src` pixels
-    let e_x = x.ceil().to_isize().unwrap();
-    let s_y = y.ceil().to_isize().unwrap();
-    let w_x = e_x - 1;
-    let n_y = s_y - 1;
-
-    let w = image.width as isize;
-    let h = image.height as isize;
-
-    // if the `src` pixel exists, add its weighted value to `src_val`
-    if n_y >= 0 && n_y < h && w_x >= 0 && w_x < w {
-        src_val += *image.pixel_at(w_x as usize, n_y as usize) * nw;
+struct Bounds {
+    width: usize,
+    height: usize,
+}
+
+impl Bounds {
+    fn pixel<F: Float>(&self, x: F, y: F) -> Option<(usize, usize)> {
+        if x < F::zero() || y < F::zero() {
+            return None;
+        }
+        let (x, y) = (x.to_usize().unwrap(), y.to_usize().unwrap());
+        if x < self.width && y < self.height { Some((x, y)) } else { None }
+    }
+}
+
+/// Calls `f` for every output pixel in the bounding box of `poly`.
+/// Pixels left of or above the output edge (negative coords) are skipped.
+fn for_each_covered<F: Float, Func: FnMut(usize, usize)>(bounds: &Bounds, poly: &[Point<F>], mut f: Func) {
+    let min_x = poly.iter().fold(F::infinity(), |acc, p| acc.min(p.x)).floor().max(F::zero());
+    let min_y = poly.iter().fold(F::infinity(), |acc, p| acc.min(p.y)).floor().max(F::zero());
+    let max_x = poly.iter().fold(F::neg_infinity(), |acc, p| acc.max(p.x)).ceil();
+    let max_y = poly.iter().fold(F::neg_infinity(), |acc, p| acc.max(p.y)).ceil();
+    if max_x <= F::zero() || max_y <= F::zero() {
+        return;
     }
-    if n_y >= 0 && n_y < h && e_x >= 0 && e_x < w {
-        src_val += *image.pixel_at(e_x as usize, n_y as usize) * ne;
+    let x1 = min_x.to_usize().unwrap();
+    let y1 = min_y.to_usize().unwrap();
+    let x2 = max_x.to_usize().unwrap().min(bounds.width);
+    let y2 = max_y.to_usize().unwrap().min(bounds.height);
+    for oy in y1..y2 {
+        for ox in x1..x2 {
+            f(ox, oy);
+        }
     }
-    if s_y >= 0 && s_y < h && e_x >= 0 && e_x < w {
-        src_val += *image.pixel_at(e_x as usize, s_y as usize) * se;
+}
+
+fn polygon_area<F: Float>(poly: &[Point<F>]) -> F {
+    let mut sum = F::zero();
+    for i in 0..poly.len() {
+        let a = poly[i];
+        let b = poly[(i + 1) % poly.len()];
+        sum = sum + a.x * b.y - b.x * a.y;
     }
-    if s_y >= 0 && s_y < h && w_x >= 0 && w_x < w {
-        src_val += *image.pixel_at(w_x as usize, s_y as usize) * sw;
+    (sum / (F::one() + F::one())).abs()
+}
+
+/// Area of the intersection of the convex polygon `poly` with the
+/// output pixel at `(x, y)`, using Sutherland–Hodgman clipping.
+fn overlap<F: Float>(poly: &[Point<F>; 4], x: F, y: F) -> F {
+    // clipping a quad against 4 edges adds at most 4 vertices
+    let mut a = [Point { x: F::zero(), y: F::zero() }; 8];
+    let mut b = a;
+    a[..4].copy_from_slice(&poly[..]);
+    let mut len = 4;
+    len = clip(&a[..len], &mut b, |p| p.x - x);
+    len = clip(&b[..len], &mut a, |p| x + F::one() - p.x);
+    len = clip(&a[..len], &mut b, |p| p.y - y);
+    len = clip(&b[..len], &mut a, |p| y + F::one() - p.y);
+    if len < 3 {
+        return F::zero();
     }
+    polygon_area(&a[..len])
+}
 
-    src_val
+/// Keeps the part of `src` where `dist(p) >= 0`, writing it to `dst`.
+fn clip<F, DistFn>(src: &[Point<F>], dst: &mut [Point<F>; 8], dist: DistFn) -> usize
+where F: Float, DistFn: Fn(Point<F>) -> F {
+    let mut len = 0;
+    for i in 0..src.len() {
+        let cur = src[i];
+        let prev = src[(i + src.len() - 1) % src.len()];
+        let (d_cur, d_prev) = (dist(cur), dist(prev));
+        if (d_cur >= F::zero()) != (d_prev >= F::zero()) {
+            let t = d_prev / (d_prev - d_cur);
+            dst[len] = Point {
+                x: prev.x + (cur.x - prev.x) * t,
+                y: prev.y + (cur.y - prev.y) * t,
+            };
+            len += 1;
+        }
+        if d_cur >= F::zero() {
+            dst[len] = cur;
+            len += 1;
+        }
+    }
+    len
 }
 
 #[cfg(test)]
@@ -129,43 +328,39 @@ mod tests {
     use super::*;
     use image::Image;
 
-    fn run_resample_test(pixels: Vec<f32>, x: f32, y: f32, expected: f32) {
-        run_resample_test_with_factor(1.0, 1.0, pixels, x, y, expected);
-    }
-
-    fn run_resample_test_with_factor(factor: f32, pixel_aperture: f32, pixels: Vec<f32>, x: f32, y: f32, expected: f32) {
-        let image = Image {
+    fn image() -> Image<f32> {
+        Image {
             width: 3,
             height: 3,
-            pixels: pixels,
-        };
-        let mut stack = ImageStack::new(3, 3, factor, pixel_aperture);
-        stack.add(&image, Vector { x: -x, y: -y });
-        let v = *stack.into_image().pixel_at(0, 0);
-        assert_eq!(v, expected);
-    }
-
-    #[test]
-    fn test_1() {
-        run_resample_test(
-            vec![
+            pixels: vec![
                 0.5, 0.5, 0.5,
                 0.5, 1.0, 0.5,
                 0.5, 0.5, 0.5,
             ],
-            1.0, 1.0,
-            1.0
-        );
+        }
+    }
+
+    /// Returns the unnormalized value of output pixel (0, 0) when the reference
+    /// pixel (0, 0) is at `(x, y)` in the sample.
+    fn run_resample_test(x: f32, y: f32, expected: f32) {
+        run_resample_test_with_factor(1.0, 1.0, Kernel::Square, x, y, expected);
+    }
+
+    fn run_resample_test_with_factor(factor: f32, pixel_aperture: f32, kernel: Kernel, x: f32, y: f32, expected: f32) {
+        let mut stack = canvas(&image(), factor);
+        add(&mut stack, &image(), Matrix3x3::translation(x, y), factor, pixel_aperture, kernel, |_,_,_| true);
+        let v = *stack.pixel_at(0, 0);
+        assert!((v - expected).abs() < 1e-6, "{} != {}", v, expected);
+    }
+
+    #[test]
+    fn test_1() {
+        run_resample_test(1.0, 1.0, 1.0);
     }
 
     #[test]
     fn test_2() {
         run_resample_test(
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ],
             0.75, 0.75,
             (0.75 * 0.75 * 1.0) + (0.75 * 0.25 * 2.0 * 0.5) + (0.25 * 0.25 * 0.5)
         );
@@ -173,32 +368,13 @@ mod tests {
 
     #[test]
     fn test_edge() {
-        run_resample_test(
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ],
-            -0.75, -0.75,
-            0.25 * 0.25 * 0.5
-        );
+        run_resample_test(-0.75, -0.75, 0.25 * 0.25 * 0.5);
     }
 
-
     #[test]
     fn test_factor() {
         let run = |x, y, expected| {
-            run_resample_test_with_factor(
-                2.0,
-                1.0,
-                vec![
-                    0.5, 0.5, 0.5,
-                    0.5, 1.0, 0.5,
-                    0.5, 0.5, 0.5,
-                ],
-                x, y,
-                expected
-            );
+            run_resample_test_with_factor(2.0, 1.0, Kernel::Square, x, y, expected);
         };
         run(-3.0, -3.0, 0.0);
         run(0.0, 0.0, 0.125);
@@ -215,144 +391,72 @@ mod tests {
 
     #[test]
     fn small_pixel() {
-        let run = |x, y, expected| {
-            run_resample_test_with_factor(
-                2.0,
-                0.5,
-                vec![
-                    0.5, 0.5, 0.5,
-                    0.5, 1.0, 0.5,
-                    0.5, 0.5, 0.5,
-                ],
-                x, y,
-                expected
-            );
-        };
-        run(1.0, 1.0, 0.25 / 4.0);
-    }
-
-    #[bench]
-    fn bench_stack(b: &mut Bencher) {
-        let image = Image {
-            width: 3,
-            height: 3,
-            pixels: vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ]
-        };
-        b.iter(|| {
-            let mut stack = ImageStack::new(3, 3, 1.0, 1.0);
-            stack.add(&image, Vector { x: 0.5, y: 0.5 });
-            stack.into_image()
-        });
+        // the drop of the center pixel is one output pixel big, and lands on (0.5, 0.5);
+        // a quarter of it lands on (0, 0)
+        run_resample_test_with_factor(2.0, 0.5, Kernel::Square, 1.0, 1.0, 0.25);
     }
 
-    //#[bench]
-    //fn bench_resample(b: &mut Bencher) {
-        //let image = Image {
-            //width: 3,
-            //height: 3,
-            //pixels: vec![
-                //0.5, 0.5, 0.5,
-                //0.5, 1.0, 0.5,
-                //0.5, 0.5, 0.5,
-            //]
-        //};
-        //b.iter(|| {
-            //resample(&image, 1.0, 1.0);
-        //});
-    //}
-
-    fn run_stack_test(pixels: Vec<f32>, x: f32, y: f32, expected: Vec<f32>) {
-        let image: Image<f32> = Image {
-            width: 3,
-            height: 3,
-            pixels: pixels,
-        };
-        let mut stacker = ImageStack::new(3, 3, 1.0, 1.0);
-        stacker.add(&image, Vector {x: x, y: y});
-        assert_eq!(stacker.into_image().pixels, expected);
+    #[test]
+    fn turbo_matches_square_without_rotation() {
+        run_resample_test_with_factor(1.0, 1.0, Kernel::Turbo, 0.75, 0.75,
+            (0.75 * 0.75 * 1.0) + (0.75 * 0.25 * 2.0 * 0.5) + (0.25 * 0.25 * 0.5));
+        run_resample_test_with_factor(2.0, 0.5, Kernel::Turbo, 1.0, 1.0, 0.25);
     }
 
-    fn run_stack_test_2(pixels1: Vec<f32>, x1: f32, y1: f32, pixels2: Vec<f32>, x2: f32, y2: f32, expected: Vec<f32>) {
-        let mut stacker = ImageStack::new(3, 3, 1.0, 1.0);
-        let image1: Image<f32> = Image {
-            width: 3,
-            height: 3,
-            pixels: pixels1,
-        };
-        stacker.add(&image1, Vector {x: x1, y: y1});
-        let image2: Image<f32> = Image {
-            width: 3,
-            height: 3,
-            pixels: pixels2,
-        };
-        stacker.add(&image2, Vector {x: x2, y: y2});
-        assert_eq!(stacker.into_image().pixels, expected);
+    #[test]
+    fn point_kernel() {
+        run_resample_test_with_factor(1.0, 1.0, Kernel::Point, 0.75, 0.75, 1.0);
+        run_resample_test_with_factor(1.0, 1.0, Kernel::Point, 0.25, 0.25, 0.5);
     }
 
     #[test]
-    fn test_stack_1() {
-        run_stack_test(
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ],
-            0.0, 0.0,
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ]
-        );
+    fn weights_sum_to_one() {
+        let image = Image { width: 1, height: 1, pixels: vec![1.0f64] };
+        let transform = Matrix3x3::translation(-4.3, -4.1) * Matrix3x3::rotation(0.3);
+        for &kernel in [Kernel::Square, Kernel::Point, Kernel::Gaussian, Kernel::Turbo].iter() {
+            let mut total = 0.0;
+            drizzle(&image, transform, 30, 30, 3.0, 0.7, kernel, |_, _, _, w| total += w);
+            assert!((total - 1.0).abs() < 1e-9, "{:?}: {}", kernel, total);
+        }
     }
 
     #[test]
-    fn test_stack_2() {
-        run_stack_test_2(
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ],
-            0.0, 0.0,
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ],
-            0.0, 0.0,
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ]
-        );
+    fn overlap_rotated() {
+        // a unit square rotated by 45 degrees, centered on the pixel
+        let h = 0.5f64.sqrt();
+        let quad = [
+            Point { x: 0.5, y: 0.5 - h },
+            Point { x: 0.5 + h, y: 0.5 },
+            Point { x: 0.5, y: 0.5 + h },
+            Point { x: 0.5 - h, y: 0.5 },
+        ];
+        // each tip sticks out of the pixel as a triangle of area `tip`
+        let tip = (h - 0.5) * (h - 0.5);
+        assert!((overlap(&quad, 0.0, 0.0) - (1.0 - 4.0 * tip)).abs() < 1e-9);
+        assert!((overlap(&quad, 1.0, 0.0) - tip).abs() < 1e-9);
+        assert_eq!(overlap(&quad, 1.0, 1.0), 0.0);
     }
 
     #[test]
     fn test_stack_3() {
-        run_stack_test_2(
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ],
-            0.0, 0.0,
-            vec![
-                0.5, 0.5, 0.5,
-                0.5, 1.0, 0.5,
-                0.5, 0.5, 0.5,
-            ],
-            0.5, 0.5,
-            vec![
-                0.3125, 0.375, 0.375,
-                0.375, 0.8125, 0.5625,
-                0.375, 0.5625, 0.5625,
-            ]
-        );
+        let mut stack = ImageStack::new(3, 3, 1.0, 1.0, Kernel::Square);
+        stack.add(&image(), Matrix3x3::identity());
+        stack.add(&image(), Matrix3x3::translation(0.5, 0.5));
+        let (image, weights) = stack.finish();
+        // the shifted frame puts a quarter of pixels (1,1), (2,1), (1,2) and (2,2) on (1,1)
+        assert!((image.pixel_at(1, 1) - (1.0 + 0.625) / 2.0).abs() < 1e-6);
+        assert!((weights.pixel_at(1, 1) - 2.0).abs() < 1e-6);
+        assert!((weights.pixel_at(2, 2) - 1.25).abs() < 1e-6);
+    }
+
+    #[bench]
+    fn bench_stack(b: &mut Bencher) {
+        let image = Image::<f32>::new(300, 300);
+        let transform = Matrix3x3::translation(0.5, 0.5) * Matrix3x3::rotation(0.01);
+        b.iter(|| {
+            let mut stack = ImageStack::new(300, 300, 2.0, 0.7, Kernel::Square);
+            stack.add(&image, transform);
+            stack.finish()
+        });
     }
 }
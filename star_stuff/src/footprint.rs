@@ -0,0 +1,174 @@
+//! Where the frames land on the reference, and which part of it the stack covers.
+
+use std::str::FromStr;
+use geom::{Point, Matrix3x3};
+use image::Image;
+
+/// The part of the sky written to the output.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Canvas {
+    /// The reference frame.
+    Reference,
+    /// What every frame covers, cropped further where the coverage falls off.
+    Intersection,
+    /// What any frame covers, for mosaics.
+    Union,
+}
+
+impl FromStr for Canvas {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reference" => Ok(Canvas::Reference),
+            "intersection" => Ok(Canvas::Intersection),
+            "union" => Ok(Canvas::Union),
+            _ => Err(format!("unknown canvas: {} (expected reference, intersection or union)", s))
+        }
+    }
+}
+
+/// An axis-aligned rectangle in reference pixel coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bounds {
+    pub left: f64,
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+}
+
+impl Bounds {
+    pub fn width(&self) -> f64 {
+        (self.right - self.left).max(0.0)
+    }
+
+    pub fn height(&self) -> f64 {
+        (self.bottom - self.top).max(0.0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width() == 0.0 || self.height() == 0.0
+    }
+
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        Bounds {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    pub fn intersection(&self, other: &Bounds) -> Bounds {
+        Bounds {
+            left: self.left.max(other.left),
+            top: self.top.max(other.top),
+            right: self.right.min(other.right),
+            bottom: self.bottom.min(other.bottom),
+        }
+    }
+}
+
+/// Bounding box, on the reference, of a `width` × `height` frame.
+/// `transform` maps reference coordinates to the frame's.
+pub fn footprint(transform: Matrix3x3<f64>, width: usize, height: usize) -> Bounds {
+    let inverse = transform.inverse();
+    let (w, h) = (width as f64, height as f64);
+    let corners: Vec<Point<f64>> = [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].iter()
+        .map(|&(x, y)| inverse * Point { x, y })
+        .collect();
+    Bounds {
+        left: corners.iter().map(|p| p.x).fold(f64::INFINITY, f64::min),
+        top: corners.iter().map(|p| p.y).fold(f64::INFINITY, f64::min),
+        right: corners.iter().map(|p| p.x).fold(f64::NEG_INFINITY, f64::max),
+        bottom: corners.iter().map(|p| p.y).fold(f64::NEG_INFINITY, f64::max),
+    }
+}
+
+/// The part of the reference that `canvas` covers, given the transform of every
+/// `width` × `height` frame, widened to whole pixels. Empty if the frames don't overlap.
+pub fn area(canvas: Canvas, transforms: &[Matrix3x3<f64>], width: usize, height: usize) -> Bounds {
+    let reference = Bounds { left: 0.0, top: 0.0, right: width as f64, bottom: height as f64 };
+    let mut footprints = transforms.iter().map(|&t| footprint(t, width, height));
+    let area = match canvas {
+        Canvas::Reference => reference,
+        Canvas::Union => footprints.fold(reference, |a, b| a.union(&b)),
+        Canvas::Intersection => footprints.fold(reference, |a, b| a.intersection(&b)),
+    };
+    if area.is_empty() {
+        return Bounds { left: 0.0, top: 0.0, right: 0.0, bottom: 0.0 };
+    }
+    Bounds {
+        left: area.left.floor(),
+        top: area.top.floor(),
+        right: area.right.ceil(),
+        bottom: area.bottom.ceil(),
+    }
+}
+
+/// Shrinks `coverage` from its edges, one row or column at a time, until every pixel left
+/// is at least `min`. The edge with the largest share of pixels below `min` goes first.
+/// Returns `(x, y, width, height)`, which is empty if no pixel is covered enough.
+pub fn crop_to_coverage(coverage: &Image<f64>, min: f64) -> (usize, usize, usize, usize) {
+    let (mut x1, mut y1, mut x2, mut y2) = (0, 0, coverage.width, coverage.height);
+    let low = |x: usize, y: usize| *coverage.pixel_at(x, y) < min;
+    while x1 < x2 && y1 < y2 {
+        let (w, h) = ((x2 - x1) as f64, (y2 - y1) as f64);
+        let top = (x1..x2).filter(|&x| low(x, y1)).count() as f64 / w;
+        let bottom = (x1..x2).filter(|&x| low(x, y2 - 1)).count() as f64 / w;
+        let left = (y1..y2).filter(|&y| low(x1, y)).count() as f64 / h;
+        let right = (y1..y2).filter(|&y| low(x2 - 1, y)).count() as f64 / h;
+        let worst = top.max(bottom).max(left).max(right);
+        if worst == 0.0 {
+            break;
+        } else if worst == top {
+            y1 += 1;
+        } else if worst == bottom {
+            y2 -= 1;
+        } else if worst == left {
+            x1 += 1;
+        } else {
+            x2 -= 1;
+        }
+    }
+    if x1 >= x2 || y1 >= y2 {
+        return (0, 0, 0, 0);
+    }
+    (x1, y1, x2 - x1, y2 - y1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_area() {
+        let transforms = [Matrix3x3::identity(), Matrix3x3::translation(10.5, -20.0)];
+        assert_eq!(area(Canvas::Reference, &transforms, 100, 80),
+                   Bounds { left: 0.0, top: 0.0, right: 100.0, bottom: 80.0 });
+        assert_eq!(area(Canvas::Union, &transforms, 100, 80),
+                   Bounds { left: -11.0, top: 0.0, right: 100.0, bottom: 100.0 });
+        assert_eq!(area(Canvas::Intersection, &transforms, 100, 80),
+                   Bounds { left: 0.0, top: 20.0, right: 90.0, bottom: 80.0 });
+        let apart = [Matrix3x3::identity(), Matrix3x3::translation(200.0, 0.0)];
+        assert!(area(Canvas::Intersection, &apart, 100, 80).is_empty());
+    }
+
+    #[test]
+    fn rotated_footprint() {
+        let b = footprint(Matrix3x3::rotation(::std::f64::consts::PI / 2.0), 100, 80);
+        assert!((b.width() - 80.0).abs() < 1e-9 && (b.height() - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_crop_to_coverage() {
+        let mut coverage = Image::new(10, 8);
+        for y in 0..8 {
+            for x in 0..10 {
+                *coverage.pixel_at_mut(x, y) = if x >= 2 && y < 7 { 1.0 } else { 0.5 };
+            }
+        }
+        *coverage.pixel_at_mut(9, 0) = 0.0;
+        assert_eq!(crop_to_coverage(&coverage, 0.9), (2, 0, 7, 7));
+    }
+}
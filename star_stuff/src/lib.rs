@@ -8,5 +8,12 @@ extern crate geom;
 pub mod star_stacker;
 //pub mod types;
 pub mod drizzle;
+pub mod resample;
+pub mod rejection;
+pub mod quality;
+pub mod normalization;
+pub mod footprint;
+pub mod mosaic;
+#[cfg(any(test, feature = "test-support"))] pub mod test_support;
 
 pub use star_stacker::ImageStack;
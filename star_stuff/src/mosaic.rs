@@ -0,0 +1,199 @@
+//! Assembles integrated panels into one mosaic.
+//!
+//! The panels are resampled onto the union of their footprints, one at a time. Each is matched
+//! to what's already on the mosaic, with an offset and a scale per channel estimated where
+//! they overlap, and blended in with weights that fall off towards its edges, which hides the seams.
+
+use std::f64;
+use image::{Image, Rgb};
+use image::stats::median_mad;
+use geom::{Point, Matrix3x3};
+use resample::{Resampler, Interpolation};
+use footprint::{self, Bounds};
+
+/// Offset and scale that map a panel's channel onto the mosaic: `offset + scale * v`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Level {
+    pub offset: f64,
+    pub scale: f64,
+}
+
+/// The result of `assemble`.
+pub struct Mosaic {
+    pub image: Image<Rgb<f64>>,
+    /// The levels each panel was matched with, per channel, or `None` for the first one placed.
+    pub levels: Vec<Option<[Level; 3]>>,
+}
+
+/// Places `panels` on one canvas. `transforms[i]` maps the canvas coordinates, before the canvas
+/// is moved to fit all panels, to `panels[i]`'s. Pixels that are NaN are treated as not covered.
+/// Seams are feathered over `feather` pixels; with 0 overlaps are averaged evenly.
+pub fn assemble(panels: &[Image<Rgb<f64>>], transforms: &[Matrix3x3<f64>], interpolation: Interpolation, feather: f64) -> Mosaic {
+    assert_eq!(panels.len(), transforms.len());
+    let footprints: Vec<Bounds> = panels.iter().zip(transforms.iter())
+        .map(|(panel, &t)| footprint::footprint(t, panel.width, panel.height))
+        .collect();
+    let union = footprints.iter().fold(footprints[0], |a, b| a.union(b));
+    let (left, top) = (union.left.floor(), union.top.floor());
+    let (width, height) = ((union.right.ceil() - left) as usize, (union.bottom.ceil() - top) as usize);
+
+    let mut sum = vec![Rgb { r: 0.0, g: 0.0, b: 0.0 }; width * height];
+    let mut weights = vec![0.0; width * height];
+    let mut placed = vec![false; panels.len()];
+    let mut matched = vec![None; panels.len()];
+    for _ in 0..panels.len() {
+        // The first panel sets the levels, then the one that overlaps the most with what's placed,
+        // so it can be matched well.
+        let overlap = |i: usize| (0..panels.len()).filter(|&j| placed[j])
+            .map(|j| { let o = footprints[i].intersection(&footprints[j]); o.width() * o.height() })
+            .fold(0.0, f64::max);
+        let mut next = 0;
+        for i in 1..panels.len() {
+            if placed[next] || (!placed[i] && overlap(i) > overlap(next)) {
+                next = i;
+            }
+        }
+        placed[next] = true;
+
+        let transform = transforms[next] * Matrix3x3::translation(left, top);
+        let mut registered = register(&panels[next], transform, width, height, interpolation);
+        if weights.iter().any(|&w| w > 0.0) {
+            let levels = match_levels(&registered, &sum, &weights);
+            matched[next] = Some(levels);
+            for p in registered.pixels.iter_mut() {
+                p.r = levels[0].offset + levels[0].scale * p.r;
+                p.g = levels[1].offset + levels[1].scale * p.g;
+                p.b = levels[2].offset + levels[2].scale * p.b;
+            }
+        }
+        let distances = edge_distances(&registered);
+        for (i, p) in registered.pixels.iter().enumerate() {
+            if distances[i] == 0.0 {
+                continue;
+            }
+            let w = if feather > 0.0 { distances[i].min(feather) / feather } else { 1.0 };
+            sum[i].r += p.r * w;
+            sum[i].g += p.g * w;
+            sum[i].b += p.b * w;
+            weights[i] += w;
+        }
+    }
+
+    let pixels = sum.iter().zip(weights.iter()).map(|(s, &w)| {
+        if w > 0.0 {
+            Rgb { r: s.r / w, g: s.g / w, b: s.b / w }
+        } else {
+            Rgb { r: f64::NAN, g: f64::NAN, b: f64::NAN }
+        }
+    }).collect();
+    Mosaic { image: Image { width, height, pixels }, levels: matched }
+}
+
+fn is_covered(p: &Rgb<f64>) -> bool {
+    p.r.is_finite() && p.g.is_finite() && p.b.is_finite()
+}
+
+/// `panel` resampled onto a `width` × `height` canvas, NaN where it doesn't reach.
+fn register(panel: &Image<Rgb<f64>>, transform: Matrix3x3<f64>, width: usize, height: usize, interpolation: Interpolation) -> Image<Rgb<f64>> {
+    let nan = Rgb { r: f64::NAN, g: f64::NAN, b: f64::NAN };
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let p = transform * Point { x: x as f64 + 0.5, y: y as f64 + 0.5 };
+            pixels.push(interpolation.sample(panel, p.x, p.y).unwrap_or(nan));
+        }
+    }
+    Image { width, height, pixels }
+}
+
+/// Levels of each channel of `panel` against the mosaic so far, from the median and MAD
+/// of the pixels both cover. Channels without overlap are left as they are.
+fn match_levels(panel: &Image<Rgb<f64>>, sum: &[Rgb<f64>], weights: &[f64]) -> [Level; 3] {
+    let mut levels = [Level { offset: 0.0, scale: 1.0 }; 3];
+    let channels: [&Fn(&Rgb<f64>) -> f64; 3] = [&|p| p.r, &|p| p.g, &|p| p.b];
+    for (level, channel) in levels.iter_mut().zip(channels.iter()) {
+        let (mut mosaic, mut values) = (Vec::new(), Vec::new());
+        for ((p, s), &w) in panel.pixels.iter().zip(sum.iter()).zip(weights.iter()) {
+            if w > 0.0 && is_covered(p) {
+                mosaic.push(channel(s) / w);
+                values.push(channel(p));
+            }
+        }
+        if values.is_empty() {
+            continue;
+        }
+        let (m1, s1) = median_mad(&mut mosaic);
+        let (m2, s2) = median_mad(&mut values);
+        let scale = if s1 > 0.0 && s2 > 0.0 { s1 / s2 } else { 1.0 };
+        *level = Level { offset: m1 - scale * m2, scale };
+    }
+    levels
+}
+
+/// City block distance of each pixel to the nearest one that isn't covered, counting the
+/// outside of the image as not covered. 0 for pixels that aren't covered themselves.
+fn edge_distances(image: &Image<Rgb<f64>>) -> Vec<f64> {
+    let (w, h) = (image.width, image.height);
+    let mut d: Vec<f64> = image.pixels.iter().map(|p| if is_covered(p) { f64::INFINITY } else { 0.0 }).collect();
+    for y in 0..h {
+        for x in 0..w {
+            let i = y * w + x;
+            let up = if y > 0 { d[i - w] } else { 0.0 };
+            let left = if x > 0 { d[i - 1] } else { 0.0 };
+            d[i] = d[i].min(up + 1.0).min(left + 1.0);
+        }
+    }
+    for y in (0..h).rev() {
+        for x in (0..w).rev() {
+            let i = y * w + x;
+            let down = if y + 1 < h { d[i + w] } else { 0.0 };
+            let right = if x + 1 < w { d[i + 1] } else { 0.0 };
+            d[i] = d[i].min(down + 1.0).min(right + 1.0);
+        }
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{sky, image};
+
+    fn panel<F: Fn(usize, usize) -> f64>(width: usize, height: usize, f: F) -> Image<Rgb<f64>> {
+        image(width, height, |x, y| {
+            let v = f(x, y);
+            Rgb { r: v, g: v, b: v }
+        })
+    }
+
+    #[test]
+    fn matches_levels_in_the_overlap() {
+        // Two 60 × 40 panels of a 100 × 40 field, the second brighter and with more gain.
+        let a = panel(60, 40, |x, y| sky(x, y));
+        let b = panel(60, 40, |x, y| 20.0 + 2.0 * sky(x + 40, y));
+        let transforms = [Matrix3x3::identity(), Matrix3x3::translation(-40.0, 0.0)];
+        let mosaic = assemble(&[a, b], &transforms, Interpolation::Nearest, 10.0);
+        assert!(mosaic.levels[0].is_none() && mosaic.levels[1].is_some());
+        let mosaic = mosaic.image;
+        assert_eq!((mosaic.width, mosaic.height), (100, 40));
+        for y in 0..40 {
+            for x in 0..100 {
+                let p = mosaic.pixel_at(x, y);
+                assert!((p.g - sky(x, y)).abs() < 1e-9, "{} at {}, {}", p.g, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_edge_distances() {
+        let mut image = panel(5, 3, |_, _| 1.0);
+        image.pixel_at_mut(2, 1).r = f64::NAN;
+        assert_eq!(edge_distances(&image), vec![
+            1.0, 1.0, 1.0, 1.0, 1.0,
+            1.0, 1.0, 0.0, 1.0, 1.0,
+            1.0, 1.0, 1.0, 1.0, 1.0,
+        ]);
+        let image = panel(7, 7, |_, _| 1.0);
+        assert_eq!(edge_distances(&image)[3 * 7 + 3], 4.0);
+    }
+}
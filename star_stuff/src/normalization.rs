@@ -0,0 +1,306 @@
+//! Matching the background level and contrast of each frame to the reference frame,
+//! so frames taken under a changing sky can be combined and compared pixel by pixel.
+//!
+//! Works on flat-fielded CFA frames, before registration. Each of the four Bayer
+//! sub-planes is normalized on its own. A frame is modelled as `offset + gain * reference`,
+//! and normalizing maps it back with `(v - offset) / gain`.
+
+use std::str::FromStr;
+use image::Image;
+use image::stats::{median, median_mad};
+use geom::{Point, Matrix3x3};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Normalization {
+    None,
+    /// Only the offset, for a sky background that changes.
+    Additive,
+    /// Only the gain, for changing transparency.
+    Multiplicative,
+    /// Both offset and gain.
+    Both,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "additive" => Ok(Normalization::Additive),
+            "multiplicative" => Ok(Normalization::Multiplicative),
+            "both" => Ok(Normalization::Both),
+            _ => Err(format!("unknown normalization: {} (expected none, additive, multiplicative or both)", s))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Estimator {
+    /// Compares the median and MAD of the frame with those of the reference.
+    MedianMad,
+    /// Fits a line through the pairs of pixels that registration says are the same,
+    /// rejecting outliers like stars, satellites and hot pixels.
+    Fit,
+}
+
+impl FromStr for Estimator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "median-mad" => Ok(Estimator::MedianMad),
+            "fit" => Ok(Estimator::Fit),
+            _ => Err(format!("unknown normalization estimator: {} (expected median-mad or fit)", s))
+        }
+    }
+}
+
+/// Offset and gain of one Bayer sub-plane relative to the reference.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Scale {
+    pub offset: f64,
+    pub gain: f64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Stats {
+    median: f64,
+    mad: f64,
+}
+
+/// Sub-planes are in the order `(0, 0)`, `(1, 0)`, `(0, 1)`, `(1, 1)`.
+pub const SUB_PLANES: [(usize, usize); 4] = [(0, 0), (1, 0), (0, 1), (1, 1)];
+
+/// Every pixel pair this far apart (in sub-plane pixels) is used by `Estimator::Fit`.
+const FIT_STEP: usize = 4;
+
+pub struct Reference {
+    image: Image<f64>,
+    stats: [Stats; 4],
+}
+
+impl Reference {
+    /// Takes the flat-fielded reference frame.
+    pub fn new(image: Image<f64>) -> Self {
+        let mut stats = [Stats { median: 0.0, mad: 0.0 }; 4];
+        for (s, &(dx, dy)) in stats.iter_mut().zip(SUB_PLANES.iter()) {
+            *s = sub_plane_stats(&image, dx, dy);
+        }
+        Reference { image, stats }
+    }
+
+    /// Estimates how `image` relates to the reference in each sub-plane.
+    /// `transform` maps reference coordinates to `image` coordinates, as in the alignment file.
+    /// A sub-plane whose gain or offset can't be estimated, for example because the reference
+    /// is flat or there are no pairs, is left as it is.
+    pub fn estimate(&self, image: &Image<f64>, transform: Matrix3x3<f64>, normalization: Normalization, estimator: Estimator) -> [Scale; 4] {
+        let mut scales = [Scale { offset: 0.0, gain: 1.0 }; 4];
+        for (i, &(dx, dy)) in SUB_PLANES.iter().enumerate() {
+            let (offset, gain) = match estimator {
+                Estimator::MedianMad => {
+                    let reference = self.stats[i];
+                    let frame = sub_plane_stats(image, dx, dy);
+                    match normalization {
+                        Normalization::None => (0.0, 1.0),
+                        Normalization::Additive => (frame.median - reference.median, 1.0),
+                        Normalization::Multiplicative => (0.0, frame.median / reference.median),
+                        Normalization::Both => {
+                            let gain = frame.mad / reference.mad;
+                            (frame.median - gain * reference.median, gain)
+                        }
+                    }
+                }
+                Estimator::Fit => {
+                    let pairs = self.pairs(image, transform, dx, dy);
+                    match normalization {
+                        Normalization::None => (0.0, 1.0),
+                        Normalization::Additive => {
+                            let mut differences: Vec<f64> = pairs.iter().map(|&(r, v)| v - r).collect();
+                            (median(&mut differences), 1.0)
+                        }
+                        Normalization::Multiplicative => {
+                            let mut ratios: Vec<f64> = pairs.iter().filter(|p| p.0 != 0.0).map(|&(r, v)| v / r).collect();
+                            (0.0, median(&mut ratios))
+                        }
+                        Normalization::Both => robust_fit(&pairs),
+                    }
+                }
+            };
+            if gain.is_finite() && gain > 0.0 && offset.is_finite() {
+                scales[i] = Scale { offset, gain };
+            }
+        }
+        scales
+    }
+
+    /// Pairs of `(reference, image)` values of the same sub-plane at the same place of the sky.
+    fn pairs(&self, image: &Image<f64>, transform: Matrix3x3<f64>, dx: usize, dy: usize) -> Vec<(f64, f64)> {
+        let mut pairs = Vec::new();
+        for y in (0..self.image.height / 2).filter(|y| y % FIT_STEP == 0) {
+            for x in (0..self.image.width / 2).filter(|x| x % FIT_STEP == 0) {
+                let (rx, ry) = (2 * x + dx, 2 * y + dy);
+                if let Some((sx, sy)) = corresponding_pixel(transform, rx, ry, image.width, image.height) {
+                    pairs.push((*self.image.pixel_at(rx, ry), *image.pixel_at(sx, sy)));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+/// The pixel of the same Bayer sub-plane nearest to where `transform` maps the reference
+/// pixel `(x, y)`, if it's within a `width` × `height` image.
+pub fn corresponding_pixel(transform: Matrix3x3<f64>, x: usize, y: usize, width: usize, height: usize) -> Option<(usize, usize)> {
+    let snap = |v: f64, d: usize, size: usize| -> Option<usize> {
+        let i = 2.0 * ((v - 0.5 - d as f64) / 2.0).round() + d as f64;
+        if i >= 0.0 && i < size as f64 { Some(i as usize) } else { None }
+    };
+    let p = transform * Point { x: x as f64 + 0.5, y: y as f64 + 0.5 };
+    match (snap(p.x, x % 2, width), snap(p.y, y % 2, height)) {
+        (Some(sx), Some(sy)) => Some((sx, sy)),
+        _ => None
+    }
+}
+
+/// Maps `image` onto the reference's levels, in place.
+pub fn apply(image: &mut Image<f64>, scales: &[Scale; 4]) {
+    for (scale, &(dx, dy)) in scales.iter().zip(SUB_PLANES.iter()) {
+        for y in (dy..image.height).filter(|y| y % 2 == dy) {
+            for x in (dx..image.width).filter(|x| x % 2 == dx) {
+                let p = image.pixel_at_mut(x, y);
+                *p = (*p - scale.offset) / scale.gain;
+            }
+        }
+    }
+}
+
+fn sub_plane_stats(image: &Image<f64>, dx: usize, dy: usize) -> Stats {
+    let mut values = Vec::with_capacity(image.width * image.height / 4);
+    for y in (dy..image.height).filter(|y| y % 2 == dy) {
+        for x in (dx..image.width).filter(|x| x % 2 == dx) {
+            values.push(*image.pixel_at(x, y));
+        }
+    }
+    let (median, mad) = median_mad(&mut values);
+    Stats { median, mad: 1.4826 * mad }
+}
+
+/// Least squares fit of `v = offset + gain * r` to the pairs `(r, v)`, repeated after
+/// dropping the pairs further than 3 sigma from the line.
+fn robust_fit(pairs: &[(f64, f64)]) -> (f64, f64) {
+    let mut kept = pairs.to_vec();
+    let (mut offset, mut gain) = (0.0, 1.0);
+    for _ in 0..5 {
+        if kept.len() < 2 {
+            break;
+        }
+        let n = kept.len() as f64;
+        let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+        for &(x, y) in kept.iter() {
+            sx += x;
+            sy += y;
+            sxx += x * x;
+            sxy += x * y;
+        }
+        gain = (n * sxy - sx * sy) / (n * sxx - sx * sx);
+        offset = (sy - gain * sx) / n;
+        if !gain.is_finite() {
+            // All the reference values are the same.
+            break;
+        }
+
+        let mut residuals: Vec<f64> = pairs.iter().map(|&(x, y)| (y - offset - gain * x).abs()).collect();
+        let sigma = 1.4826 * median(&mut residuals);
+        let next: Vec<(f64, f64)> = pairs.iter().cloned()
+            .filter(|&(x, y)| (y - offset - gain * x).abs() <= 3.0 * sigma)
+            .collect();
+        if next.len() == kept.len() {
+            break;
+        }
+        kept = next;
+    }
+    (offset, gain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{sky, image};
+
+    /// A field with structure, different in each sub-plane.
+    fn field(width: usize, height: usize) -> Image<f64> {
+        image(width, height, |x, y| sky(x, y) + (x % 2) as f64 * 50.0)
+    }
+
+    fn assert_close(a: &Image<f64>, b: &Image<f64>) {
+        for (p, q) in a.pixels.iter().zip(b.pixels.iter()) {
+            assert!((p - q).abs() < 1e-6, "{} != {}", p, q);
+        }
+    }
+
+    fn scaled(image: &Image<f64>, offset: f64, gain: f64) -> Image<f64> {
+        image.map(|&v| offset + gain * v)
+    }
+
+    #[test]
+    fn median_mad() {
+        let reference = Reference::new(field(64, 64));
+        let frame = scaled(&field(64, 64), 30.0, 1.5);
+        let scales = reference.estimate(&frame, Matrix3x3::identity(), Normalization::Both, Estimator::MedianMad);
+        for s in scales.iter() {
+            assert!((s.gain - 1.5).abs() < 1e-9 && (s.offset - 30.0).abs() < 1e-6, "{:?}", s);
+        }
+        let mut normalized = frame.clone();
+        apply(&mut normalized, &scales);
+        assert_close(&normalized, &field(64, 64));
+    }
+
+    #[test]
+    fn additive_and_multiplicative() {
+        let reference = Reference::new(field(64, 64));
+        let frame = scaled(&field(64, 64), 30.0, 1.0);
+        let scales = reference.estimate(&frame, Matrix3x3::identity(), Normalization::Additive, Estimator::MedianMad);
+        assert_eq!(scales[0], Scale { offset: 30.0, gain: 1.0 });
+
+        let frame = scaled(&field(64, 64), 0.0, 2.0);
+        let scales = reference.estimate(&frame, Matrix3x3::identity(), Normalization::Multiplicative, Estimator::Fit);
+        assert_eq!(scales[1], Scale { offset: 0.0, gain: 2.0 });
+    }
+
+    #[test]
+    fn fit_follows_the_transform() {
+        // The frame is the reference shifted by two pixels, with a bright outlier.
+        let reference = field(64, 64);
+        let mut frame = Image::new(64, 64);
+        for y in 0..64 {
+            for x in 2..64 {
+                *frame.pixel_at_mut(x, y) = 20.0 + 0.5 * *reference.pixel_at(x - 2, y);
+            }
+        }
+        *frame.pixel_at_mut(10, 8) = 1e6;
+        let reference = Reference::new(reference);
+        let scales = reference.estimate(&frame, Matrix3x3::translation(2.0, 0.0), Normalization::Both, Estimator::Fit);
+        for s in scales.iter() {
+            assert!((s.gain - 0.5).abs() < 1e-9 && (s.offset - 20.0).abs() < 1e-6, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn unusable_estimates_are_ignored() {
+        let flat = Reference::new(Image::new(64, 64));
+        let frame = field(64, 64);
+        for &(normalization, estimator) in [(Normalization::Both, Estimator::MedianMad), (Normalization::Multiplicative, Estimator::MedianMad),
+                                            (Normalization::Multiplicative, Estimator::Fit), (Normalization::Both, Estimator::Fit)].iter() {
+            let scales = flat.estimate(&frame, Matrix3x3::identity(), normalization, estimator);
+            assert_eq!(scales, [Scale { offset: 0.0, gain: 1.0 }; 4], "{:?} {:?}", normalization, estimator);
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("both".parse::<Normalization>(), Ok(Normalization::Both));
+        assert_eq!("median-mad".parse::<Estimator>(), Ok(Estimator::MedianMad));
+        assert!("mean".parse::<Estimator>().is_err());
+    }
+}
@@ -0,0 +1,278 @@
+//! Per-frame quality estimates, used to weight frames when stacking.
+//!
+//! All functions take a flat-fielded CFA frame. The noise is estimated on each of the
+//! four Bayer sub-planes separately, so the differences between the color channels
+//! don't count as noise.
+
+use std::str::FromStr;
+use image::Image;
+use image::stats::{median, median_mad};
+use image::wavelets::{Decomposition, layer_noise};
+use geom::Point;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NoiseEstimator {
+    /// Median absolute deviation from the median, scaled to a standard deviation.
+    Mad,
+    /// Standard deviation of the first à trous wavelet layer, after clipping stars and
+    /// nebulosity. Less biased by large scale structure than `Mad`.
+    Wavelet,
+}
+
+impl FromStr for NoiseEstimator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mad" => Ok(NoiseEstimator::Mad),
+            "wavelet" => Ok(NoiseEstimator::Wavelet),
+            _ => Err(format!("unknown noise estimator: {} (expected mad or wavelet)", s))
+        }
+    }
+}
+
+/// Standard deviation of the noise of a CFA frame.
+pub fn cfa_noise(image: &Image<f64>, estimator: NoiseEstimator) -> f64 {
+    let mut variance = 0.0;
+    for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+        let plane = sub_plane(image, dx, dy);
+        let sigma = match estimator {
+            NoiseEstimator::Mad => mad_noise(&plane.pixels),
+            NoiseEstimator::Wavelet => wavelet_noise(&plane),
+        };
+        variance += sigma * sigma / 4.0;
+    }
+    variance.sqrt()
+}
+
+pub fn mad_noise(values: &[f64]) -> f64 {
+    let (_, mad) = median_mad(&mut values.to_vec());
+    1.4826 * mad
+}
+
+/// Noise of the image from the finest wavelet layer, clipped to the background.
+pub fn wavelet_noise(image: &Image<f64>) -> f64 {
+    let layer = Decomposition::new(&image.pixels, image.width, image.height, 1).layers.remove(0);
+
+    // Clip at 3 sigma until sigma settles, so only background pixels are left.
+    let mut sigma = std_dev(layer.iter().cloned());
+    for _ in 0..10 {
+        let limit = 3.0 * sigma;
+        let new_sigma = std_dev(layer.iter().cloned().filter(|v| v.abs() < limit));
+        let converged = (new_sigma - sigma).abs() <= 0.001 * sigma;
+        sigma = new_sigma;
+        if converged {
+            break;
+        }
+    }
+    sigma / layer_noise(0)
+}
+
+/// Median FWHM of the stars of a CFA frame, in pixels, or `None` if no stars were found.
+/// Stars are measured on 2×2 superpixels, so the result isn't affected by the Bayer pattern.
+pub fn cfa_fwhm(image: &Image<f64>) -> Option<f64> {
+    fwhm(&superpixels(image)).map(|f| 2.0 * f)
+}
+
+/// Like `cfa_fwhm`, but measures the stars at `stars`, in frame pixels, such as those of the
+/// star catalog, instead of looking for them again.
+pub fn cfa_fwhm_at(image: &Image<f64>, stars: &[Point<f64>]) -> Option<f64> {
+    let lum = superpixels(image);
+    let r = STAR_RADIUS as isize;
+    let background = median(&mut lum.pixels.clone());
+    let peaks: Vec<(usize, usize)> = stars.iter().take(MAX_STARS).filter_map(|s| {
+        let (x, y) = ((s.x / 2.0).round() as isize, (s.y / 2.0).round() as isize);
+        if x < 2 * r || y < 2 * r || x + 2 * r >= lum.width as isize || y + 2 * r >= lum.height as isize {
+            return None;
+        }
+        // The catalog positions are only close to the peak, after the raw conversion.
+        let mut best = (x as usize, y as usize);
+        for dy in -r..r + 1 {
+            for dx in -r..r + 1 {
+                let (px, py) = ((x + dx) as usize, (y + dy) as usize);
+                if *lum.pixel_at(px, py) > *lum.pixel_at(best.0, best.1) {
+                    best = (px, py);
+                }
+            }
+        }
+        Some(best)
+    }).collect();
+    median_fwhm(&lum, background, &peaks).map(|f| 2.0 * f)
+}
+
+/// Sums of each 2×2 block of a CFA frame.
+fn superpixels(image: &Image<f64>) -> Image<f64> {
+    let mut lum = Image::new(image.width / 2, image.height / 2);
+    for y in 0..lum.height {
+        for x in 0..lum.width {
+            *lum.pixel_at_mut(x, y) =
+                *image.pixel_at(2 * x, 2 * y) + *image.pixel_at(2 * x + 1, 2 * y) +
+                *image.pixel_at(2 * x, 2 * y + 1) + *image.pixel_at(2 * x + 1, 2 * y + 1);
+        }
+    }
+    lum
+}
+
+/// A star must be the brightest pixel within this radius, and is measured within it.
+const STAR_RADIUS: usize = 4;
+const MAX_STARS: usize = 200;
+
+/// Median FWHM of the brightest isolated stars of a grayscale image, in pixels.
+pub fn fwhm(image: &Image<f64>) -> Option<f64> {
+    let r = STAR_RADIUS as isize;
+    let mut pixels = image.pixels.clone();
+    let background = median(&mut pixels);
+    let threshold = background + 10.0 * mad_noise(&image.pixels);
+
+    let mut peaks = Vec::new();
+    for y in STAR_RADIUS..image.height.saturating_sub(STAR_RADIUS) {
+        for x in STAR_RADIUS..image.width.saturating_sub(STAR_RADIUS) {
+            let p = *image.pixel_at(x, y);
+            if p <= threshold {
+                continue;
+            }
+            let is_peak = (-r..r + 1).all(|dy| (-r..r + 1).all(|dx| {
+                let q = *image.pixel_at((x as isize + dx) as usize, (y as isize + dy) as usize);
+                q < p || (dx == 0 && dy == 0)
+            }));
+            if is_peak {
+                peaks.push((p, x, y));
+            }
+        }
+    }
+    peaks.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    let peaks: Vec<(usize, usize)> = peaks.iter().take(MAX_STARS).map(|&(_, x, y)| (x, y)).collect();
+    median_fwhm(image, background, &peaks)
+}
+
+/// Median FWHM of the stars peaking at `peaks`, at least `STAR_RADIUS` from the edges.
+fn median_fwhm(image: &Image<f64>, background: f64, peaks: &[(usize, usize)]) -> Option<f64> {
+    let r = STAR_RADIUS as isize;
+    let mut fwhms: Vec<f64> = peaks.iter().filter_map(|&(x, y)| {
+        // For a gaussian, the mean of r² weighted by intensity is 2σ².
+        let (mut sum, mut sum_r2) = (0.0, 0.0);
+        for dy in -r..r + 1 {
+            for dx in -r..r + 1 {
+                let v = *image.pixel_at((x as isize + dx) as usize, (y as isize + dy) as usize) - background;
+                if v > 0.0 {
+                    sum += v;
+                    sum_r2 += v * (dx * dx + dy * dy) as f64;
+                }
+            }
+        }
+        if sum > 0.0 {
+            Some(2.3548 * (sum_r2 / sum / 2.0).sqrt())
+        } else {
+            None
+        }
+    }).collect();
+    if fwhms.is_empty() {
+        None
+    } else {
+        Some(median(&mut fwhms))
+    }
+}
+
+fn sub_plane(image: &Image<f64>, dx: usize, dy: usize) -> Image<f64> {
+    let mut plane = Image::new(image.width / 2, image.height / 2);
+    for y in 0..plane.height {
+        for x in 0..plane.width {
+            *plane.pixel_at_mut(x, y) = *image.pixel_at(2 * x + dx, 2 * y + dy);
+        }
+    }
+    plane
+}
+
+fn std_dev<I: Iterator<Item=f64>>(values: I) -> f64 {
+    let (mut n, mut sum, mut sum2) = (0.0, 0.0, 0.0);
+    for v in values {
+        n += 1.0;
+        sum += v;
+        sum2 += v * v;
+    }
+    if n == 0.0 {
+        return 0.0;
+    }
+    let mean = sum / n;
+    (sum2 / n - mean * mean).max(0.0).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    /// Deterministic, roughly normal noise with standard deviation `sigma`.
+    fn noise(width: usize, height: usize, sigma: f64) -> Image<f64> {
+        let mut state = 12345u64;
+        let mut uniform = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+        };
+        let mut image = Image::new(width, height);
+        for p in image.pixels.iter_mut() {
+            // Box-Muller
+            *p = 100.0 + sigma * (-2.0 * uniform().ln()).sqrt() * (2.0 * PI * uniform()).cos();
+        }
+        image
+    }
+
+    fn add_star(image: &mut Image<f64>, cx: f64, cy: f64, sigma: f64, flux: f64) {
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let r2 = (x as f64 - cx).powi(2) + (y as f64 - cy).powi(2);
+                *image.pixel_at_mut(x, y) += flux * (-r2 / (2.0 * sigma * sigma)).exp();
+            }
+        }
+    }
+
+    #[test]
+    fn test_mad_noise() {
+        let image = noise(128, 128, 5.0);
+        assert!((mad_noise(&image.pixels) - 5.0).abs() < 0.25);
+        assert!((cfa_noise(&image, NoiseEstimator::Mad) - 5.0).abs() < 0.25);
+    }
+
+    #[test]
+    fn test_wavelet_noise() {
+        let image = noise(128, 128, 5.0);
+        assert!((cfa_noise(&image, NoiseEstimator::Wavelet) - 5.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn wavelet_noise_ignores_gradients() {
+        let mut image = noise(128, 128, 5.0);
+        for y in 0..128 {
+            for x in 0..128 {
+                *image.pixel_at_mut(x, y) += x as f64 * 2.0;
+            }
+        }
+        assert!(mad_noise(&image.pixels) > 20.0);
+        assert!((wavelet_noise(&image) - 5.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_fwhm() {
+        let mut image = noise(100, 100, 1.0);
+        for &(x, y) in [(20.0, 20.0), (70.0, 30.0), (40.0, 75.0)].iter() {
+            add_star(&mut image, x, y, 1.5, 500.0);
+        }
+        let f = fwhm(&image).unwrap();
+        assert!((f - 2.3548 * 1.5).abs() < 0.3, "{}", f);
+        assert_eq!(fwhm(&noise(100, 100, 1.0)), None);
+    }
+
+    #[test]
+    fn test_cfa_fwhm_at() {
+        let mut image = noise(200, 200, 1.0);
+        let stars = [(40.5, 40.5), (140.5, 60.5), (80.5, 150.5)];
+        for &(x, y) in stars.iter() {
+            add_star(&mut image, x, y, 3.0, 500.0);
+        }
+        let detected = cfa_fwhm(&image).unwrap();
+        // Positions a pixel off, as from a catalog of the converted raw.
+        let known: Vec<Point<f64>> = stars.iter().map(|&(x, y)| Point { x: x + 1.0, y: y - 1.0 }).collect();
+        assert_eq!(cfa_fwhm_at(&image, &known), Some(detected));
+        assert_eq!(cfa_fwhm_at(&image, &[Point { x: 2.0, y: 2.0 }]), None);
+    }
+}
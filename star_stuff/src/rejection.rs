@@ -0,0 +1,318 @@
+//! Per-pixel rejection of outliers across a stack of registered frames.
+//!
+//! The samples of a pixel are sorted by value, and every method rejects from the two ends,
+//! so what's left is always a contiguous range of the sorted samples.
+//! `low` and `high` are the limits below and above the center; they are in sigmas,
+//! except for `Percentile` (fraction of the median) and `MinMax` (number of samples).
+
+use std::str::FromStr;
+use image::stats::sorted_median;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Rejection {
+    None,
+    MinMax,
+    Percentile,
+    SigmaClip,
+    Winsorized,
+    LinearFit,
+}
+
+impl FromStr for Rejection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Rejection::None),
+            "min-max" => Ok(Rejection::MinMax),
+            "percentile" => Ok(Rejection::Percentile),
+            "sigma-clip" => Ok(Rejection::SigmaClip),
+            "winsorized" => Ok(Rejection::Winsorized),
+            "linear-fit" => Ok(Rejection::LinearFit),
+            _ => Err(format!("unknown rejection: {} (expected none, min-max, percentile, sigma-clip, winsorized or linear-fit)", s))
+        }
+    }
+}
+
+/// How the samples that are left after rejection are combined.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Combination {
+    /// Mean, weighted by coverage.
+    Mean,
+    Median,
+}
+
+impl FromStr for Combination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Combination::Mean),
+            "median" => Ok(Combination::Median),
+            _ => Err(format!("unknown combination: {} (expected mean or median)", s))
+        }
+    }
+}
+
+/// The value of one frame at a pixel, and how much of the pixel the frame covered.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub value: f64,
+    pub weight: f64,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Combined {
+    /// Weighted mean or median of the samples that were kept.
+    pub value: f64,
+    /// Sum of the weights of the samples that were kept.
+    pub weight: f64,
+    pub rejected: usize,
+}
+
+/// Iterative methods stop once fewer samples than this are left.
+const MIN_SAMPLES: usize = 3;
+
+/// Rejects outliers from `samples` and combines the rest.
+/// `samples` is sorted in place.
+pub fn combine(samples: &mut [Sample], rejection: Rejection, low: f64, high: f64, combination: Combination) -> Combined {
+    samples.sort_by(|a, b| a.value.partial_cmp(&b.value).unwrap());
+    let n = samples.len();
+    let (lo, hi) = match rejection {
+        Rejection::None => (0, n),
+        Rejection::MinMax => min_max(n, low, high),
+        Rejection::Percentile => percentile(samples, low, high),
+        Rejection::SigmaClip => iterate(samples, |s| {
+            let values: Vec<f64> = s.iter().map(|s| s.value).collect();
+            (sorted_median(&values), std_dev(&values))
+        }, low, high),
+        Rejection::Winsorized => iterate(samples, |s| {
+            let values: Vec<f64> = s.iter().map(|s| s.value).collect();
+            winsorized(&values)
+        }, low, high),
+        Rejection::LinearFit => linear_fit(samples, low, high),
+    };
+
+    let kept = &samples[lo..hi];
+    let weight: f64 = kept.iter().map(|s| s.weight).sum();
+    let value = if weight <= 0.0 {
+        0.0
+    } else if combination == Combination::Median {
+        let values: Vec<f64> = kept.iter().map(|s| s.value).collect();
+        sorted_median(&values)
+    } else {
+        kept.iter().map(|s| s.value * s.weight).sum::<f64>() / weight
+    };
+    Combined { value, weight, rejected: n - kept.len() }
+}
+
+fn min_max(n: usize, low: f64, high: f64) -> (usize, usize) {
+    let lo = (low as usize).min(n);
+    let hi = n.saturating_sub(high as usize).max(lo);
+    (lo, hi)
+}
+
+fn percentile(samples: &[Sample], low: f64, high: f64) -> (usize, usize) {
+    if samples.len() < MIN_SAMPLES {
+        return (0, samples.len());
+    }
+    let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+    let m = sorted_median(&values);
+    let scale = m.abs();
+    clip_range(samples, 0, samples.len(), |_, v| {
+        (m - v > low * scale, v - m > high * scale)
+    })
+}
+
+/// Repeatedly rejects everything more than `low`/`high` times the spread from the center,
+/// as estimated by `estimate`, until nothing more is rejected.
+fn iterate<E>(samples: &[Sample], estimate: E, low: f64, high: f64) -> (usize, usize)
+where E: Fn(&[Sample]) -> (f64, f64) {
+    let (mut lo, mut hi) = (0, samples.len());
+    while hi - lo >= MIN_SAMPLES {
+        let (center, sigma) = estimate(&samples[lo..hi]);
+        let (new_lo, new_hi) = clip_range(samples, lo, hi, |_, v| {
+            (center - v > low * sigma, v - center > high * sigma)
+        });
+        if (new_lo, new_hi) == (lo, hi) {
+            break;
+        }
+        lo = new_lo;
+        hi = new_hi;
+    }
+    (lo, hi)
+}
+
+/// Fits a line to the sorted samples, and rejects the ones too far from it.
+fn linear_fit(samples: &[Sample], low: f64, high: f64) -> (usize, usize) {
+    let (mut lo, mut hi) = (0, samples.len());
+    while hi - lo >= MIN_SAMPLES {
+        let (a, b) = fit_line(&samples[lo..hi], lo);
+        let sigma = samples[lo..hi].iter().enumerate()
+            .map(|(i, s)| (s.value - (a + b * (lo + i) as f64)).abs())
+            .sum::<f64>() / (hi - lo) as f64;
+        let (new_lo, new_hi) = clip_range(samples, lo, hi, |i, v| {
+            let fit = a + b * i as f64;
+            (fit - v > low * sigma, v - fit > high * sigma)
+        });
+        if (new_lo, new_hi) == (lo, hi) {
+            break;
+        }
+        lo = new_lo;
+        hi = new_hi;
+    }
+    (lo, hi)
+}
+
+/// Least squares fit of `value = a + b * index`, where the first sample has index `offset`.
+fn fit_line(samples: &[Sample], offset: usize) -> (f64, f64) {
+    let n = samples.len() as f64;
+    let (mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0);
+    for (i, s) in samples.iter().enumerate() {
+        let x = (offset + i) as f64;
+        sx += x;
+        sy += s.value;
+        sxx += x * x;
+        sxy += x * s.value;
+    }
+    let b = (n * sxy - sx * sy) / (n * sxx - sx * sx);
+    let a = (sy - b * sx) / n;
+    (a, b)
+}
+
+/// Shrinks `lo..hi` from both ends while `reject(index, value)` says the sample is too low
+/// or too high, respectively.
+fn clip_range<F>(samples: &[Sample], mut lo: usize, mut hi: usize, reject: F) -> (usize, usize)
+where F: Fn(usize, f64) -> (bool, bool) {
+    while lo < hi && reject(lo, samples[lo].value).0 {
+        lo += 1;
+    }
+    while hi > lo && reject(hi - 1, samples[hi - 1].value).1 {
+        hi -= 1;
+    }
+    (lo, hi)
+}
+
+fn std_dev(values: &[f64]) -> f64 {
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    (values.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / n).sqrt()
+}
+
+/// Median and a standard deviation that isn't pulled up by outliers: values further than
+/// 1.5 sigma from the median are moved to that limit, until sigma converges.
+/// The factor 1.134 makes up for the winsorization of a normal distribution.
+fn winsorized(values: &[f64]) -> (f64, f64) {
+    let m = sorted_median(values);
+    let mut sigma = std_dev(values);
+    let mut clamped = values.to_vec();
+    for _ in 0..50 {
+        for (c, &v) in clamped.iter_mut().zip(values.iter()) {
+            *c = v.max(m - 1.5 * sigma).min(m + 1.5 * sigma);
+        }
+        let new_sigma = 1.134 * std_dev(&clamped);
+        let converged = (new_sigma - sigma).abs() <= 0.0005 * sigma;
+        sigma = new_sigma;
+        if converged {
+            break;
+        }
+    }
+    (m, sigma)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(values: &[f64]) -> Vec<Sample> {
+        values.iter().map(|&value| Sample { value, weight: 1.0 }).collect()
+    }
+
+    fn kept(values: &[f64], rejection: Rejection, low: f64, high: f64) -> Combined {
+        combine(&mut samples(values), rejection, low, high, Combination::Mean)
+    }
+
+    const WITH_OUTLIERS: [f64; 10] = [10.0, 10.2, 9.9, 10.1, 9.8, 10.0, 50.0, 10.3, 9.7, 0.0];
+
+    #[test]
+    fn none_is_weighted_mean() {
+        let mut s = samples(&[1.0, 2.0, 4.0]);
+        s[2].weight = 2.0;
+        let c = combine(&mut s, Rejection::None, 0.0, 0.0, Combination::Mean);
+        assert_eq!(c, Combined { value: 11.0 / 4.0, weight: 4.0, rejected: 0 });
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(kept(&[], Rejection::Winsorized, 3.0, 3.0), Combined::default());
+        assert_eq!(kept(&[], Rejection::MinMax, 1.0, 1.0), Combined::default());
+    }
+
+    #[test]
+    fn min_max_rejects_extremes() {
+        let c = kept(&[5.0, 1.0, 3.0, 100.0], Rejection::MinMax, 1.0, 1.0);
+        assert_eq!(c, Combined { value: 4.0, weight: 2.0, rejected: 2 });
+        assert_eq!(kept(&[1.0, 2.0], Rejection::MinMax, 2.0, 2.0).rejected, 2);
+    }
+
+    #[test]
+    fn percentile() {
+        let c = kept(&WITH_OUTLIERS, Rejection::Percentile, 0.1, 0.1);
+        assert_eq!(c.rejected, 2);
+        assert!((c.value - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn sigma_clip() {
+        let c = kept(&WITH_OUTLIERS, Rejection::SigmaClip, 2.0, 2.0);
+        assert_eq!(c.rejected, 2);
+        assert!((c.value - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn winsorized_rejects_what_sigma_clip_misses() {
+        // The outlier inflates the plain standard deviation enough to hide itself.
+        let values = [10.0, 10.2, 9.9, 10.1, 9.8, 10.0, 11.5];
+        assert_eq!(kept(&values, Rejection::SigmaClip, 3.0, 3.0).rejected, 0);
+        let c = kept(&values, Rejection::Winsorized, 3.0, 3.0);
+        assert_eq!(c.rejected, 1);
+        assert!((c.value - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn linear_fit() {
+        let c = kept(&WITH_OUTLIERS, Rejection::LinearFit, 2.0, 2.0);
+        assert_eq!(c.rejected, 2);
+        assert!((c.value - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn keeps_clean_data() {
+        let values = [10.0, 10.2, 9.9, 10.1, 9.8, 10.0, 10.3, 9.7];
+        for &r in [Rejection::Percentile, Rejection::SigmaClip, Rejection::Winsorized, Rejection::LinearFit].iter() {
+            assert_eq!(kept(&values, r, 3.0, 3.0).rejected, 0, "{:?}", r);
+        }
+    }
+
+    #[test]
+    fn too_few_samples() {
+        assert_eq!(kept(&[1.0, 100.0], Rejection::Winsorized, 0.1, 0.1).rejected, 0);
+        assert_eq!(kept(&[1.0, 100.0], Rejection::LinearFit, 0.1, 0.1).rejected, 0);
+    }
+
+    #[test]
+    fn median() {
+        let mut s = samples(&[3.0, 1.0, 100.0, 2.0]);
+        let c = combine(&mut s, Rejection::None, 0.0, 0.0, Combination::Median);
+        assert_eq!(c, Combined { value: 2.5, weight: 4.0, rejected: 0 });
+        let c = combine(&mut s, Rejection::MinMax, 0.0, 1.0, Combination::Median);
+        assert_eq!(c, Combined { value: 2.0, weight: 3.0, rejected: 1 });
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("linear-fit".parse::<Rejection>(), Ok(Rejection::LinearFit));
+        assert!("median".parse::<Rejection>().is_err());
+        assert_eq!("median".parse::<Combination>(), Ok(Combination::Median));
+    }
+}
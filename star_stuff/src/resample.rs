@@ -0,0 +1,361 @@
+//! Interpolating resamplers, for registering frames without drizzling.
+//!
+//! Pixel `(x, y)` covers `[x, x + 1) × [y, y + 1)`, so its center is at `(x + 0.5, y + 0.5)`,
+//! same as in `drizzle`. Samples near the edges reuse the edge pixels.
+
+use std::default::Default;
+use std::fmt::Display;
+use std::f64::consts::PI;
+use std::ops::{AddAssign, Mul};
+use std::str::FromStr;
+use image::{Image, Rgb, RgbBayer};
+use geom::{Point, Matrix3x3};
+use num::{Float, FromPrimitive};
+
+/// Component-wise clamping, used to suppress the ringing of the
+/// bicubic and Lanczos kernels around stars.
+pub trait Clamp: Copy {
+    fn min_with(self, other: Self) -> Self;
+    fn max_with(self, other: Self) -> Self;
+
+    fn clamp_to(self, lower: Self, upper: Self) -> Self {
+        self.max_with(lower).min_with(upper)
+    }
+}
+
+impl Clamp for f32 {
+    fn min_with(self, other: Self) -> Self { self.min(other) }
+    fn max_with(self, other: Self) -> Self { self.max(other) }
+}
+
+impl Clamp for f64 {
+    fn min_with(self, other: Self) -> Self { self.min(other) }
+    fn max_with(self, other: Self) -> Self { self.max(other) }
+}
+
+impl<T: Float> Clamp for Rgb<T> {
+    fn min_with(self, other: Self) -> Self {
+        Rgb {
+            r: self.r.min(other.r),
+            g: self.g.min(other.g),
+            b: self.b.min(other.b),
+        }
+    }
+
+    fn max_with(self, other: Self) -> Self {
+        Rgb {
+            r: self.r.max(other.r),
+            g: self.g.max(other.g),
+            b: self.b.max(other.b),
+        }
+    }
+}
+
+impl<T: Float> Clamp for RgbBayer<T> {
+    fn min_with(self, other: Self) -> Self {
+        RgbBayer {
+            r: self.r.min(other.r),
+            g: self.g.min(other.g),
+            b: self.b.min(other.b),
+            rc: self.rc.min(other.rc),
+            gc: self.gc.min(other.gc),
+            bc: self.bc.min(other.bc),
+        }
+    }
+
+    fn max_with(self, other: Self) -> Self {
+        RgbBayer {
+            r: self.r.max(other.r),
+            g: self.g.max(other.g),
+            b: self.b.max(other.b),
+            rc: self.rc.max(other.rc),
+            gc: self.gc.max(other.gc),
+            bc: self.bc.max(other.bc),
+        }
+    }
+}
+
+pub trait Resampler {
+    /// Returns the interpolated value at `(x, y)`, or `None` if it's outside the image.
+    fn sample<P, F>(&self, image: &Image<P>, x: F, y: F) -> Option<P>
+    where P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp, F: Float + FromPrimitive;
+}
+
+pub struct Nearest;
+
+impl Resampler for Nearest {
+    fn sample<P, F>(&self, image: &Image<P>, x: F, y: F) -> Option<P>
+    where P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp, F: Float + FromPrimitive {
+        if !is_inside(image, x, y) {
+            return None;
+        }
+        Some(*image.pixel_at(x.to_usize().unwrap(), y.to_usize().unwrap()))
+    }
+}
+
+pub struct Bilinear;
+
+impl Resampler for Bilinear {
+    fn sample<P, F>(&self, image: &Image<P>, x: F, y: F) -> Option<P>
+    where P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp, F: Float + FromPrimitive {
+        separable(image, x, y, 1, false, |d| F::one() - d.abs())
+    }
+}
+
+/// Keys' cubic convolution, with a = -0.5.
+pub struct Bicubic {
+    /// Clamp to the surrounding 2×2 pixels, to avoid overshoot.
+    pub clamp: bool,
+}
+
+impl Resampler for Bicubic {
+    fn sample<P, F>(&self, image: &Image<P>, x: F, y: F) -> Option<P>
+    where P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp, F: Float + FromPrimitive {
+        let a = F::from_f64(-0.5).unwrap();
+        let two = F::from_f64(2.0).unwrap();
+        let three = F::from_f64(3.0).unwrap();
+        separable(image, x, y, 2, self.clamp, |d| {
+            let d = d.abs();
+            if d <= F::one() {
+                (a + two) * d * d * d - (a + three) * d * d + F::one()
+            } else if d < two {
+                a * d * d * d - F::from_f64(5.0).unwrap() * a * d * d + F::from_f64(8.0).unwrap() * a * d - F::from_f64(4.0).unwrap() * a
+            } else {
+                F::zero()
+            }
+        })
+    }
+}
+
+pub struct Lanczos {
+    /// The number of lobes: 3 or 4.
+    pub a: usize,
+    /// Clamp to the surrounding 2×2 pixels, to avoid ringing.
+    pub clamp: bool,
+}
+
+impl Resampler for Lanczos {
+    fn sample<P, F>(&self, image: &Image<P>, x: F, y: F) -> Option<P>
+    where P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp, F: Float + FromPrimitive {
+        assert!(self.a > 0 && self.a <= MAX_RADIUS, "unsupported lanczos size: {}", self.a);
+        let a = F::from_usize(self.a).unwrap();
+        let pi = F::from_f64(PI).unwrap();
+        separable(image, x, y, self.a, self.clamp, |d| {
+            if d.abs() < F::epsilon() {
+                F::one()
+            } else if d.abs() >= a {
+                F::zero()
+            } else {
+                let pd = pi * d;
+                a * pd.sin() * (pd / a).sin() / (pd * pd)
+            }
+        })
+    }
+}
+
+/// The resamplers by name, so they can be picked on the command line.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+    Bicubic,
+    Lanczos3,
+    Lanczos4,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Interpolation::Nearest),
+            "bilinear" => Ok(Interpolation::Bilinear),
+            "bicubic" => Ok(Interpolation::Bicubic),
+            "lanczos3" => Ok(Interpolation::Lanczos3),
+            "lanczos4" => Ok(Interpolation::Lanczos4),
+            _ => Err(format!("unknown interpolation: {} (expected nearest, bilinear, bicubic, lanczos3 or lanczos4)", s))
+        }
+    }
+}
+
+impl Resampler for Interpolation {
+    fn sample<P, F>(&self, image: &Image<P>, x: F, y: F) -> Option<P>
+    where P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp, F: Float + FromPrimitive {
+        match *self {
+            Interpolation::Nearest => Nearest.sample(image, x, y),
+            Interpolation::Bilinear => Bilinear.sample(image, x, y),
+            Interpolation::Bicubic => Bicubic { clamp: true }.sample(image, x, y),
+            Interpolation::Lanczos3 => Lanczos { a: 3, clamp: true }.sample(image, x, y),
+            Interpolation::Lanczos4 => Lanczos { a: 4, clamp: true }.sample(image, x, y),
+        }
+    }
+}
+
+/// Resamples `image` onto `stack`, which is `factor` times bigger than the reference.
+/// `transform` maps reference coordinates to sample coordinates, like in `drizzle::add`.
+/// Samples for which `filter` returns false, and output pixels that fall outside `image`, are left out.
+pub fn add<P,F,R,FilterFn>(
+    stack: &mut Image<P>,
+    image: &Image<P>,
+    transform: Matrix3x3<F>,
+    factor: F,
+    resampler: &R,
+    filter: FilterFn
+)
+where
+    P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp,
+    F: Float + FromPrimitive + Display,
+    R: Resampler,
+    FilterFn: Fn(usize, usize, P) -> bool
+{
+    let half = F::from_f64(0.5).unwrap();
+    for y in 0..stack.height {
+        for x in 0..stack.width {
+            let src_pos = transform * Point {
+                x: (F::from_usize(x).unwrap() + half) / factor,
+                y: (F::from_usize(y).unwrap() + half) / factor
+            };
+            if let Some(p) = resampler.sample(image, src_pos.x, src_pos.y) {
+                if filter(x, y, p) {
+                    *stack.pixel_at_mut(x, y) += p;
+                }
+            }
+        }
+    }
+}
+
+const MAX_RADIUS: usize = 4;
+
+fn is_inside<P, F: Float>(image: &Image<P>, x: F, y: F) -> bool {
+    x >= F::zero() && y >= F::zero() &&
+        x < F::from(image.width).unwrap() && y < F::from(image.height).unwrap()
+}
+
+/// Convolves the `2 * radius` × `2 * radius` pixels around `(x, y)` with `kernel(dx) * kernel(dy)`.
+fn separable<P, F, K>(image: &Image<P>, x: F, y: F, radius: usize, clamp: bool, kernel: K) -> Option<P>
+where P: Copy + AddAssign + Mul<F, Output=P> + Default + Clamp, F: Float + FromPrimitive, K: Fn(F) -> F {
+    if !is_inside(image, x, y) {
+        return None;
+    }
+    // position relative to the pixel centers
+    let half = F::from_f64(0.5).unwrap();
+    let (u, v) = (x - half, y - half);
+    let (u0, v0) = (u.floor(), v.floor());
+    let first_x = u0.to_isize().unwrap() - radius as isize + 1;
+    let first_y = v0.to_isize().unwrap() - radius as isize + 1;
+    let taps = radius * 2;
+
+    let mut wx = [F::zero(); MAX_RADIUS * 2];
+    let mut wy = [F::zero(); MAX_RADIUS * 2];
+    let (mut sum_x, mut sum_y) = (F::zero(), F::zero());
+    for i in 0..taps {
+        let offset = F::from_isize(i as isize - radius as isize + 1).unwrap();
+        wx[i] = kernel(u - u0 - offset);
+        wy[i] = kernel(v - v0 - offset);
+        sum_x = sum_x + wx[i];
+        sum_y = sum_y + wy[i];
+    }
+
+    let max_x = image.width as isize - 1;
+    let max_y = image.height as isize - 1;
+    let at = |x: isize, y: isize| {
+        *image.pixel_at(x.max(0).min(max_x) as usize, y.max(0).min(max_y) as usize)
+    };
+
+    let mut res: P = Default::default();
+    for j in 0..taps {
+        let mut row: P = Default::default();
+        for i in 0..taps {
+            row += at(first_x + i as isize, first_y + j as isize) * (wx[i] / sum_x);
+        }
+        res += row * (wy[j] / sum_y);
+    }
+
+    if clamp {
+        let (x0, y0) = (u0.to_isize().unwrap(), v0.to_isize().unwrap());
+        let neighbors = [at(x0, y0), at(x0 + 1, y0), at(x0, y0 + 1), at(x0 + 1, y0 + 1)];
+        let lower = neighbors.iter().fold(neighbors[0], |acc, &p| acc.min_with(p));
+        let upper = neighbors.iter().fold(neighbors[0], |acc, &p| acc.max_with(p));
+        res = res.clamp_to(lower, upper);
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Image;
+
+    fn image() -> Image<f64> {
+        Image {
+            width: 4,
+            height: 4,
+            pixels: vec![
+                0.0, 0.0, 0.0, 0.0,
+                0.0, 1.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 0.0,
+                0.0, 0.0, 0.0, 0.0,
+            ],
+        }
+    }
+
+    fn all() -> Vec<Interpolation> {
+        vec![
+            Interpolation::Nearest,
+            Interpolation::Bilinear,
+            Interpolation::Bicubic,
+            Interpolation::Lanczos3,
+            Interpolation::Lanczos4,
+        ]
+    }
+
+    #[test]
+    fn pixel_centers_are_exact() {
+        for r in all() {
+            assert!((r.sample(&image(), 1.5, 1.5).unwrap() - 1.0).abs() < 1e-9, "{:?}", r);
+            assert!(r.sample(&image(), 2.5, 1.5).unwrap().abs() < 1e-9, "{:?}", r);
+        }
+    }
+
+    #[test]
+    fn outside_is_none() {
+        for r in all() {
+            assert_eq!(r.sample(&image(), -0.1, 1.0), None);
+            assert_eq!(r.sample(&image(), 1.0, 4.0), None);
+        }
+    }
+
+    #[test]
+    fn bilinear() {
+        assert_eq!(Bilinear.sample(&image(), 2.0, 2.0), Some(0.25));
+        assert_eq!(Bilinear.sample(&image(), 1.75, 1.5), Some(0.75));
+    }
+
+    #[test]
+    fn clamping_prevents_ringing() {
+        // between two dark pixels next to the star, lanczos would go negative
+        let v = Lanczos { a: 3, clamp: false }.sample(&image(), 3.0, 1.5).unwrap();
+        assert!(v < 0.0);
+        let v = Lanczos { a: 3, clamp: true }.sample(&image(), 3.0, 1.5).unwrap();
+        assert_eq!(v, 0.0);
+    }
+
+    #[test]
+    fn rgb() {
+        let image = Image {
+            width: 2,
+            height: 1,
+            pixels: vec![Rgb { r: 0.0, g: 1.0, b: 2.0 }, Rgb { r: 1.0, g: 1.0, b: 0.0 }],
+        };
+        assert_eq!(Bilinear.sample(&image, 1.0, 0.5), Some(Rgb { r: 0.5, g: 1.0, b: 1.0 }));
+    }
+
+    #[test]
+    fn add_translated() {
+        let mut stack = Image::new(4, 4);
+        add(&mut stack, &image(), Matrix3x3::translation(1.0, 0.0), 1.0, &Interpolation::Bicubic, |_,_,_| true);
+        assert!((stack.pixel_at(0, 1) - 1.0).abs() < 1e-9);
+        // the last column maps outside the image
+        assert_eq!(*stack.pixel_at(3, 1), 0.0);
+    }
+}
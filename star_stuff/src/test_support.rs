@@ -0,0 +1,20 @@
+//! Frames shared by the tests of star_stuff, and of the crates that use it with the
+//! `test-support` feature.
+
+use image::Image;
+
+/// A sky of 100 with some structure, so the MAD isn't 0.
+pub fn sky(x: usize, y: usize) -> f64 {
+    100.0 + ((x * 7 + y * 13) % 17) as f64
+}
+
+/// An image of `f(x, y)`.
+pub fn image<P, F: Fn(usize, usize) -> P>(width: usize, height: usize, f: F) -> Image<P> {
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            pixels.push(f(x, y));
+        }
+    }
+    Image { width, height, pixels }
+}